@@ -0,0 +1,31 @@
+#!/bin/bash
+
+# Re-sign the existing server and client keys with the existing CA
+# once the certificates run out. Keys, CSRs and the CA stay the same.
+# Run from certs/ or other_certs/.
+
+DAYS=${DAYS:-1825}
+
+SERIAL=$(cat serial)
+
+sign()
+{
+    openssl x509 -req -in $1.csr -CA cert/ec-cacert.pem -CAkey private/ec-cakey.pem -set_serial 0x$SERIAL -days $DAYS -sha256 -extfile $2 -out $1.crt
+    openssl verify -CAfile cert/ec-cacert.pem $1.crt
+
+    SERIAL=$(printf "%02X" $((0x$SERIAL + 1)))
+}
+
+sign server server_ext.cnf
+
+cat server.crt cert/ec-cacert.pem > server.pem
+
+for client in first second third fourth
+do
+    if [ -f $client.csr ]
+    then
+        sign $client client_ext.cnf
+    fi
+done
+
+echo $SERIAL > serial
@@ -0,0 +1,269 @@
+use std::collections::*;
+use std::net::IpAddr;
+
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::client::Rejection;
+use crate::rate_limit::{RateLimitPolicy, TokenBucket};
+
+// Sources not seen for this long are forgotten, unless banned
+const SOURCE_IDLE : Duration = Duration::from_secs(300);
+
+// Limits per source IP, checked before a TLS handshake is started.
+// A source that used up its failure budget is refused until the bucket refills,
+// ban_after authentication failures in a row within ban_window ban it for ban_for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AbuseConfig
+{
+    pub accept_rate     : Option<RateLimitPolicy>, // accepted sockets
+    pub failure_rate    : Option<RateLimitPolicy>, // failed handshakes
+    pub ban_after       : Option<u32>,
+    pub ban_window      : Duration,
+    pub ban_for         : Duration,
+}
+
+impl Default for AbuseConfig
+{
+    // Everything off
+    fn default() -> Self
+    {
+        Self { accept_rate: None, failure_rate: None, ban_after: None, ban_window: Duration::from_secs(60), ban_for: Duration::from_secs(600) }
+    }
+}
+
+struct SourceState
+{
+    accepts         : Option<TokenBucket>,
+    failures        : Option<TokenBucket>,
+    auth_failures   : Option<(u32, Instant)>, // in a row, time of the first one
+    banned_until    : Option<Instant>,
+    last_seen       : Instant,
+}
+
+pub struct AbuseGuard
+{
+    config  : AbuseConfig,
+    sources : HashMap<IpAddr, SourceState>,
+}
+
+impl AbuseGuard
+{
+    pub fn new(config: AbuseConfig) -> Self
+    {
+        Self { config, sources: HashMap::new() }
+    }
+
+    pub fn set_config(&mut self, config: AbuseConfig)
+    {
+        self.config = config;
+        self.sources.clear();
+    }
+
+    fn is_enabled(&self) -> bool
+    {
+        self.config.accept_rate.is_some() || self.config.failure_rate.is_some() || self.config.ban_after.is_some()
+    }
+
+    fn get_source(&mut self, ip: &IpAddr) -> &mut SourceState
+    {
+        let config = &self.config;
+
+        let source = self.sources.entry(*ip).or_insert_with(|| SourceState
+        {
+            accepts: config.accept_rate.clone().map(TokenBucket::new),
+            failures: config.failure_rate.clone().map(TokenBucket::new),
+            auth_failures: None,
+            banned_until: None,
+            last_seen: Instant::now(),
+        });
+
+        source.last_seen = Instant::now();
+        source
+    }
+
+    // Called for every accepted socket
+    pub fn check_accept(&mut self, ip: &IpAddr) -> Result<(), Rejection>
+    {
+        if !self.is_enabled()
+        {
+            return Ok(());
+        }
+
+        let source = self.get_source(ip);
+
+        if let Some(until) = source.banned_until
+        {
+            if Instant::now() < until
+            {
+                return Err(Rejection::IP_BANNED);
+            }
+
+            source.banned_until = None;
+            info!("Source {ip} ban lifted");
+        }
+
+        if let Some(failures) = source.failures.as_mut()
+        {
+            if failures.available() < 1.0
+            {
+                return Err(Rejection::HANDSHAKE_FAILURE_LIMIT);
+            }
+        }
+
+        if let Some(accepts) = source.accepts.as_mut()
+        {
+            if !accepts.try_take(1.0)
+            {
+                return Err(Rejection::ACCEPT_RATE_LIMIT);
+            }
+        }
+
+        Ok(())
+    }
+
+    // A failed handshake or an unknown identity. Returns true when it got the source banned.
+    pub fn record_failure(&mut self, ip: &IpAddr) -> bool
+    {
+        if !self.is_enabled()
+        {
+            return false;
+        }
+
+        let (ban_after, ban_window, ban_for) = (self.config.ban_after, self.config.ban_window, self.config.ban_for);
+
+        let source = self.get_source(ip);
+
+        if let Some(failures) = source.failures.as_mut()
+        {
+            failures.consume(1.0);
+        }
+
+        let ban_after = match ban_after
+        {
+            Some(ban_after) => ban_after,
+            None => return false,
+        };
+
+        let (count, first) = match source.auth_failures
+        {
+            Some((count, first)) if first.elapsed() < ban_window => (count + 1, first),
+            _ => (1, Instant::now()),
+        };
+
+        source.auth_failures = Some((count, first));
+
+        if count >= ban_after && source.banned_until.is_none()
+        {
+            source.banned_until  = Some(Instant::now() + ban_for);
+            source.auth_failures = None;
+
+            warn!("Source {ip} banned for {:?} after {count} authentication failures", ban_for);
+            return true;
+        }
+
+        false
+    }
+
+    pub fn record_success(&mut self, ip: &IpAddr)
+    {
+        if let Some(source) = self.sources.get_mut(ip)
+        {
+            source.auth_failures = None;
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_banned(&self, ip: &IpAddr) -> bool
+    {
+        self.sources.get(ip).and_then(|v| v.banned_until).map(|v| Instant::now() < v).unwrap_or(false)
+    }
+
+    // Banned sources and how long they have left, for the admin status
+    pub fn get_banned(&self) -> Vec<(IpAddr, Duration)>
+    {
+        let now = Instant::now();
+
+        let mut banned : Vec<(IpAddr, Duration)> = self.sources.iter()
+            .filter_map(|(ip, v)| v.banned_until.filter(|until| *until > now).map(|until| (*ip, until - now)))
+            .collect();
+
+        banned.sort();
+        banned
+    }
+
+    // Forget idle sources so the table does not grow without bound
+    pub fn prune(&mut self)
+    {
+        let now = Instant::now();
+
+        self.sources.retain(|_ip, v| v.banned_until.map(|until| until > now).unwrap_or(false) || v.last_seen.elapsed() < SOURCE_IDLE);
+    }
+}
+
+#[test]
+fn test_abuse_accept_rate_and_failures()
+{
+    let config = AbuseConfig { accept_rate: Some(RateLimitPolicy::new(0.0, 2.0)), failure_rate: Some(RateLimitPolicy::new(0.0, 1.0)), ..AbuseConfig::default() };
+
+    let mut guard = AbuseGuard::new(config);
+
+    let a : IpAddr = "192.0.2.1".parse().unwrap();
+    let b : IpAddr = "192.0.2.2".parse().unwrap();
+
+    assert!(guard.check_accept(&a).is_ok());
+    assert!(guard.check_accept(&a).is_ok());
+    assert!(guard.check_accept(&a) == Err(Rejection::ACCEPT_RATE_LIMIT));
+
+    // Sources are limited on their own
+    assert!(guard.check_accept(&b).is_ok());
+
+    // The failure budget runs out before the accept budget
+    assert!(!guard.record_failure(&b));
+    assert!(guard.check_accept(&b) == Err(Rejection::HANDSHAKE_FAILURE_LIMIT));
+
+    // Nothing is tracked while everything is off
+    let mut off = AbuseGuard::new(AbuseConfig::default());
+
+    for _ in 0..100
+    {
+        assert!(off.check_accept(&a).is_ok());
+        assert!(!off.record_failure(&a));
+    }
+
+    assert!(off.sources.is_empty());
+}
+
+#[test]
+fn test_abuse_bans()
+{
+    let config = AbuseConfig { ban_after: Some(3), ban_window: Duration::from_secs(10), ban_for: Duration::from_millis(50), ..AbuseConfig::default() };
+
+    let mut guard = AbuseGuard::new(config);
+
+    let ip : IpAddr = "2001:db8::1".parse().unwrap();
+
+    // A success in between starts the count again
+    assert!(!guard.record_failure(&ip));
+    assert!(!guard.record_failure(&ip));
+    guard.record_success(&ip);
+    assert!(!guard.record_failure(&ip));
+    assert!(!guard.record_failure(&ip));
+    assert!(guard.check_accept(&ip).is_ok());
+
+    assert!(guard.record_failure(&ip));
+    assert!(guard.is_banned(&ip));
+    assert!(guard.check_accept(&ip) == Err(Rejection::IP_BANNED));
+    assert!(guard.get_banned().len() == 1 && guard.get_banned()[0].0 == ip);
+
+    // Bans outlive pruning and run out on their own
+    guard.prune();
+    assert!(guard.is_banned(&ip));
+
+    std::thread::sleep(Duration::from_millis(60));
+
+    assert!(guard.check_accept(&ip).is_ok());
+    assert!(!guard.is_banned(&ip));
+    assert!(guard.get_banned().is_empty());
+}
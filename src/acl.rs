@@ -0,0 +1,162 @@
+use std::net::IpAddr;
+
+
+// An address block such as 10.0.0.0/8 or 2001:db8::/32
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr
+{
+    addr    : IpAddr,
+    prefix  : u8,
+}
+
+impl Cidr
+{
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, Box<dyn std::error::Error>>
+    {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+
+        if prefix > max
+        {
+            return Err(format!("prefix /{prefix} too long for {addr}").into());
+        }
+
+        Ok(Self { addr, prefix })
+    }
+
+    // IPv4 mapped IPv6 addresses, as seen on dual stack listeners, match IPv4 blocks
+    pub fn contains(&self, ip: &IpAddr) -> bool
+    {
+        let ip = match ip
+        {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(*ip),
+            _ => *ip,
+        };
+
+        match (self.addr, ip)
+        {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => matches(u32::from(net) as u128, u32::from(ip) as u128, 32, self.prefix),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => matches(u128::from(net), u128::from(ip), 128, self.prefix),
+            _ => false,
+        }
+    }
+}
+
+fn matches(net: u128, ip: u128, bits: u8, prefix: u8) -> bool
+{
+    if prefix == 0
+    {
+        return true;
+    }
+
+    let shift = (bits - prefix) as u32;
+
+    (net >> shift) == (ip >> shift)
+}
+
+impl std::str::FromStr for Cidr
+{
+    type Err = Box<dyn std::error::Error>;
+
+    // A bare address is a block of one
+    fn from_str(s: &str) -> Result<Self, Self::Err>
+    {
+        match s.split_once('/')
+        {
+            Some((addr, prefix)) => Cidr::new(addr.trim().parse()?, prefix.trim().parse()?),
+            None =>
+            {
+                let addr : IpAddr = s.trim().parse()?;
+                Cidr::new(addr, if addr.is_ipv4() { 32 } else { 128 })
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Cidr
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[allow(dead_code)] // for listeners set up in config.rs
+pub fn parse_cidrs(list: &[&str]) -> Result<Vec<Cidr>, Box<dyn std::error::Error>>
+{
+    list.iter().map(|v| v.parse()).collect()
+}
+
+pub fn any_contains(cidrs: &[Cidr], ip: &IpAddr) -> bool
+{
+    cidrs.iter().any(|v| v.contains(ip))
+}
+
+// Source addresses a listener takes connections from. A deny entry
+// always wins, without an allow list everyone else is let in.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IpAcl
+{
+    pub allow   : Option<Vec<Cidr>>,
+    pub deny    : Vec<Cidr>,
+}
+
+impl IpAcl
+{
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool
+    {
+        if any_contains(&self.deny, ip)
+        {
+            return false;
+        }
+
+        self.allow.as_ref().map(|v| any_contains(v, ip)).unwrap_or(true)
+    }
+}
+
+#[test]
+fn test_cidr_contains()
+{
+    let net : Cidr = "10.1.0.0/16".parse().unwrap();
+
+    assert!(net.contains(&"10.1.200.3".parse().unwrap()));
+    assert!(!net.contains(&"10.2.0.1".parse().unwrap()));
+    assert!(net.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+    assert!(!net.contains(&"2001:db8::1".parse().unwrap()));
+
+    let v6 : Cidr = "2001:db8::/32".parse().unwrap();
+
+    assert!(v6.contains(&"2001:db8:1::1".parse().unwrap()));
+    assert!(!v6.contains(&"2001:db9::1".parse().unwrap()));
+
+    let host : Cidr = "127.0.0.1".parse().unwrap();
+
+    assert!(host.to_string() == "127.0.0.1/32");
+    assert!(host.contains(&"127.0.0.1".parse().unwrap()));
+    assert!(!host.contains(&"127.0.0.2".parse().unwrap()));
+
+    assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(&"8.8.8.8".parse().unwrap()));
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("10.0.0/8".parse::<Cidr>().is_err());
+
+    let cidrs = parse_cidrs(&[ "192.168.0.0/24", "::1" ]).unwrap();
+    assert!(any_contains(&cidrs, &"::1".parse().unwrap()));
+    assert!(!any_contains(&cidrs, &"192.168.1.1".parse().unwrap()));
+}
+
+#[test]
+fn test_ip_acl()
+{
+    assert!(IpAcl::default().is_allowed(&"203.0.113.9".parse().unwrap()));
+
+    let acl = IpAcl { allow: Some(parse_cidrs(&[ "10.0.0.0/8", "2001:db8::/32" ]).unwrap()), deny: parse_cidrs(&[ "10.6.6.0/24" ]).unwrap() };
+
+    assert!(acl.is_allowed(&"10.1.2.3".parse().unwrap()));
+    assert!(acl.is_allowed(&"2001:db8::7".parse().unwrap()));
+    assert!(!acl.is_allowed(&"10.6.6.6".parse().unwrap()));
+    assert!(!acl.is_allowed(&"192.168.1.1".parse().unwrap()));
+
+    let deny_only = IpAcl { allow: None, deny: parse_cidrs(&[ "192.168.0.0/16" ]).unwrap() };
+
+    assert!(!deny_only.is_allowed(&"::ffff:192.168.1.1".parse().unwrap()));
+    assert!(deny_only.is_allowed(&"10.1.2.3".parse().unwrap()));
+}
@@ -0,0 +1,764 @@
+use std::collections::*;
+
+
+// A server that can take a new connection, as seen by a Balancer.
+// Candidates are handed over sorted by id so that ties are always
+// broken the same way (lowest id wins).
+#[derive(Clone, Debug)]
+pub struct Candidate
+{
+    pub id          : u32,
+    pub connections : usize,
+    pub weight      : u32,
+    pub ramp        : f64, // 0.0 - 1.0 share of the weight in use while slow starting
+    pub bytes       : u64,
+    pub latency     : Option<f64>, // smoothed round trip in ms, None until measured
+    pub peak_latency: Option<f64>, // same but jumps straight up to slow samples
+}
+
+impl Candidate
+{
+    pub fn new(id: u32, connections: usize) -> Self
+    {
+        Self { id, connections, weight: 1, ramp: 1.0, bytes: 0, latency: None, peak_latency: None }
+    }
+
+    pub fn effective_weight(&self) -> f64
+    {
+        self.weight.max(1) as f64 * self.ramp
+    }
+
+    // Compares (connections + 1) / weight, i.e. the load a server would have
+    // after taking the new connection relative to its size
+    pub fn cmp_load(&self, other: &Candidate) -> std::cmp::Ordering
+    {
+        let a = (self.connections as f64 + 1.0) / self.effective_weight();
+        let b = (other.connections as f64 + 1.0) / other.effective_weight();
+
+        a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+    }
+}
+
+// What is known about the connection being placed
+#[derive(Clone, Debug, Default)]
+pub struct PickContext
+{
+    pub identity    : String,
+    pub source_ip   : Option<std::net::IpAddr>,
+    pub listener    : usize,
+}
+
+pub trait Balancer
+{
+    fn pick(&mut self, candidates: &[Candidate], _ctx: &PickContext) -> Option<u32>;
+
+    // Every server of the group with its weight, whether it can be picked or not.
+    // Told again whenever the servers or their weights change.
+    fn set_servers(&mut self, _servers: &[(u32, u32)])
+    {
+    }
+
+    fn name(&self) -> &'static str;
+}
+
+// Load balancing strategy of a server group.
+// Random strategies take a seed so they can be replayed in tests,
+// without one they are seeded from the clock.
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[allow(dead_code)] // picked per group in config.rs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Strategy
+{
+    LEAST_CONNECTIONS,
+    ROUND_ROBIN,
+    WEIGHTED_ROUND_ROBIN,
+    RANDOM(Option<u64>),
+    POWER_OF_TWO_CHOICES(Option<u64>),
+    LEAST_BYTES,
+    CONSISTENT_HASH(HashKey),
+    LEAST_LATENCY,
+    PEAK_EWMA,
+}
+
+// What a connection is hashed on for session affinity
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashKey
+{
+    IDENTITY,
+    #[allow(dead_code)] // for groups set up in config.rs
+    SOURCE_IP,
+}
+
+impl Strategy
+{
+    pub fn create(&self) -> Box<dyn Balancer>
+    {
+        match self
+        {
+            Strategy::LEAST_CONNECTIONS          => Box::new(LeastConnections {}),
+            Strategy::ROUND_ROBIN                => Box::new(RoundRobin { last: None }),
+            Strategy::WEIGHTED_ROUND_ROBIN       => Box::new(WeightedRoundRobin { current: HashMap::new() }),
+            Strategy::RANDOM(seed)               => Box::new(Random { rng: XorShiftRng::new(*seed) }),
+            Strategy::POWER_OF_TWO_CHOICES(seed) => Box::new(PowerOfTwoChoices { rng: XorShiftRng::new(*seed) }),
+            Strategy::LEAST_BYTES                => Box::new(LeastBytes {}),
+            Strategy::CONSISTENT_HASH(key)       => Box::new(ConsistentHash { key: *key, ring: vec![], ring_ids: vec![] }),
+            Strategy::LEAST_LATENCY              => Box::new(LeastLatency {}),
+            Strategy::PEAK_EWMA                  => Box::new(PeakEwma {}),
+        }
+    }
+}
+
+// Small deterministic generator, good enough for spreading load.
+// Not for anything security related.
+pub struct XorShiftRng
+{
+    state : u64,
+}
+
+impl XorShiftRng
+{
+    pub fn new(seed: Option<u64>) -> Self
+    {
+        let seed = seed.unwrap_or_else(||
+        {
+            std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|v| v.as_nanos() as u64).unwrap_or(0)
+        });
+
+        // state must never be zero
+        Self { state: seed ^ 0x9E37_79B9_7F4A_7C15 | 1 }
+    }
+
+    pub fn next_u64(&mut self) -> u64
+    {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+
+        self.state
+    }
+
+    pub fn next_below(&mut self, n: usize) -> usize
+    {
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+pub struct LeastConnections {}
+
+impl Balancer for LeastConnections
+{
+    fn pick(&mut self, candidates: &[Candidate], _ctx: &PickContext) -> Option<u32>
+    {
+        // min_by returns the first minimum -> lowest id on ties
+        candidates.iter().min_by(|a, b| a.cmp_load(b)).map(|v| v.id)
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "least-connections"
+    }
+}
+
+pub struct RoundRobin
+{
+    last : Option<u32>,
+}
+
+impl Balancer for RoundRobin
+{
+    fn pick(&mut self, candidates: &[Candidate], _ctx: &PickContext) -> Option<u32>
+    {
+        // Follow on from the last id picked, so servers coming and going
+        // do not reset the rotation
+        let next = match self.last
+        {
+            Some(last) => candidates.iter().find(|v| v.id > last).or_else(|| candidates.first()),
+            None => candidates.first(),
+        };
+
+        self.last = next.map(|v| v.id);
+
+        self.last
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "round-robin"
+    }
+}
+
+// Smooth weighted round robin (as used by nginx).
+// Spreads picks of heavy servers out rather than sending them in a burst.
+pub struct WeightedRoundRobin
+{
+    current : HashMap<u32, i64>,
+}
+
+impl Balancer for WeightedRoundRobin
+{
+    fn pick(&mut self, candidates: &[Candidate], _ctx: &PickContext) -> Option<u32>
+    {
+        let mut total : i64 = 0;
+        let mut best : Option<(u32, i64)> = None;
+
+        // servers that left, e.g. removed by discovery, are forgotten
+        self.current.retain(|id, _| candidates.iter().any(|c| c.id == *id));
+
+        for candidate in candidates.iter()
+        {
+            // scaled up so that a slow starting server still gets a share
+            let weight = ((candidate.effective_weight() * 100.0) as i64).max(1);
+
+            let current = self.current.entry(candidate.id).or_insert(0);
+
+            *current += weight;
+            total    += weight;
+
+            match best
+            {
+                Some((_, best_weight)) if best_weight >= *current => {},
+                _ => { best = Some((candidate.id, *current)); }
+            }
+        }
+
+        if let Some((id, _)) = best
+        {
+            if let Some(current) = self.current.get_mut(&id)
+            {
+                *current -= total;
+            }
+        }
+
+        best.map(|v| v.0)
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "weighted-round-robin"
+    }
+}
+
+pub struct Random
+{
+    rng : XorShiftRng,
+}
+
+impl Balancer for Random
+{
+    fn pick(&mut self, candidates: &[Candidate], _ctx: &PickContext) -> Option<u32>
+    {
+        if candidates.is_empty()
+        {
+            return None;
+        }
+
+        Some(candidates[self.rng.next_below(candidates.len())].id)
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "random"
+    }
+}
+
+// Pick two servers at random and use the less loaded one
+pub struct PowerOfTwoChoices
+{
+    rng : XorShiftRng,
+}
+
+impl Balancer for PowerOfTwoChoices
+{
+    fn pick(&mut self, candidates: &[Candidate], _ctx: &PickContext) -> Option<u32>
+    {
+        if candidates.len() < 2
+        {
+            return candidates.first().map(|v| v.id);
+        }
+
+        let first = self.rng.next_below(candidates.len());
+
+        // second choice is always a different server
+        let second = (first + 1 + self.rng.next_below(candidates.len() - 1)) % candidates.len();
+
+        let (a, b) = (&candidates[first.min(second)], &candidates[first.max(second)]);
+
+        if b.cmp_load(a) == std::cmp::Ordering::Less
+        {
+            return Some(b.id);
+        }
+
+        Some(a.id)
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "power-of-two-choices"
+    }
+}
+
+// Pick the server moving the least data over its current connections
+pub struct LeastBytes {}
+
+impl Balancer for LeastBytes
+{
+    fn pick(&mut self, candidates: &[Candidate], _ctx: &PickContext) -> Option<u32>
+    {
+        candidates.iter().min_by_key(|v| v.bytes).map(|v| v.id)
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "least-bytes"
+    }
+}
+
+// Exponentially weighted moving average of a server's round trip time.
+// Samples are weighted by the time since the previous one so a burst of
+// samples does not wipe out the history, decay is the time constant.
+// The peak average jumps straight to a sample slower than itself and
+// only decays back down, so a server that turns slow is avoided at once.
+pub struct LatencyEwma
+{
+    decay       : std::time::Duration,
+    value       : Option<f64>, // ms
+    peak        : Option<f64>, // ms
+    last_update : std::time::Instant,
+}
+
+impl LatencyEwma
+{
+    pub fn new(decay: std::time::Duration) -> Self
+    {
+        Self { decay, value: None, peak: None, last_update: std::time::Instant::now() }
+    }
+
+    pub fn observe(&mut self, rtt: std::time::Duration)
+    {
+        let sample = rtt.as_secs_f64() * 1000.0;
+
+        let elapsed = self.last_update.elapsed().as_secs_f64();
+        let w = (-elapsed / self.decay.as_secs_f64().max(f64::EPSILON)).exp();
+
+        self.value = Some(self.value.map(|v| v * w + sample * (1.0 - w)).unwrap_or(sample));
+
+        self.peak = Some(match self.peak
+        {
+            Some(peak) if sample <= peak => peak * w + sample * (1.0 - w),
+            _ => sample,
+        });
+
+        self.last_update = std::time::Instant::now();
+    }
+
+    pub fn get(&self) -> Option<f64>
+    {
+        self.value
+    }
+
+    pub fn get_peak(&self) -> Option<f64>
+    {
+        self.peak
+    }
+}
+
+// Pick the server with the lowest smoothed round trip.
+// Servers not measured yet go first so they get a sample,
+// ties are broken on load.
+pub struct LeastLatency {}
+
+impl Balancer for LeastLatency
+{
+    fn pick(&mut self, candidates: &[Candidate], _ctx: &PickContext) -> Option<u32>
+    {
+        candidates.iter()
+                  .min_by(|a, b|
+                  {
+                      a.latency.unwrap_or(0.0).partial_cmp(&b.latency.unwrap_or(0.0))
+                       .unwrap_or(std::cmp::Ordering::Equal)
+                       .then_with(|| a.cmp_load(b))
+                  })
+                  .map(|v| v.id)
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "least-latency"
+    }
+}
+
+// Peak EWMA: the peak round trip times the load the server would have
+// after taking the connection. Slow but alive servers get less traffic
+// without being cut off entirely.
+pub struct PeakEwma {}
+
+impl PeakEwma
+{
+    fn cost(candidate: &Candidate) -> f64
+    {
+        // unmeasured servers count as 1ms so the load still matters
+        let rtt = candidate.peak_latency.unwrap_or(1.0).max(0.001);
+
+        rtt * (candidate.connections as f64 + 1.0) / candidate.effective_weight()
+    }
+}
+
+impl Balancer for PeakEwma
+{
+    fn pick(&mut self, candidates: &[Candidate], _ctx: &PickContext) -> Option<u32>
+    {
+        candidates.iter()
+                  .min_by(|a, b| Self::cost(a).partial_cmp(&Self::cost(b)).unwrap_or(std::cmp::Ordering::Equal))
+                  .map(|v| v.id)
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "peak-ewma"
+    }
+}
+
+// Virtual nodes per unit of weight on the hash ring
+const RING_POINTS : u32 = 160;
+
+// FNV-1a followed by a 64 bit finaliser so that short similar keys
+// still spread out over the ring. Stable across runs and builds.
+pub fn stable_hash(data: &[u8]) -> u64
+{
+    let mut hash : u64 = 0xcbf2_9ce4_8422_2325;
+
+    for b in data.iter()
+    {
+        hash ^= *b as u64;
+        hash  = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+
+    hash ^= hash >> 33;
+    hash  = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash  = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^= hash >> 33;
+
+    hash
+}
+
+// Consistent hash ring, a key lands on the first server point at or after its hash.
+// Every server of the group is on the ring and a pick walks past the ones that
+// cannot take it, so when a server drops out only the keys that landed on it move,
+// everyone else stays put. The ring is only rebuilt when the servers change.
+pub struct ConsistentHash
+{
+    key         : HashKey,
+    ring        : Vec<(u64, u32)>, // point, server id
+    ring_ids    : Vec<(u32, u32)>, // server id, weight the ring was built from
+}
+
+impl ConsistentHash
+{
+    fn build_ring(&mut self, ids: Vec<(u32, u32)>)
+    {
+        self.ring.clear();
+
+        for (id, weight) in ids.iter()
+        {
+            for i in 0..(RING_POINTS * (*weight).max(1))
+            {
+                self.ring.push((stable_hash(format!("{id}-{i}").as_bytes()), *id));
+            }
+        }
+
+        self.ring.sort();
+        self.ring_ids = ids;
+    }
+
+    // Candidates the ring was not told about, e.g. with the balancer used on its own, are added to it
+    fn add_candidates(&mut self, candidates: &[Candidate])
+    {
+        if candidates.iter().all(|c| self.ring_ids.contains(&(c.id, c.weight)))
+        {
+            return;
+        }
+
+        let mut ids : Vec<(u32, u32)> = self.ring_ids.iter().filter(|v| !candidates.iter().any(|c| c.id == v.0)).copied().collect();
+        ids.extend(candidates.iter().map(|v| (v.id, v.weight)));
+        ids.sort();
+
+        self.build_ring(ids);
+    }
+}
+
+impl Balancer for ConsistentHash
+{
+    fn pick(&mut self, candidates: &[Candidate], ctx: &PickContext) -> Option<u32>
+    {
+        self.add_candidates(candidates);
+
+        if self.ring.is_empty() || candidates.is_empty()
+        {
+            return None;
+        }
+
+        let key = match self.key
+        {
+            HashKey::IDENTITY  => ctx.identity.clone(),
+            // fall back on the identity when the address is not known
+            HashKey::SOURCE_IP => ctx.source_ip.map(|v| v.to_string()).unwrap_or_else(|| ctx.identity.clone()),
+        };
+
+        let hash = stable_hash(key.as_bytes());
+
+        let start = self.ring.partition_point(|v| v.0 < hash);
+
+        // first point clockwise of a server that can take it
+        (0..self.ring.len()).map(|i| self.ring[(start + i) % self.ring.len()].1).find(|id| candidates.iter().any(|c| c.id == *id))
+    }
+
+    fn set_servers(&mut self, servers: &[(u32, u32)])
+    {
+        if servers != self.ring_ids.as_slice()
+        {
+            self.build_ring(servers.to_vec());
+        }
+    }
+
+    fn name(&self) -> &'static str
+    {
+        "consistent-hash"
+    }
+}
+
+#[test]
+fn test_balancer_least_connections_and_bytes()
+{
+    let ctx = PickContext::default();
+    let mut candidates = vec![ Candidate::new(0, 3), Candidate::new(1, 1), Candidate::new(2, 1) ];
+
+    candidates[0].bytes = 10;
+    candidates[1].bytes = 500;
+    candidates[2].bytes = 20;
+
+    // tie between 1 and 2 goes to the lowest id
+    assert!(Strategy::LEAST_CONNECTIONS.create().pick(&candidates, &ctx) == Some(1));
+    assert!(Strategy::LEAST_BYTES.create().pick(&candidates, &ctx) == Some(0));
+    assert!(Strategy::LEAST_CONNECTIONS.create().pick(&[], &ctx).is_none());
+}
+
+#[test]
+fn test_balancer_weighted_least_connections()
+{
+    let ctx = PickContext::default();
+    let mut lc = Strategy::LEAST_CONNECTIONS.create();
+
+    let mut candidates = vec![ Candidate::new(0, 0), Candidate::new(1, 0) ];
+
+    candidates[0].weight = 3;
+
+    for i in 0..40
+    {
+        let id = lc.pick(&candidates, &ctx).unwrap();
+
+        candidates[id as usize].connections += 1;
+    }
+
+    assert!(candidates[0].connections == 30);
+    assert!(candidates[1].connections == 10);
+}
+
+#[test]
+fn test_balancer_round_robin()
+{
+    let ctx = PickContext::default();
+    let mut rr = Strategy::ROUND_ROBIN.create();
+
+    let candidates = vec![ Candidate::new(0, 0), Candidate::new(3, 0), Candidate::new(7, 0) ];
+
+    let picks : Vec<Option<u32>> = (0..4).map(|_| rr.pick(&candidates, &ctx)).collect();
+
+    assert!(picks == vec![Some(0), Some(3), Some(7), Some(0)]);
+
+    // Server 3 goes away, rotation carries on from the last pick
+    let candidates = vec![ Candidate::new(0, 0), Candidate::new(7, 0) ];
+
+    assert!(rr.pick(&candidates, &ctx) == Some(7));
+}
+
+#[test]
+fn test_balancer_weighted_round_robin()
+{
+    let ctx = PickContext::default();
+    let mut wrr = Strategy::WEIGHTED_ROUND_ROBIN.create();
+
+    let mut candidates = vec![ Candidate::new(0, 0), Candidate::new(1, 0), Candidate::new(2, 0) ];
+
+    candidates[0].weight = 5;
+
+    let picks : Vec<u32> = (0..7).map(|_| wrr.pick(&candidates, &ctx).unwrap()).collect();
+
+    assert!(picks == vec![0, 0, 1, 0, 2, 0, 0]);
+
+    // Only the servers still on offer are tracked
+    let mut wrr = WeightedRoundRobin { current: HashMap::new() };
+
+    let candidates : Vec<Candidate> = (0..100).map(|i| Candidate::new(i, 0)).collect();
+    wrr.pick(&candidates, &ctx);
+    assert!(wrr.current.len() == 100);
+
+    wrr.pick(&candidates[0..2], &ctx);
+    assert!(wrr.current.len() == 2 && wrr.current.contains_key(&0) && wrr.current.contains_key(&1));
+}
+
+#[test]
+fn test_balancer_random_strategies_are_seeded()
+{
+    let ctx = PickContext::default();
+    let candidates : Vec<Candidate> = (0..10).map(|i| Candidate::new(i, i as usize)).collect();
+
+    for strategy in [ Strategy::RANDOM(Some(42)), Strategy::POWER_OF_TWO_CHOICES(Some(42)) ].iter()
+    {
+        let mut a = strategy.create();
+        let mut b = strategy.create();
+
+        let picks_a : Vec<Option<u32>> = (0..20).map(|_| a.pick(&candidates, &ctx)).collect();
+        let picks_b : Vec<Option<u32>> = (0..20).map(|_| b.pick(&candidates, &ctx)).collect();
+
+        assert!(picks_a == picks_b);
+    }
+
+    // Power of two never picks the most loaded server
+    let mut p2c = Strategy::POWER_OF_TWO_CHOICES(Some(7)).create();
+
+    for i in 0..100
+    {
+        assert!(p2c.pick(&candidates, &ctx) != Some(9));
+    }
+}
+
+#[test]
+fn test_balancer_consistent_hash_affinity()
+{
+    let mut ch = Strategy::CONSISTENT_HASH(HashKey::IDENTITY).create();
+
+    let candidates : Vec<Candidate> = (0..5).map(|i| Candidate::new(i, 0)).collect();
+
+    let users : Vec<PickContext> = (0..1000).map(|i| PickContext { identity: format!("user{i}@example.com"), source_ip: None, ..PickContext::default() }).collect();
+
+    let before : Vec<Option<u32>> = users.iter().map(|v| ch.pick(&candidates, v)).collect();
+
+    // Same user always lands on the same server
+    let again : Vec<Option<u32>> = users.iter().map(|v| ch.pick(&candidates, v)).collect();
+    assert!(before == again);
+
+    // Every server gets a share
+    for i in 0..5
+    {
+        assert!(before.iter().filter(|v| **v == Some(i)).count() > 100);
+    }
+
+    // Server 2 goes unhealthy -> only its users move
+    let without : Vec<Candidate> = candidates.iter().filter(|v| v.id != 2).cloned().collect();
+    let after : Vec<Option<u32>> = users.iter().map(|v| ch.pick(&without, v)).collect();
+
+    for (b, a) in before.iter().zip(after.iter())
+    {
+        if *b != Some(2)
+        {
+            assert!(a == b);
+        }
+        else
+        {
+            assert!(*a != Some(2));
+        }
+    }
+
+    // Adding a sixth server moves roughly a sixth of the users
+    let mut with : Vec<Candidate> = candidates.clone();
+    with.push(Candidate::new(5, 0));
+    let after : Vec<Option<u32>> = users.iter().map(|v| ch.pick(&with, v)).collect();
+
+    let moved = before.iter().zip(after.iter()).filter(|(b, a)| b != a).count();
+
+    assert!(moved < 300);
+    assert!(before.iter().zip(after.iter()).all(|(b, a)| a == b || *a == Some(5)));
+}
+
+#[test]
+fn test_balancer_consistent_hash_ring_kept()
+{
+    let mut ch = ConsistentHash { key: HashKey::IDENTITY, ring: vec![], ring_ids: vec![] };
+
+    ch.set_servers(&[ (0, 1), (1, 1), (2, 2) ]);
+    assert!(ch.ring.len() == 4 * RING_POINTS as usize);
+
+    let ctx = PickContext { identity: "user@example.com".into(), ..PickContext::default() };
+    let candidates : Vec<Candidate> = (0..3).map(|i| Candidate { weight: if i == 2 { 2 } else { 1 }, ..Candidate::new(i, 0) }).collect();
+
+    let first = ch.pick(&candidates, &ctx).unwrap();
+    let ring = ch.ring.clone();
+
+    // A retry walks on to the next server without touching the ring
+    let others : Vec<Candidate> = candidates.iter().filter(|v| v.id != first).cloned().collect();
+    let second = ch.pick(&others, &ctx).unwrap();
+
+    assert!(second != first);
+    assert!(ch.ring == ring);
+    assert!(ch.pick(&[], &ctx).is_none());
+
+    // A new weight moves the ring
+    ch.set_servers(&[ (0, 1), (1, 1), (2, 1) ]);
+    assert!(ch.ring.len() == 3 * RING_POINTS as usize);
+}
+
+#[test]
+fn test_balancer_latency_ewma()
+{
+    let mut ewma = LatencyEwma::new(std::time::Duration::from_millis(50));
+
+    assert!(ewma.get().is_none());
+
+    ewma.observe(std::time::Duration::from_millis(10));
+    assert!(ewma.get() == Some(10.0));
+
+    // The peak jumps to a slow sample, the average only moves part of the way
+    std::thread::sleep(std::time::Duration::from_millis(20));
+    ewma.observe(std::time::Duration::from_millis(100));
+    assert!(ewma.get_peak() == Some(100.0));
+    assert!(ewma.get().unwrap() > 10.0 && ewma.get().unwrap() < 100.0);
+
+    // and both decay back once it is fast again
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    ewma.observe(std::time::Duration::from_millis(10));
+    assert!(ewma.get_peak().unwrap() < 20.0);
+}
+
+#[test]
+fn test_balancer_latency_strategies()
+{
+    let ctx = PickContext::default();
+    let mut candidates = vec![ Candidate::new(0, 0), Candidate::new(1, 0), Candidate::new(2, 0) ];
+
+    candidates[0].latency = Some(50.0);
+    candidates[1].latency = Some(5.0);
+    candidates[2].latency = Some(20.0);
+
+    assert!(Strategy::LEAST_LATENCY.create().pick(&candidates, &ctx) == Some(1));
+
+    // Unmeasured servers are tried first
+    candidates[2].latency = None;
+    assert!(Strategy::LEAST_LATENCY.create().pick(&candidates, &ctx) == Some(2));
+
+    candidates[0].peak_latency = Some(10.0);
+    candidates[1].peak_latency = Some(40.0);
+    candidates[2].peak_latency = Some(10.0);
+    candidates[0].connections  = 1;
+    candidates[1].connections  = 0;
+    candidates[2].connections  = 4;
+
+    // costs 20, 40, 50
+    assert!(Strategy::PEAK_EWMA.create().pick(&candidates, &ctx) == Some(0));
+
+    candidates[0].connections = 5;
+
+    // costs 60, 40, 50
+    assert!(Strategy::PEAK_EWMA.create().pick(&candidates, &ctx) == Some(1));
+}
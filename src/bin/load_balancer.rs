@@ -4,7 +4,16 @@ use teleport_coding_challenge::config;
 use log::{warn, info, error};
 use std::time::Duration;
 use simple_logger::SimpleLogger;
-use argparse::{ArgumentParser, StoreTrue, Store};
+use argparse::{ArgumentParser, StoreTrue, Store, List};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Set by SIGUSR1, the maintenance file is run again on the next loop
+static RELOAD_MAINTENANCE : AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigusr1(_signal: libc::c_int)
+{
+    RELOAD_MAINTENANCE.store(true, Ordering::SeqCst);
+}
 
 fn main() -> Result<(), Box<dyn std::error::Error>>
 {
@@ -12,11 +21,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>>
     
     let mut other_certs = false;
     let mut port : u16  = 8443;
+    let mut binds : Vec<std::net::IpAddr> = vec![];
+    let mut maintenance_file = String::new();
+    let mut discover : Vec<String> = vec![];
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("TLS 1.3 Upstream Server");
         ap.refer(&mut other_certs).add_option(&["--other"], StoreTrue, "This flag changes the ca that has signed the server cert -- to test authentication with different CAs");
-        ap.refer(&mut port).add_option(&["--port"], Store, "The port that the load balancer will listen to on localhost. default: 8443");
+        ap.refer(&mut port).add_option(&["--port"], Store, "The port that the load balancer will listen to. default: 8443");
+        ap.refer(&mut binds).add_option(&["--bind"], List, "Addresses to listen on, one listener each, e.g. 127.0.0.1 ::1 or :: for dual stack. default: 127.0.0.1");
+        ap.refer(&mut maintenance_file).add_option(&["--maintenance-file"], Store, "File of admin commands, e.g. drain 0/2 300, run at start and on SIGUSR1. default: off");
+        ap.refer(&mut discover).add_option(&["--discover"], List, "Server groups to keep filled from DNS or service files, e.g. 2=srv:_upstream._tcp.example.com 2=host:upstream.internal:2500 3=dir:batch:/etc/lb/services. default: none");
         ap.parse_args_or_exit();
     }
 
@@ -33,10 +48,35 @@ fn main() -> Result<(), Box<dyn std::error::Error>>
 
     // load the whole load balancer in with configuration
     // see src/config.rs for more details
-    let mut lb = config::load_configuration(port, other_certs)?;
+    if binds.is_empty()
+    {
+        binds.push(std::net::Ipv4Addr::LOCALHOST.into());
+    }
+
+    let mut lb = config::load_configuration(&binds, port, other_certs)?;
+
+    for spec in discover.iter()
+    {
+        config::add_discovery(&mut lb, spec)?;
+    }
+
+    if !maintenance_file.is_empty()
+    {
+        lb.set_maintenance_file(maintenance_file.into());
+        lb.reload_maintenance();
+
+        // only sets a flag, everything else happens in the poll loop
+        unsafe { libc::signal(libc::SIGUSR1, on_sigusr1 as *const () as libc::sighandler_t); }
+    }
 
     loop
     {
+        if RELOAD_MAINTENANCE.swap(false, Ordering::SeqCst)
+        {
+            info!("SIGUSR1: reloading maintenance file");
+            lb.reload_maintenance();
+        }
+
         match lb.poll()
         {
             Ok(()) => {},
@@ -6,14 +6,23 @@ use std::io::Write;
 use log::{warn, info, error};
 use simple_logger::SimpleLogger;
 use argparse::{ArgumentParser, StoreTrue, Store};
+use teleport_coding_challenge::proxy_protocol::ProxyHeader;
+use teleport_coding_challenge::{HEALTH_REQUEST, HEALTH_RESPONSE};
 
 
 // Upstream Server
 // Just a straight TcpListener which listens for streams.
 // No encryption! 
-// Does two things!
+// Does three things!
 // 1. listens for data and echos it back
 // 2. listens for the string "PING" and returns PONG 
+// 3. answers the load balancer's default health check, HEALTH with OK
+//
+// With --health-port a second listener is opened for health checks,
+// it only answers the health check and closes the connection.
+//
+// A connection that starts with a PROXY protocol v2 header has the header
+// logged and stripped before the echo. One with a broken header is closed.
 //
 // For simplicity it is expected that all messages will fit within the buffer of 1024 bytes. This would not work in practise but for demonstration/testing this will suffice.
 fn main() -> Result<(), Box<dyn std::error::Error>>
@@ -21,11 +30,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>>
     SimpleLogger::new().with_level(log::LevelFilter::Debug).init().unwrap();
     
     let mut port : u16 = 2500;
+    let mut health_port : u16 = 0;
 
     {
         let mut ap = ArgumentParser::new();
         ap.set_description("TLS 1.3 Upstream Server");
         ap.refer(&mut port).add_option(&["--port"], Store, "The port that the upstream will listen to on localhost. default: 2500");
+        ap.refer(&mut health_port).add_option(&["--health-port"], Store, "Optional separate port for health checks on localhost. default: off");
         ap.parse_args_or_exit();
     }
 
@@ -39,48 +50,108 @@ fn main() -> Result<(), Box<dyn std::error::Error>>
 
     listener.set_nonblocking(true)?;
 
-    let mut streams : Vec<std::net::TcpStream> = vec![];
+    let mut listeners = vec![listener];
+
+    if health_port != 0
+    {
+        let health_listener = std::net::TcpListener::bind(format!("127.0.0.1:{health_port}"))?;
+
+        info!("health checks on 127.0.0.1:{health_port}");
+
+        health_listener.set_nonblocking(true)?;
+
+        listeners.push(health_listener);
+    }
+
+    let mut streams : Vec<(std::net::TcpStream, bool, bool)> = vec![]; // stream, first read done, health check
 
     loop
     {
-        for stream_res in listener.incoming()
+        for (index, listener) in listeners.iter().enumerate()
         {
-			match stream_res
-			{
-				Ok(stream) =>
-				{
-                    info!("Connection!");
-
-                    stream.set_read_timeout(Some(Duration::from_millis(1)))?;
-                    stream.set_write_timeout(Some(Duration::from_millis(1)))?;
-                    stream.set_nonblocking(true)?;
-                    stream.set_nodelay(true)?;
-
-					// Handle new stream
-                    streams.push(stream);
-				},
-    			Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock =>
-    			{
-					// Do nothing we will 
-					break;
-    			},
-    			Err(e) =>
+            for stream_res in listener.incoming()
+            {
+    			match stream_res
     			{
-                    error!("{e}");
-				}
-			}
+    				Ok(stream) =>
+    				{
+                        info!("Connection!");
+
+                        stream.set_read_timeout(Some(Duration::from_millis(1)))?;
+                        stream.set_write_timeout(Some(Duration::from_millis(1)))?;
+                        stream.set_nonblocking(true)?;
+                        stream.set_nodelay(true)?;
+
+    					// Handle new stream
+                        streams.push((stream, false, index > 0));
+    				},
+        			Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock =>
+        			{
+    					// Do nothing we will 
+    					break;
+        			},
+        			Err(e) =>
+        			{
+                        error!("{e}");
+    				}
+    			}
+            }
         }
 
         let mut to_remove : Vec<usize> = vec![];
 
-        for (i, stream) in streams.iter_mut().enumerate()
+        for (i, (stream, first_read, health)) in streams.iter_mut().enumerate()
         {
             let mut buf : [u8; 1024] = [0; 1024];
             match stream.read(&mut buf)
             {
                 Ok(n) =>
                 {
-                    if n == 4 && buf[0..n] == *"PING".as_bytes()
+                    let mut start = 0;
+
+                    if !*first_read && n > 0
+                    {
+                        *first_read = true;
+
+                        match strip_proxy_header(&buf[0..n])
+                        {
+                            Ok(used) => { start = used; },
+                            Err(e) =>
+                            {
+                                // never echo a header back as data
+                                error!("{e} .. closing");
+                                to_remove.push(i);
+                                continue;
+                            }
+                        }
+                    }
+
+                    let buf = &buf[start..n];
+                    let n = buf.len();
+
+                    if n == 0 && start > 0
+                    {
+                        // only the header so far
+                    }
+                    else if buf == HEALTH_REQUEST || *health
+                    {
+                        if buf == HEALTH_REQUEST
+                        {
+                            info!("Received health check");
+
+                            if let Err(e) = stream.write_all(HEALTH_RESPONSE)
+                            {
+                                error!("{e}");
+                            }
+                        }
+
+                        // the health port answers nothing else
+                        if *health
+                        {
+                            to_remove.push(i);
+                        }
+                    }
+                    else if n == 4 && buf[0..n] == *"PING".as_bytes()
                     {
                         info!("Received Ping");
                         // Send back "PONG"
@@ -145,3 +216,56 @@ fn main() -> Result<(), Box<dyn std::error::Error>>
 
     Ok(())
 }
+
+// Logs a PROXY protocol header at the start of a connection and returns its length, 0 without one.
+// The header is expected whole in the first read.
+fn strip_proxy_header(buf: &[u8]) -> Result<usize, String>
+{
+    if !ProxyHeader::has_signature(buf)
+    {
+        // plain connection
+        return Ok(0);
+    }
+
+    match ProxyHeader::decode(buf)
+    {
+        Ok(Some((header, used))) =>
+        {
+            info!("{header}");
+            Ok(used)
+        },
+        Ok(None) => Err("Incomplete PROXY protocol header".into()),
+        Err(e) => Err(format!("Malformed PROXY protocol header: {e}")),
+    }
+}
+
+#[test]
+fn test_upstream_strip_proxy_header()
+{
+    use teleport_coding_challenge::proxy_protocol::TLV_IDENTITY;
+
+    let mut header = ProxyHeader::new("192.0.2.5:51000".parse().unwrap(), "127.0.0.1:8443".parse().unwrap());
+    header.add_tlv(TLV_IDENTITY, "fourth".as_bytes());
+
+    let mut buf = header.encode().unwrap();
+    let len = buf.len();
+    buf.extend_from_slice(HEALTH_REQUEST);
+
+    assert!(strip_proxy_header(&buf) == Ok(len));
+    assert!(buf[len..] == *HEALTH_REQUEST);
+
+    // LOCAL from a health check
+    let local = ProxyHeader { source: None, destination: None, tlvs: vec![] }.encode().unwrap();
+    assert!(strip_proxy_header(&local) == Ok(local.len()));
+
+    assert!(strip_proxy_header(HEALTH_REQUEST) == Ok(0));
+    assert!(strip_proxy_header("PING".as_bytes()) == Ok(0));
+
+    // A cut short or broken header is not taken for data
+    assert!(strip_proxy_header(&buf[0..20]).is_err());
+    assert!(strip_proxy_header(&local[0..8]).is_err());
+
+    let mut broken = local.clone();
+    broken[12] = 0x10;
+    assert!(strip_proxy_header(&broken).is_err());
+}
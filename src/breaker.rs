@@ -0,0 +1,220 @@
+use std::time::{Duration, Instant};
+
+
+// A breaker opens after failure_threshold consecutive failures, a connect
+// slower than latency_threshold counts as a failure. After open_for it lets
+// half_open_probes trial connections through, all of them have to succeed
+// to close it again and any failure opens it again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BreakerConfig
+{
+    pub failure_threshold   : u32,
+    pub latency_threshold   : Option<Duration>,
+    pub open_for            : Duration,
+    pub half_open_probes    : u32,
+}
+
+impl Default for BreakerConfig
+{
+    fn default() -> Self
+    {
+        Self { failure_threshold: 5, latency_threshold: None, open_for: Duration::from_secs(10), half_open_probes: 1 }
+    }
+}
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState
+{
+    CLOSED,
+    OPEN(Instant),
+    HALF_OPEN,
+}
+
+pub struct CircuitBreaker
+{
+    config      : BreakerConfig,
+    state       : BreakerState,
+    failures    : u32, // consecutive, while closed
+    admitted    : u32, // trial connections let through while half open
+    passed      : u32, // trial connections that succeeded
+}
+
+impl CircuitBreaker
+{
+    pub fn new(config: BreakerConfig) -> Self
+    {
+        Self { config, state: BreakerState::CLOSED, failures: 0, admitted: 0, passed: 0 }
+    }
+
+    #[cfg(test)]
+    pub fn get_state(&self) -> BreakerState
+    {
+        self.state
+    }
+
+    // Short name for logs and the admin status
+    pub fn get_state_name(&self) -> &'static str
+    {
+        match self.state
+        {
+            BreakerState::CLOSED => "CLOSED",
+            BreakerState::OPEN(_) => "OPEN",
+            BreakerState::HALF_OPEN => "HALF_OPEN",
+        }
+    }
+
+    // Whether a connection could be let through right now
+    pub fn is_available(&self) -> bool
+    {
+        match self.state
+        {
+            BreakerState::CLOSED => true,
+            BreakerState::OPEN(since) => since.elapsed() >= self.config.open_for,
+            BreakerState::HALF_OPEN => self.admitted < self.config.half_open_probes,
+        }
+    }
+
+    // Let a connection through, counting it as a trial while half open
+    pub fn allow(&mut self) -> bool
+    {
+        if let BreakerState::OPEN(since) = self.state
+        {
+            if since.elapsed() < self.config.open_for
+            {
+                return false;
+            }
+
+            self.half_open();
+        }
+
+        match self.state
+        {
+            BreakerState::HALF_OPEN =>
+            {
+                if self.admitted >= self.config.half_open_probes
+                {
+                    return false;
+                }
+
+                self.admitted += 1;
+                true
+            },
+            _ => true,
+        }
+    }
+
+    pub fn record_success(&mut self, latency: Option<Duration>)
+    {
+        if let (Some(threshold), Some(latency)) = (self.config.latency_threshold, latency)
+        {
+            if latency > threshold
+            {
+                self.record_failure();
+                return;
+            }
+        }
+
+        match self.state
+        {
+            BreakerState::CLOSED => { self.failures = 0; },
+            BreakerState::HALF_OPEN =>
+            {
+                self.passed += 1;
+
+                if self.passed >= self.config.half_open_probes
+                {
+                    self.state    = BreakerState::CLOSED;
+                    self.failures = 0;
+                }
+            },
+            BreakerState::OPEN(_) => {},
+        }
+    }
+
+    pub fn record_failure(&mut self)
+    {
+        match self.state
+        {
+            BreakerState::CLOSED =>
+            {
+                self.failures += 1;
+
+                if self.failures >= self.config.failure_threshold
+                {
+                    self.open();
+                }
+            },
+            BreakerState::HALF_OPEN => self.open(),
+            BreakerState::OPEN(_) => {},
+        }
+    }
+
+    fn open(&mut self)
+    {
+        self.state    = BreakerState::OPEN(Instant::now());
+        self.failures = 0;
+    }
+
+    fn half_open(&mut self)
+    {
+        self.state    = BreakerState::HALF_OPEN;
+        self.admitted = 0;
+        self.passed   = 0;
+    }
+}
+
+#[test]
+fn test_circuit_breaker_open_and_close()
+{
+    let config = BreakerConfig { failure_threshold: 3, latency_threshold: None, open_for: Duration::from_millis(50), half_open_probes: 2 };
+
+    let mut breaker = CircuitBreaker::new(config);
+
+    breaker.record_failure();
+    breaker.record_failure();
+    breaker.record_success(None);
+    breaker.record_failure();
+    breaker.record_failure();
+    assert!(breaker.get_state() == BreakerState::CLOSED);
+
+    breaker.record_failure();
+    assert!(breaker.get_state_name() == "OPEN");
+    assert!(!breaker.is_available());
+    assert!(!breaker.allow());
+
+    std::thread::sleep(Duration::from_millis(60));
+
+    // Only the trial connections get through
+    assert!(breaker.is_available());
+    assert!(breaker.allow());
+    assert!(breaker.get_state() == BreakerState::HALF_OPEN);
+    assert!(breaker.allow());
+    assert!(!breaker.allow());
+    assert!(!breaker.is_available());
+
+    breaker.record_success(None);
+    assert!(breaker.get_state() == BreakerState::HALF_OPEN);
+    breaker.record_success(None);
+    assert!(breaker.get_state() == BreakerState::CLOSED);
+}
+
+#[test]
+fn test_circuit_breaker_half_open_failure_and_latency()
+{
+    let config = BreakerConfig { failure_threshold: 2, latency_threshold: Some(Duration::from_millis(20)), open_for: Duration::from_millis(50), half_open_probes: 1 };
+
+    let mut breaker = CircuitBreaker::new(config);
+
+    // Slow connects count as failures
+    breaker.record_success(Some(Duration::from_millis(30)));
+    breaker.record_success(Some(Duration::from_millis(30)));
+    assert!(breaker.get_state_name() == "OPEN");
+
+    std::thread::sleep(Duration::from_millis(60));
+
+    assert!(breaker.allow());
+    breaker.record_failure();
+    assert!(breaker.get_state_name() == "OPEN");
+    assert!(!breaker.allow());
+}
@@ -5,40 +5,182 @@ use std::collections::*;
 
 use std::io::{Write, Read};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use std::sync::Arc;
-use rustls;
 
 use x509_parser::prelude::*;
 
 use log::{trace, debug, info, warn, error};
 
+use crate::rate_limit::{RateLimitPolicy, TokenBucket};
+use crate::listener::IdentitySource;
+use crate::proxy_protocol::{self, ProxyHeader};
+
+// Below this many bytes in a bandwidth bucket a client is reported as throttled
+const THROTTLE_THRESHOLD : usize = 2048;
+
+// Longest a single attempt to connect to an upstream may take
+pub const UPSTREAM_CONNECT_TIMEOUT : Duration = Duration::from_millis(100);
+
 pub struct Client
 {
     email                : String,
+    role                 : Option<String>,
+    common_name          : Option<String>, // for listeners that identify clients by the CN of their certificate
     connections          : Vec<Connection>,
-    cxn_time             : i64,
-    cxn_cnt              : usize,
+    rate_limiter         : TokenBucket,
+    bandwidth            : Option<TokenBucket>, // bytes per second shared by all connections
+    throttled            : bool,
+    poll_cnt             : usize,
+    server_bytes         : HashMap<(u32, u32), u64>, // bytes relayed per upstream server group and server since the last drain
     allowed_server_group : u32,
+    other_server_groups  : Vec<u32>, // can also be routed here by a listener
 }
 
 impl Client
 {
     pub fn new(email: String, allowed_server_group: u32) -> Self
     {
-        Self { email, connections: vec![], cxn_time: i64::MIN, cxn_cnt: 0,  allowed_server_group }
+        Self { email, role: None, common_name: None, connections: vec![], rate_limiter: TokenBucket::new(RateLimitPolicy::default()), bandwidth: None, throttled: false, poll_cnt: 0, server_bytes: HashMap::new(), allowed_server_group, other_server_groups: vec![] }
     }
 
-    pub fn poll(&mut self) -> Result<(), Box<dyn std::error::Error>>
+    pub fn set_bandwidth_limit(&mut self, policy: RateLimitPolicy)
+    {
+        self.bandwidth = Some(TokenBucket::new(policy));
+    }
+
+    pub fn get_bandwidth_tokens(&mut self) -> Option<f64>
+    {
+        self.bandwidth.as_mut().map(|v| v.available())
+    }
+
+    pub fn is_throttled(&self) -> bool
+    {
+        self.throttled
+    }
+
+    pub fn set_role(&mut self, role: String)
+    {
+        self.role = Some(role);
+    }
+
+    pub fn get_role(&self) -> Option<&str>
+    {
+        self.role.as_deref()
+    }
+
+    pub fn get_email(&self) -> &str
+    {
+        &self.email
+    }
+
+    pub fn set_common_name(&mut self, common_name: String)
+    {
+        self.common_name = Some(common_name);
+    }
+
+    pub fn get_common_name(&self) -> Option<&str>
+    {
+        self.common_name.as_deref()
+    }
+
+    pub fn set_rate_limit(&mut self, policy: RateLimitPolicy)
+    {
+        self.rate_limiter.set_policy(policy);
+    }
+
+    pub fn get_rate_limit_tokens(&mut self) -> f64
+    {
+        self.rate_limiter.available()
+    }
+
+    // Returns the number of bytes relayed in both directions.
+    // The client's bucket is split evenly between its active connections, the
+    // connection that gets the first pick of any remainder rotates every poll.
+    // group_budgets holds this client's share of the bucket of each server group
+    // its connections are routed to, split evenly between its connections in that group.
+    // The group buckets are charged by the caller from drain_server_bytes.
+    pub fn poll(&mut self, group_budgets: &HashMap<u32, usize>) -> Result<usize, Box<dyn std::error::Error>>
     {
+        let mut budget = usize::MAX;
+
+        if let Some(bucket) = &mut self.bandwidth
+        {
+            budget = budget.min(bucket.available() as usize);
+        }
+
+        self.throttled = budget < THROTTLE_THRESHOLD || group_budgets.values().any(|v| *v < THROTTLE_THRESHOLD);
+
+        let active : Vec<usize> = self.connections.iter().enumerate().filter(|(_, v)| v.conn_state == ConnState::OKAY).map(|(i, _)| i).collect();
+
+        if active.is_empty()
+        {
+            return Ok(0);
+        }
+
+        let mut per_group : HashMap<u32, usize> = HashMap::new();
+
+        for i in active.iter()
+        {
+            *per_group.entry(self.connections[*i].get_upstream_server_group()).or_insert(0) += 1;
+        }
+
+        let share = budget / active.len();
+        let remainder = budget % active.len();
+        let start = self.poll_cnt % active.len();
+
+        self.poll_cnt = self.poll_cnt.wrapping_add(1);
+
         // poll connections
-		for cxn in self.connections.iter_mut()
+        let mut relayed : usize = 0;
+		for j in 0..active.len()
         {
-            cxn.poll()?;
+            let cxn = &mut self.connections[active[(start + j) % active.len()]];
+            let group = cxn.get_upstream_server_group();
+
+            let mut cxn_budget = if j < remainder { share + 1 } else { share };
+
+            if let Some(group_budget) = group_budgets.get(&group)
+            {
+                cxn_budget = cxn_budget.min(group_budget / per_group.get(&group).copied().unwrap_or(1));
+            }
+
+            let cxn_relayed = cxn.poll(cxn_budget)?;
+
+            if cxn_relayed > 0
+            {
+                *self.server_bytes.entry((group, cxn.get_upstream_server_id())).or_insert(0) += cxn_relayed as u64;
+            }
+
+            relayed += cxn_relayed;
         }
 
-        Ok(())
+        if let Some(bucket) = &mut self.bandwidth
+        {
+            bucket.consume(relayed as f64);
+        }
+
+        Ok(relayed)
+    }
+
+    // Server groups the active connections are routed to, which may be
+    // outside the client's own group by a listener or a fallback
+    pub fn get_active_server_groups(&self) -> Vec<u32>
+    {
+        let mut groups : Vec<u32> = self.connections.iter().filter(|v| v.conn_state == ConnState::OKAY).map(|v| v.get_upstream_server_group()).collect();
+
+        groups.sort();
+        groups.dedup();
+
+        groups
+    }
+
+    // Bytes relayed per upstream server group and server since the last call.
+    // Connections may be routed outside the client's own group by a listener or a fallback.
+    pub fn drain_server_bytes(&mut self) -> HashMap<(u32, u32), u64>
+    {
+        std::mem::take(&mut self.server_bytes)
     }
 
     pub fn get_server_group(&self) -> u32
@@ -46,33 +188,60 @@ impl Client
         self.allowed_server_group
     }
 
-    pub fn add_connection(&mut self, cxn: Connection)
+    pub fn allow_server_group(&mut self, group: u32)
     {
-        // convert current ts to i64 and divide 30 to give current 30s period
-        let now : i64 = chrono::Utc::now().timestamp() / 30;
+        self.other_server_groups.push(group);
+    }
 
-        let mut ok = true;
+    pub fn is_server_group_allowed(&self, group: u32) -> bool
+    {
+        group == self.allowed_server_group || self.other_server_groups.contains(&group)
+    }
 
-        if now == self.cxn_time
-        {
-            if self.cxn_cnt >= 10
-            {
-                ok = false;
-                error!("Client rate limit hit for {}", self.email);
-            }
-        }
-        // cxn_time does not match start a new 30s period
-        else
+    // Must be called before an upstream is picked for the connection,
+    // an admitted connection is expected to be added with add_connection
+    pub fn check_admission(&mut self) -> Result<(), Rejection>
+    {
+        if !self.rate_limiter.try_take(1.0)
         {
-            self.cxn_time = now;
-            self.cxn_cnt  = 0;
+            error!("Client rate limit hit for {} (policy: {:?})", self.email, self.rate_limiter.get_policy());
+            return Err(Rejection::RATE_LIMITED);
         }
 
-        if ok
+        debug!("Client {} rate limit tokens left: {:.2}", self.email, self.rate_limiter.available());
+
+        Ok(())
+    }
+
+    pub fn add_connection(&mut self, cxn: Connection)
+    {
+        self.connections.push(cxn);
+    }
+
+    pub fn get_connection_count(&self) -> usize
+    {
+        self.connections.len()
+    }
+
+    // Has connections that relay on the next poll
+    pub fn is_active(&self) -> bool
+    {
+        self.connections.iter().any(|v| v.conn_state == ConnState::OKAY)
+    }
+
+    // Close the open connections to a server, they are removed on the next cleanup.
+    // Returns how many were closed.
+    pub fn close_server_connections(&mut self, serv_group: u32, serv_id: u32, reason: ConnState) -> usize
+    {
+        let mut closed = 0;
+
+        for cxn in self.connections.iter_mut().filter(|v| v.conn_state == ConnState::OKAY && v.upstream_serv_group == serv_group && v.upstream_serv_id == serv_id)
         {
-            self.connections.push(cxn);
-            self.cxn_cnt += 1;
+            cxn.close(reason.clone());
+            closed += 1;
         }
+
+        closed
     }
 
     pub fn cleanup_connections(&mut self) -> Vec<Connection>
@@ -84,10 +253,13 @@ impl Client
             match cxn.conn_state
             {
                 ConnState::UP_DISCONNECT    |
+                ConnState::UP_RESET         |
                 ConnState::UP_TIMEOUT       | 
                 ConnState::DOWN_DISCONNECT  |
                 ConnState::DOWN_TIMEOUT     |
-                ConnState::DOWN_ENC_ERR     =>
+                ConnState::DOWN_ENC_ERR     |
+                ConnState::MAX_LIFETIME     |
+                ConnState::DRAIN_DEADLINE   =>
                 {
                     to_remove.push(i);
                 }
@@ -178,7 +350,10 @@ fn test_client_rate_limiter()
 
         let cxn = Connection::new(down_stream, up_stream, tls_conn, 0, 0).unwrap();
 
-        cli.add_connection(cxn);
+        if cli.check_admission().is_ok()
+        {
+            cli.add_connection(cxn);
+        }
     }
 
 	println!("{}", cli.connections.len());
@@ -186,12 +361,64 @@ fn test_client_rate_limiter()
 }
 
 
-#[derive(Clone, PartialEq, Eq)]
+// Time limits applied to connections.
+// The handshake limit is taken from the listener, the rest can be
+// overridden per server group.
+#[derive(Clone, Debug)]
+pub struct Timeouts
+{
+    pub handshake       : Duration,
+    pub down_idle       : Duration,
+    pub up_idle         : Duration,
+    pub max_lifetime    : Duration,
+}
+
+impl Default for Timeouts
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            handshake       : Duration::from_secs(10),
+            down_idle       : Duration::from_secs(300),
+            up_idle         : Duration::from_secs(300),
+            max_lifetime    : Duration::from_secs(3600),
+        }
+    }
+}
+
+// Reasons a connection is turned away
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Rejection
+{
+    RATE_LIMITED,
+    CONCURRENCY_LIMIT,
+    QUEUE_TIMEOUT,
+    PENDING_LIMIT,
+    TOTAL_LIMIT,
+    UNKNOWN_CLIENT,
+    IDENTITY_NOT_ALLOWED,
+    UNKNOWN_SERVER_GROUP,
+    NO_HEALTHY_SERVER,
+    UPSTREAM_CONNECT_FAILED,
+    IP_DENIED,
+    IP_BANNED,
+    ACCEPT_RATE_LIMIT,
+    HANDSHAKE_FAILURE_LIMIT,
+    GROUP_NOT_ALLOWED,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 enum PartialConnState
 {
+    PROXY_HEADER, // waiting on the PROXY protocol header of a front load balancer
+    PROXY_ERROR, // no usable header, not held against the front load balancer
     INIT,
     COMPLETED,
     ERROR,
+    HANDSHAKE_TIMEOUT,
+    NO_HANDSHAKE, // closed or timed out before sending anything, e.g. an L4 health check or a port scan
 }
 
 
@@ -201,15 +428,92 @@ pub struct PartialConnection
     down_stream         : std::net::TcpStream,
     tls_conn            : rustls::ServerConnection,
     state               : PartialConnState,
-    email_address       : Option<String>,
-    
+    identity            : Option<String>, // from the certificate, as named by the listener's identity source
+    client              : Option<String>, // email of the client record the identity maps to
+    created             : Instant,
+    handshake_timeout   : Duration,
+    peer_addr           : Option<std::net::SocketAddr>, // the client, as told by a PROXY protocol header if there is one
+    local_addr          : Option<std::net::SocketAddr>,
+    needs_screening     : bool, // the client address came from a PROXY protocol header and is not checked yet
+    listener            : usize, // index of the listener it came in on
+    identity_source     : IdentitySource,
+    hello_seen          : bool, // the first bytes of the ClientHello have arrived
 }
 
 impl PartialConnection
 {
-    pub fn new(down_stream: std::net::TcpStream, tls_conn: rustls::ServerConnection) -> Self
+    pub fn new(down_stream: std::net::TcpStream, tls_conn: rustls::ServerConnection, handshake_timeout: Duration) -> Self
+    {
+        let peer_addr = down_stream.peer_addr().ok();
+        let local_addr = down_stream.local_addr().ok();
+
+        Self { down_stream, tls_conn, state: PartialConnState::INIT, identity: None, client: None, created: Instant::now(), handshake_timeout, peer_addr, local_addr, needs_screening: false,
+               listener: 0, identity_source: IdentitySource::EMAIL, hello_seen: false }
+    }
+
+    pub fn set_listener(&mut self, listener: usize, identity_source: IdentitySource)
     {
-        Self { down_stream, tls_conn, state: PartialConnState::INIT, email_address: None }
+        self.listener = listener;
+        self.identity_source = identity_source;
+    }
+
+    pub fn get_listener(&self) -> usize
+    {
+        self.listener
+    }
+
+    // Read a PROXY protocol header before the TLS handshake
+    pub fn expect_proxy_header(&mut self)
+    {
+        self.state = PartialConnState::PROXY_HEADER;
+    }
+
+    // Only the header is taken off the socket, the TLS records behind it are left for rustls
+    fn read_proxy_header(&mut self) -> Result<bool, Box<dyn std::error::Error>>
+    {
+        let mut buf = vec![0; ProxyHeader::needed_len(&[])];
+
+        // grows once to the length a v2 header gives
+        let n = loop
+        {
+            let n = match self.down_stream.peek(&mut buf)
+            {
+                Ok(0) => return Err("connection closed before the PROXY protocol header".into()),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e.into()),
+            };
+
+            let needed = ProxyHeader::needed_len(&buf[0..n]);
+
+            if needed <= buf.len()
+            {
+                break n;
+            }
+
+            buf.resize(needed, 0);
+        };
+
+        let (header, used) = match ProxyHeader::decode_any(&buf[0..n])?
+        {
+            Some(res) => res,
+            None if n == buf.len() => return Err("PROXY protocol header too long".into()),
+            None => return Ok(false),
+        };
+
+        self.down_stream.read_exact(&mut buf[0..used])?;
+
+        // LOCAL headers, e.g. health checks of the front load balancer, keep the socket addresses
+        if let (Some(source), Some(destination)) = (header.source, header.destination)
+        {
+            info!("Client address {source} via proxy {:?}", self.peer_addr);
+
+            self.peer_addr  = Some(source);
+            self.local_addr = Some(destination);
+            self.needs_screening = true;
+        }
+
+        Ok(true)
     }
 
     pub fn poll(&mut self) -> Result<(), Box<dyn std::error::Error>>
@@ -218,6 +522,49 @@ impl PartialConnection
 
 		match self.state
         {
+            PartialConnState::PROXY_HEADER if self.created.elapsed() > self.handshake_timeout =>
+            {
+                next_state = PartialConnState::PROXY_ERROR;
+                warn!("PROXY protocol header not received within {:?}", self.handshake_timeout);
+            },
+            PartialConnState::INIT if self.created.elapsed() > self.handshake_timeout =>
+            {
+                // Stop slow clients from holding a partial connection open forever
+                next_state = if self.hello_seen { PartialConnState::HANDSHAKE_TIMEOUT } else { PartialConnState::NO_HANDSHAKE };
+                warn!("Handshake not completed within {:?}", self.handshake_timeout);
+            },
+            PartialConnState::PROXY_HEADER =>
+            {
+                match self.read_proxy_header()
+                {
+                    Ok(true) => { next_state = PartialConnState::INIT; },
+                    Ok(false) => {}, // wait for next poll
+                    Err(e) =>
+                    {
+                        next_state = PartialConnState::PROXY_ERROR;
+                        error!("Bad PROXY protocol header from {:?}: {e}", self.peer_addr);
+                    }
+                }
+            },
+            PartialConnState::INIT if !self.hello_seen =>
+            {
+                // only a handshake that was started can fail authentication
+                let mut buf : [u8; 1] = [0; 1];
+
+                match self.down_stream.peek(&mut buf)
+                {
+                    Ok(0) => { next_state = PartialConnState::NO_HANDSHAKE; },
+                    Ok(_) =>
+                    {
+                        self.hello_seen = true;
+
+                        // carry on with the handshake straight away
+                        return self.poll();
+                    },
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {},
+                    Err(_e) => { next_state = PartialConnState::NO_HANDSHAKE; }
+                }
+            },
             PartialConnState::INIT =>
             {
                 // Handle authentication / authorisation
@@ -252,19 +599,19 @@ impl PartialConnection
                     // get cert
                     if let Some(certs) = self.tls_conn.peer_certificates()
                     {
-                        match extract_email_from_cert(&certs[0].0)
+                        match self.identity_source.extract(&certs[0].0)
                         {
                             Ok(email) =>
                             {
-                                info!("Email found: {email}");
+                                info!("Identity found: {email}");
 
-                                self.email_address = Some(email);
+                                self.identity = Some(email);
                                 next_state = PartialConnState::COMPLETED;
                             },
                             Err(e) =>
                             {
                                 next_state = PartialConnState::ERROR;
-                                error!("Email address not found in client cert.");
+                                error!("Identity not found in client cert.");
                                 error!("{e}");
                             }
                         }
@@ -290,35 +637,196 @@ impl PartialConnection
 
     pub fn is_completed(&self) -> bool
     {
-        return self.state == PartialConnState::COMPLETED;
+        self.state == PartialConnState::COMPLETED
     }
 
+    pub fn is_failed(&self) -> bool
+    {
+        self.is_auth_failure() || self.state == PartialConnState::PROXY_ERROR || self.state == PartialConnState::NO_HANDSHAKE
+    }
+
+    // The client started the TLS handshake and did not complete it
+    pub fn is_auth_failure(&self) -> bool
+    {
+        self.state == PartialConnState::ERROR || self.state == PartialConnState::HANDSHAKE_TIMEOUT
+    }
+
+    // Whether a client address from a PROXY protocol header still has to go through the listener checks
+    pub fn take_needs_screening(&mut self) -> bool
+    {
+        std::mem::take(&mut self.needs_screening)
+    }
+
+    pub fn get_state_name(&self) -> String
+    {
+        format!("{:?}", self.state)
+    }
+
+    // Tell the client we are done with it before the socket is dropped
+    pub fn reject(mut self)
+    {
+        self.tls_conn.send_close_notify();
+
+        if let Err(e) = self.tls_conn.write_tls(&mut self.down_stream)
+        {
+            warn!("Unable to send close notify to rejected client: {e}");
+        }
+    }
+
+    pub fn get_identity(&self) -> Option<String>
+    {
+        self.identity.clone()
+    }
+
+    pub fn set_client_id(&mut self, id: String)
+    {
+        self.client = Some(id);
+    }
+
+    // Set once the identity has been matched to a client
     pub fn client_id(&self) -> Option<String>
     {
-        self.email_address.clone()
+        self.client.clone()
+    }
+
+    pub fn get_peer_addr(&self) -> Option<std::net::SocketAddr>
+    {
+        self.peer_addr
+    }
+
+    // PROXY protocol v2 header describing the client for the upstream,
+    // the connection id is added once a server is picked
+    pub fn get_proxy_header(&self) -> Result<ProxyHeader, Box<dyn std::error::Error>>
+    {
+        let peer_addr = self.peer_addr.ok_or("client address unknown")?;
+
+        let mut header = ProxyHeader::new(peer_addr, self.local_addr.ok_or("local address unknown")?);
+
+        if let Some(identity) = &self.identity
+        {
+            header.add_tlv(proxy_protocol::TLV_IDENTITY, identity.as_bytes());
+        }
+
+        if let Some(certs) = self.tls_conn.peer_certificates()
+        {
+            let (_, cert) = X509Certificate::from_der(&certs[0].0)?;
+            let serial : String = cert.raw_serial().iter().map(|v| format!("{v:02x}")).collect();
+
+            header.add_tlv(proxy_protocol::TLV_CERT_SERIAL, serial.as_bytes());
+        }
+
+        if let Some(sni) = self.tls_conn.sni_hostname()
+        {
+            header.add_tlv(proxy_protocol::TLV_AUTHORITY, sni.as_bytes());
+        }
+
+        if let Some(alpn) = self.tls_conn.alpn_protocol()
+        {
+            header.add_tlv(proxy_protocol::TLV_ALPN, alpn);
+        }
+
+        Ok(header)
     }
 }
 
-fn extract_email_from_cert(cert: &Vec<u8>) -> Result<String, Box<dyn std::error::Error>>
+// A connect to an upstream started without blocking and polled until it completes,
+// as the poll loop must not wait on a slow or silent server
+pub struct UpstreamConnect
 {
-    let (rem, cert) = X509Certificate::from_der(&cert[..])?;
-    for email in cert.subject().iter_email()
+    socket  : Option<socket2::Socket>,
+    addr    : std::net::SocketAddr,
+    started : Instant,
+    timeout : Duration,
+}
+
+impl UpstreamConnect
+{
+    pub fn start(up_stream_addr: &str, timeout: Duration) -> Result<Self, Box<dyn std::error::Error>>
+    {
+        let addr : std::net::SocketAddr = up_stream_addr.parse()?;
+
+        let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, None)?;
+        socket.set_nonblocking(true)?;
+        socket.set_nodelay(true)?;
+
+        match socket.connect(&addr.into())
+        {
+            Ok(()) => {},
+            Err(e) if e.raw_os_error() == Some(libc::EINPROGRESS) || e.kind() == std::io::ErrorKind::WouldBlock => {},
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(Self { socket: Some(socket), addr, started: Instant::now(), timeout })
+    }
+
+    pub fn get_addr(&self) -> &std::net::SocketAddr
     {
-        return Ok(email.as_str()?.to_string());
+        &self.addr
     }
 
-	return Err("No email address found".into());
+    // Time since the connect was started, the round trip once it has completed
+    pub fn elapsed(&self) -> Duration
+    {
+        self.started.elapsed()
+    }
+
+    // Returns the stream once connected and Ok(None) while the connect is in progress
+    pub fn poll(&mut self) -> Result<Option<std::net::TcpStream>, Box<dyn std::error::Error>>
+    {
+        let socket = self.socket.as_ref().ok_or("connect already completed")?;
+
+        if let Some(e) = socket.take_error()?
+        {
+            return Err(e.into());
+        }
+
+        match socket.peer_addr()
+        {
+            Ok(_) =>
+            {
+                let up_stream : std::net::TcpStream = self.socket.take().ok_or("connect already completed")?.into();
+                up_stream.set_read_timeout(Some(Duration::from_millis(1)))?;
+                up_stream.set_write_timeout(Some(Duration::from_millis(1)))?;
+
+                return Ok(Some(up_stream));
+            },
+            // not connected yet
+            Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) => {},
+            Err(e) => return Err(e.into()),
+        }
+
+        if self.started.elapsed() >= self.timeout
+        {
+            return Err(format!("connect timed out after {:?}", self.timeout).into());
+        }
+
+        Ok(None)
+    }
 }
 
+// Goes out right after the connect, before any client data.
+// Takes the header encoded, so one that cannot be encoded never gets as far as a server.
+pub fn send_proxy_header(up_stream: &mut std::net::TcpStream, header: &[u8]) -> Result<(), Box<dyn std::error::Error>>
+{
+    // the socket is fresh so the header fits in its send buffer
+    up_stream.write_all(header)?;
+
+    Ok(())
+}
+
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub enum ConnState
 {
     OKAY,
     UP_DISCONNECT,
+    UP_RESET,
     UP_TIMEOUT,
     DOWN_DISCONNECT,
     DOWN_TIMEOUT,
     DOWN_ENC_ERR,
+    MAX_LIFETIME,
+    DRAIN_DEADLINE, // closed because its server was still draining at the deadline
 }
 
 pub struct Connection
@@ -329,25 +837,31 @@ pub struct Connection
     conn_state          : ConnState,
     upstream_serv_group : u32,
     upstream_serv_id    : u32,
+    timeouts            : Timeouts,
+    created             : Instant,
+    last_down_activity  : Instant,
+    last_up_activity    : Instant,
+    last_poll           : Instant,
+    bytes_relayed       : u64,
 }
 
 impl Connection
 {
     pub fn new(down_stream: std::net::TcpStream, up_stream: std::net::TcpStream, tls_conn: rustls::ServerConnection, upstream_serv_group: u32, upstream_serv_id: u32) -> Result<Self, Box<dyn std::error::Error>>
     {
-        Ok(Self { down_stream, up_stream, tls_conn, conn_state: ConnState::OKAY, upstream_serv_group, upstream_serv_id })
+        let now = Instant::now();
+
+        Ok(Self { down_stream, up_stream, tls_conn, conn_state: ConnState::OKAY, upstream_serv_group, upstream_serv_id,
+                  timeouts: Timeouts::default(), created: now, last_down_activity: now, last_up_activity: now, last_poll: now, bytes_relayed: 0 })
     }
 
-    pub fn from_partial_connection(partial_cxn: PartialConnection, upstream_serv_group: u32, upstream_serv_id: u32, up_stream_addr: &String) -> Result<Self, Box<dyn std::error::Error>>
+    pub fn from_partial_connection(partial_cxn: PartialConnection, up_stream: std::net::TcpStream, upstream_serv_group: u32, upstream_serv_id: u32, timeouts: &Timeouts) -> Result<Self, Box<dyn std::error::Error>>
     {
-        // TODO: Blocking call. Move to call with a timeout or wrap in a thread.
-        let up_stream = std::net::TcpStream::connect_timeout(&up_stream_addr.parse()?, Duration::from_millis(100))?;
-        up_stream.set_read_timeout(Some(Duration::from_millis(1)))?;
-        up_stream.set_write_timeout(Some(Duration::from_millis(1)))?;
-        up_stream.set_nonblocking(true)?;
-        up_stream.set_nodelay(true)?;
+        let mut cxn = Self::new(partial_cxn.down_stream, up_stream, partial_cxn.tls_conn, upstream_serv_group, upstream_serv_id)?;
+
+        cxn.timeouts = timeouts.clone();
 
-        Self::new(partial_cxn.down_stream, up_stream, partial_cxn.tls_conn, upstream_serv_group, upstream_serv_id)
+        Ok(cxn)
     }
 
     pub fn get_upstream_server_group(&self) -> u32
@@ -360,9 +874,17 @@ impl Connection
         self.upstream_serv_id
     }
 
-    fn poll(&mut self) -> Result<(), Box<dyn std::error::Error>>
+    // At most budget bytes are read from the two sides combined.
+    // A side that is not read for lack of budget is not idle in the meantime.
+    // Returns the number of bytes relayed.
+    fn poll(&mut self, budget: usize) -> Result<usize, Box<dyn std::error::Error>>
     {
         let mut next_state = self.conn_state.clone();
+        let mut relayed : usize = 0;
+
+        let now = Instant::now();
+        let since_last_poll = now.duration_since(self.last_poll);
+        self.last_poll = now;
 
         match self.conn_state
         {
@@ -373,12 +895,17 @@ impl Connection
                 let mut tls_stream = rustls::Stream::new(&mut self.tls_conn, &mut self.down_stream);
 
                 let mut down_buf   : [u8; 2048] = [0; 2048];
-                match tls_stream.read(&mut down_buf)
+                let down_limit = budget.min(down_buf.len());
+                let down_res = if down_limit > 0 { tls_stream.read(&mut down_buf[0..down_limit]) } else { self.last_down_activity += since_last_poll; Err(std::io::ErrorKind::WouldBlock.into()) };
+
+                match down_res
                 {
                     Ok(n) =>
                     {
                         trace!("Received: {n} bytes");
 
+                        relayed += n;
+
                         if n == 0
                         {
                             next_state = ConnState::DOWN_DISCONNECT;
@@ -386,6 +913,8 @@ impl Connection
                         }
                         else
                         {
+                            self.last_down_activity = Instant::now();
+
                             // write buffer back
                             match self.up_stream.write_all(&down_buf[0..n])
                             {
@@ -399,8 +928,8 @@ impl Connection
                                 },
                                 Err(e) =>
                                 {
-                                    next_state = ConnState::UP_DISCONNECT;
-                                    error!("UPSTREAM DC: {e}");
+                                    next_state = ConnState::UP_RESET;
+                                    error!("UPSTREAM RESET: {e}");
                                 }
                             }
                         }
@@ -417,12 +946,17 @@ impl Connection
                 }
 
                 let mut up_buf   : [u8; 2048] = [0; 2048];
-                match self.up_stream.read(&mut up_buf)
+                let up_limit = (budget - relayed).min(up_buf.len());
+                let up_res = if up_limit > 0 { self.up_stream.read(&mut up_buf[0..up_limit]) } else { self.last_up_activity += since_last_poll; Err(std::io::ErrorKind::WouldBlock.into()) };
+
+                match up_res
                 {
                     Ok(n) =>
                     {
                         trace!("Sent: {n} bytes");
 
+                        relayed += n;
+
                         if n == 0
                         {
                             next_state = ConnState::UP_DISCONNECT;
@@ -430,6 +964,8 @@ impl Connection
                         }
                         else
                         {
+                            self.last_up_activity = Instant::now();
+
                             // write buffer back
                             match tls_stream.write_all(&up_buf[0..n])
                             {
@@ -455,8 +991,18 @@ impl Connection
                     },
                     Err(e) =>
                     {
-                        next_state = ConnState::UP_DISCONNECT;
-                        error!("UPSTREAM DC: {e}");
+                        next_state = ConnState::UP_RESET;
+                        error!("UPSTREAM RESET: {e}");
+                    }
+                }
+
+                if next_state == ConnState::OKAY
+                {
+                    next_state = self.check_timeouts();
+
+                    if next_state != ConnState::OKAY
+                    {
+                        self.close(next_state.clone());
                     }
                 }
             },
@@ -470,16 +1016,405 @@ impl Connection
         }
 
         self.conn_state = next_state;
+        self.bytes_relayed += relayed as u64;
 
-        Ok(())
+        Ok(relayed)
+    }
+
+    // Let the client know we are going away rather than just dropping the socket
+    pub fn close(&mut self, reason: ConnState)
+    {
+        self.tls_conn.send_close_notify();
+        let _ = self.tls_conn.write_tls(&mut self.down_stream);
+
+        self.conn_state = reason;
+    }
+
+    pub fn get_bytes_relayed(&self) -> u64
+    {
+        self.bytes_relayed
+    }
+
+    fn check_timeouts(&self) -> ConnState
+    {
+        if self.created.elapsed() > self.timeouts.max_lifetime
+        {
+            warn!("Connection exceeded maximum lifetime of {:?}", self.timeouts.max_lifetime);
+            return ConnState::MAX_LIFETIME;
+        }
+
+        if self.last_down_activity.elapsed() > self.timeouts.down_idle
+        {
+            warn!("Client idle for longer than {:?}", self.timeouts.down_idle);
+            return ConnState::DOWN_TIMEOUT;
+        }
+
+        if self.last_up_activity.elapsed() > self.timeouts.up_idle
+        {
+            warn!("Upstream idle for longer than {:?}", self.timeouts.up_idle);
+            return ConnState::UP_TIMEOUT;
+        }
+
+        ConnState::OKAY
     }
 
     pub fn get_state(&self) -> ConnState
     {
         self.conn_state.clone()
     }
+
+    pub fn get_lifetime(&self) -> Duration
+    {
+        self.created.elapsed()
+    }
+}
+
+#[test]
+fn test_partial_connection_handshake_timeout()
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    listener.set_nonblocking(true).unwrap();
+
+	let config = crate::config::create_server_tls_config(false).unwrap();
+
+    let down_stream = TcpStream::connect(addr.clone()).unwrap();
+    down_stream.set_nonblocking(true).unwrap();
+    let tls_conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+
+    let mut par_cxn = PartialConnection::new(down_stream, tls_conn, Duration::from_millis(50));
+
+    par_cxn.poll().unwrap();
+
+    assert!(par_cxn.state == PartialConnState::INIT);
+
+    std::thread::sleep(Duration::from_millis(100));
+
+    par_cxn.poll().unwrap();
+
+    // Nothing was ever sent, not an authentication failure
+    assert!(par_cxn.state == PartialConnState::NO_HANDSHAKE);
+    assert!(par_cxn.is_failed() && !par_cxn.is_auth_failure());
+
+    // A ClientHello that was started and never finished
+    let down_stream = TcpStream::connect(addr.clone()).unwrap();
+    down_stream.set_nonblocking(true).unwrap();
+    let peer_addr = down_stream.local_addr().unwrap();
+
+    // the first connection is still in the backlog
+    let mut peer = std::iter::repeat_with(|| listener.accept().unwrap()).find(|(_, addr)| *addr == peer_addr).unwrap().0;
+    let tls_conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+
+    let mut par_cxn = PartialConnection::new(down_stream, tls_conn, Duration::from_millis(50));
+
+    peer.write_all(&[ 0x16, 0x03, 0x01 ]).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    par_cxn.poll().unwrap();
+
+    assert!(par_cxn.state == PartialConnState::INIT);
+
+    std::thread::sleep(Duration::from_millis(100));
+    par_cxn.poll().unwrap();
+
+    assert!(par_cxn.state == PartialConnState::HANDSHAKE_TIMEOUT);
+    assert!(par_cxn.is_failed() && par_cxn.is_auth_failure());
+}
+
+#[test]
+fn test_connection_timeouts()
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    listener.set_nonblocking(true).unwrap();
+
+	let config = crate::config::create_server_tls_config(false).unwrap();
+
+    let timeouts = [ Timeouts { handshake: Duration::from_secs(1), down_idle: Duration::from_millis(50), up_idle: Duration::from_secs(10), max_lifetime: Duration::from_secs(10) },
+                     Timeouts { handshake: Duration::from_secs(1), down_idle: Duration::from_secs(10), up_idle: Duration::from_millis(50), max_lifetime: Duration::from_secs(10) },
+                     Timeouts { handshake: Duration::from_secs(1), down_idle: Duration::from_secs(10), up_idle: Duration::from_secs(10), max_lifetime: Duration::from_millis(50) } ];
+
+    let expected = [ ConnState::DOWN_TIMEOUT, ConnState::UP_TIMEOUT, ConnState::MAX_LIFETIME ];
+
+    for (t, state) in timeouts.iter().zip(expected.iter())
+    {
+        let down_stream = TcpStream::connect(addr.clone()).unwrap();
+        let up_stream = TcpStream::connect(addr.clone()).unwrap();
+        down_stream.set_nonblocking(true).unwrap();
+        up_stream.set_nonblocking(true).unwrap();
+		let tls_conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+
+        let mut cxn = Connection::new(down_stream, up_stream, tls_conn, 0, 0).unwrap();
+        cxn.timeouts = t.clone();
+
+        cxn.poll(usize::MAX).unwrap();
+
+        assert!(cxn.get_state() == ConnState::OKAY);
+
+        std::thread::sleep(Duration::from_millis(100));
+
+        cxn.poll(usize::MAX).unwrap();
+
+        assert!(cxn.get_state() == *state);
+    }
+}
+
+#[test]
+fn test_client_bandwidth_shaping()
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let mut cli = Client::new("".to_string(), 0);
+
+    // No refill so only the burst is available
+    cli.set_bandwidth_limit(RateLimitPolicy::new(0.0, 4000.0));
+
+	let config = crate::config::create_server_tls_config(false).unwrap();
+
+    let mut peers : Vec<TcpStream> = vec![];
+
+    for i in 0..2
+    {
+        let down_stream = TcpStream::connect(addr.clone()).unwrap();
+        peers.push(listener.accept().unwrap().0);
+        let up_stream = TcpStream::connect(addr.clone()).unwrap();
+        let mut up_peer = listener.accept().unwrap().0;
+        down_stream.set_nonblocking(true).unwrap();
+        up_stream.set_nonblocking(true).unwrap();
+		let tls_conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+
+        // upstream has more data waiting than the client is allowed
+        up_peer.write_all(&[0; 3000]).unwrap();
+        peers.push(up_peer);
+
+        cli.connections.push(Connection::new(down_stream, up_stream, tls_conn, 0, 0).unwrap());
+    }
+
+    std::thread::sleep(Duration::from_millis(50));
+
+    let mut relayed = 0;
+    for i in 0..5
+    {
+        relayed += cli.poll(&HashMap::new()).unwrap();
+    }
+
+    assert!(relayed == 4000);
+    assert!(cli.is_throttled());
+
+    // Budget is shared evenly
+    for cxn in cli.connections.iter()
+    {
+        assert!(cxn.get_bytes_relayed() == 2000);
+    }
+}
+
+#[test]
+fn test_client_group_bandwidth_share()
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+	let config = crate::config::create_server_tls_config(false).unwrap();
+
+    // No refill so only the burst is available
+    let mut group_bandwidth = TokenBucket::new(RateLimitPolicy::new(0.0, 3000.0));
+
+    let mut clients = [ Client::new("a".to_string(), 0), Client::new("b".to_string(), 0) ];
+    let mut peers : Vec<TcpStream> = vec![];
+
+    for cli in clients.iter_mut()
+    {
+        let down_stream = TcpStream::connect(addr.clone()).unwrap();
+        peers.push(listener.accept().unwrap().0);
+        let up_stream = TcpStream::connect(addr.clone()).unwrap();
+        let mut up_peer = listener.accept().unwrap().0;
+        down_stream.set_nonblocking(true).unwrap();
+        up_stream.set_nonblocking(true).unwrap();
+		let tls_conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+
+        // each upstream has the whole group bucket waiting
+        up_peer.write_all(&[0; 3000]).unwrap();
+        peers.push(up_peer);
+
+        cli.connections.push(Connection::new(down_stream, up_stream, tls_conn, 0, 0).unwrap());
+    }
+
+    std::thread::sleep(Duration::from_millis(50));
+
+    // The first client polled only takes its share, not all it can read
+    let share = HashMap::from([ (0, group_bandwidth.available() as usize / 2) ]);
+    assert!(clients[0].poll(&share).unwrap() == 1500);
+    group_bandwidth.consume(clients[0].drain_server_bytes().values().sum::<u64>() as f64);
+
+    let share = HashMap::from([ (0, group_bandwidth.available() as usize) ]);
+    assert!(clients[1].poll(&share).unwrap() == 1500);
+}
+
+#[test]
+fn test_connection_throttled_not_idle()
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+	let config = crate::config::create_server_tls_config(false).unwrap();
+
+    let down_stream = TcpStream::connect(addr.clone()).unwrap();
+    let up_stream = TcpStream::connect(addr.clone()).unwrap();
+    down_stream.set_nonblocking(true).unwrap();
+    up_stream.set_nonblocking(true).unwrap();
+    let tls_conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+
+    let mut cxn = Connection::new(down_stream, up_stream, tls_conn, 0, 0).unwrap();
+    cxn.timeouts = Timeouts { handshake: Duration::from_secs(1), down_idle: Duration::from_millis(50), up_idle: Duration::from_millis(50), max_lifetime: Duration::from_secs(10) };
+
+    // Polls without any budget are not idle time
+    for i in 0..10
+    {
+        std::thread::sleep(Duration::from_millis(10));
+        cxn.poll(0).unwrap();
+    }
+
+    assert!(cxn.get_state() == ConnState::OKAY);
+
+    // Once it may read again nothing arrives
+    std::thread::sleep(Duration::from_millis(100));
+    cxn.poll(usize::MAX).unwrap();
+
+    assert!(cxn.get_state() == ConnState::DOWN_TIMEOUT);
+}
+
+#[test]
+fn test_partial_connection_reject_sends_close_notify()
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+	let config = crate::config::create_server_tls_config(false).unwrap();
+
+    let mut client_stream = TcpStream::connect(addr.clone()).unwrap();
+    let (down_stream, _) = listener.accept().unwrap();
+    let tls_conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+
+    let par_cxn = PartialConnection::new(down_stream, tls_conn, Duration::from_secs(10));
+
+    par_cxn.reject();
+
+    // Alert record header: content type 21 (alert)
+    let mut buf : [u8; 64] = [0; 64];
+    let n = client_stream.read(&mut buf).unwrap();
+
+    assert!(n > 0);
+    assert!(buf[0] == 21);
+}
+
+#[test]
+fn test_partial_connection_proxy_header()
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+	let config = crate::config::create_server_tls_config(false).unwrap();
+
+    let mut client_stream = TcpStream::connect(addr.clone()).unwrap();
+    let (down_stream, _) = listener.accept().unwrap();
+    down_stream.set_nonblocking(true).unwrap();
+    let tls_conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+
+    let mut par_cxn = PartialConnection::new(down_stream, tls_conn, Duration::from_secs(10));
+    par_cxn.expect_proxy_header();
+
+    // Nothing sent yet
+    par_cxn.poll().unwrap();
+    assert!(par_cxn.state == PartialConnState::PROXY_HEADER);
+
+    // Header split over two writes with the start of the TLS stream behind it
+    client_stream.write_all("PROXY TCP4 192.168.1.10 ".as_bytes()).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    par_cxn.poll().unwrap();
+    assert!(par_cxn.state == PartialConnState::PROXY_HEADER);
+
+    client_stream.write_all("127.0.0.1 51000 8443\r\nTLS".as_bytes()).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    par_cxn.poll().unwrap();
+
+    assert!(par_cxn.state == PartialConnState::INIT);
+    assert!(par_cxn.get_peer_addr() == Some("192.168.1.10:51000".parse().unwrap()));
+    assert!(par_cxn.take_needs_screening() && !par_cxn.take_needs_screening());
+    assert!(par_cxn.local_addr == Some("127.0.0.1:8443".parse().unwrap()));
+
+    let mut buf : [u8; 3] = [0; 3];
+    assert!(par_cxn.down_stream.peek(&mut buf).unwrap() == 3 && &buf == b"TLS");
+
+    // A v2 header longer than the v1 limit, here with a large identity
+    let mut client_stream = TcpStream::connect(addr.clone()).unwrap();
+    let (down_stream, _) = listener.accept().unwrap();
+    down_stream.set_nonblocking(true).unwrap();
+    let tls_conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+
+    let mut par_cxn = PartialConnection::new(down_stream, tls_conn, Duration::from_secs(10));
+    par_cxn.expect_proxy_header();
+
+    let mut header = ProxyHeader::new("192.168.1.11:51000".parse().unwrap(), "127.0.0.1:8443".parse().unwrap());
+    header.add_tlv(proxy_protocol::TLV_IDENTITY, &[ b'a'; 4000 ]);
+
+    client_stream.write_all(&header.encode().unwrap()).unwrap();
+    client_stream.write_all("TLS".as_bytes()).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    par_cxn.poll().unwrap();
+
+    assert!(par_cxn.state == PartialConnState::INIT);
+    assert!(par_cxn.get_peer_addr() == Some("192.168.1.11:51000".parse().unwrap()));
+    assert!(par_cxn.down_stream.peek(&mut buf).unwrap() == 3 && &buf == b"TLS");
+
+    // Anything but a header fails the connection
+    let mut client_stream = TcpStream::connect(addr.clone()).unwrap();
+    let (down_stream, _) = listener.accept().unwrap();
+    down_stream.set_nonblocking(true).unwrap();
+    let tls_conn = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+
+    let mut par_cxn = PartialConnection::new(down_stream, tls_conn, Duration::from_secs(10));
+    par_cxn.expect_proxy_header();
+
+    client_stream.write_all(&[ 0x16, 0x03, 0x01, 0x00 ]).unwrap();
+    std::thread::sleep(Duration::from_millis(20));
+    par_cxn.poll().unwrap();
+
+    assert!(par_cxn.is_failed() && !par_cxn.is_auth_failure());
+    assert!(par_cxn.get_peer_addr().unwrap().ip().is_loopback());
+}
+
+#[test]
+fn test_upstream_connect_nonblocking()
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+
+    let mut connect = UpstreamConnect::start(&addr, Duration::from_secs(1)).unwrap();
+    let started = Instant::now();
+    let mut up_stream = None;
+
+    while up_stream.is_none() && started.elapsed() < Duration::from_secs(1)
+    {
+        up_stream = connect.poll().unwrap();
+    }
+
+    let (_, peer) = listener.accept().unwrap();
+    assert!(up_stream.unwrap().local_addr().unwrap() == peer);
+
+    // Nothing listening once the listener is gone
+    drop(listener);
+
+    let mut connect = UpstreamConnect::start(&addr, Duration::from_secs(1)).unwrap();
+    let started = Instant::now();
+    let mut res = connect.poll();
+
+    while matches!(res, Ok(None)) && started.elapsed() < Duration::from_secs(1)
+    {
+        res = connect.poll();
+    }
+
+    assert!(res.is_err());
 }
 
 // I had more connection tests, but creating them with encryption was remaking the client and loadbalancer code again.
 // So removed.
-
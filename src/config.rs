@@ -1,8 +1,21 @@
-use crate::{ LoadBalancer,  client::Client, server::ServerGroup, server::HealthChecker };
+use crate::{ LoadBalancer,  client::Client, client::Timeouts, server::ServerGroup, server::ServerOptions, server::Tier, server::BackupPolicy, server::Threshold, server::NoHealthyPolicy, server::PassiveHealthConfig, server::RetryPolicy, server::HealthCheckConfig, server::HealthChecker };
+use crate::balancer::{Strategy, HashKey};
+use crate::probe::ProbeKind;
+use crate::breaker::BreakerConfig;
+use crate::rate_limit::{RateLimitPolicy, ConcurrencyLimits, ConcurrencyCap, OverloadPolicy};
 use std::sync::Arc;
 use rustls::server::AllowAnyAuthenticatedClient;
 use rustls::{self, RootCertStore};
 use std::io::{BufReader};
+use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use crate::listener::{ListenerConfig, IdentityRules, IdentitySource};
+use crate::abuse::AbuseConfig;
+use crate::discovery::{Discovery, DnsDiscovery, FileDiscovery, FileSource};
+use crate::dns::{DnsName, Resolver};
+
+// How often service files are looked at
+const FILE_DISCOVERY_INTERVAL : Duration = Duration::from_secs(2);
 
 
 fn load_certs(filename: &str) -> Result<Vec<rustls::Certificate>, Box<dyn std::error::Error>>
@@ -34,18 +47,18 @@ fn load_private_key(filename: &str) -> Result<rustls::PrivateKey, Box<dyn std::e
         }
     }
 
-    return Err(format!("no keys found in {:?} (encrypted keys not supported)", filename).into());
+    Err(format!("no keys found in {:?} (encrypted keys not supported)", filename).into())
 }
 
 pub fn create_server_tls_config(other_certs: bool) -> Result<Arc<rustls::ServerConfig>, Box<dyn std::error::Error>>
 {
     let roots = if !other_certs
                 {
-                    load_certs("certs/cert/ec-cacert.pem".into())?
+                    load_certs("certs/cert/ec-cacert.pem")?
                 }
                 else
                 {
-                    load_certs("other_certs/cert/ec-cacert.pem".into())?
+                    load_certs("other_certs/cert/ec-cacert.pem")?
                 };
 
     let mut client_auth_roots = RootCertStore::empty();
@@ -101,11 +114,11 @@ pub fn create_client_tls_config(other_certs: bool, id: &String) -> Result<Arc<ru
 
     let certfile = if !other_certs
                    {
-                       std::fs::File::open(&"certs/cert/ec-cacert.pem")?
+                       std::fs::File::open("certs/cert/ec-cacert.pem")?
                    }
                    else
                    {
-                       std::fs::File::open(&"other_certs/cert/ec-cacert.pem")?
+                       std::fs::File::open("other_certs/cert/ec-cacert.pem")?
                    };
 
     let mut reader = BufReader::new(certfile);
@@ -149,34 +162,187 @@ pub fn create_client_tls_config(other_certs: bool, id: &String) -> Result<Arc<ru
     Ok(Arc::new(conf))
 }
 
-pub fn load_configuration(port: u16, other_certs: bool) -> Result<LoadBalancer, Box<dyn std::error::Error>>
+pub fn load_configuration(binds: &[IpAddr], port: u16, other_certs: bool) -> Result<LoadBalancer, Box<dyn std::error::Error>>
 {
 	let tls_conf = create_server_tls_config(other_certs)?;
 
-    let mut lb = LoadBalancer::new(tls_conf, port)?;
+    // One listener per bind address, an IPv6 address is dual stack unless v6_only is set.
+    // Listeners can also restrict who may connect and route to a group of their own, e.g.
+    // for clients allowed in it with client.allow_server_group(1)
+    // ListenerConfig { identity: IdentityRules { source: IdentitySource::COMMON_NAME, allowed: Some(vec!["fourth".into()]) },
+    //                  default_group: Some(1), ..ListenerConfig::new("batch".into(), "[::]:9443".parse()?, Arc::clone(&tls_conf)) }
+    // Behind an L4 load balancer the client address comes from its PROXY protocol header, e.g.
+    // ListenerConfig { accept_proxy: Some(acl::parse_cidrs(&[ "10.0.0.0/8" ])?), ..ListenerConfig::new(..) }
+    // and source addresses can be allowed or denied before the handshake, e.g.
+    // ListenerConfig { acl: IpAcl { allow: Some(acl::parse_cidrs(&[ "192.168.0.0/16" ])?), deny: vec![] }, ..ListenerConfig::new(..) }
+    let listeners = binds.iter().map(|ip| ListenerConfig::new(format!("tls-{}", SocketAddr::new(*ip, port)), SocketAddr::new(*ip, port), Arc::clone(&tls_conf))).collect();
+
+    let mut lb = LoadBalancer::with_listeners(listeners)?;
+
+    // Listener wide timeouts, server groups may override the idle and lifetime limits
+    lb.timeouts = Timeouts
+                  {
+                      handshake       : Duration::from_secs(10),
+                      down_idle       : Duration::from_secs(300),
+                      up_idle         : Duration::from_secs(300),
+                      max_lifetime    : Duration::from_secs(3600),
+                  };
+
+    // Concurrent connection caps
+    lb.limits = ConcurrencyLimits
+                {
+                    per_identity    : Some(ConcurrencyCap::new(20, OverloadPolicy::QUEUE)),
+                    pending         : Some(ConcurrencyCap::new(256, OverloadPolicy::QUEUE)),
+                    total           : Some(ConcurrencyCap::new(1024, OverloadPolicy::REJECT)),
+                    queue_timeout   : Duration::from_secs(5),
+                };
 
-    lb.clients.insert("first@first.com".into(), Client::new("first@first.com".into(), 0));
-    lb.clients.insert("second@second.com".into(), Client::new("second@second.com".into(), 1));
-    lb.clients.insert("third@third.com".into(), Client::new("third@third.com".into(), 2));
-    lb.clients.insert("fourth@fourth.com".into(), Client::new("fourth@fourth.com".into(), 3));
+    // Per source IP limits checked before any TLS work: sources that keep
+    // failing the handshake are refused and then banned for a while
+    lb.abuse.set_config(AbuseConfig
+                {
+                    accept_rate     : Some(RateLimitPolicy::new(20.0, 50.0)),
+                    failure_rate    : Some(RateLimitPolicy::new(1.0 / 6.0, 10.0)),
+                    ban_after       : Some(20),
+                    ban_window      : Duration::from_secs(60),
+                    ban_for         : Duration::from_secs(600),
+                });
+
+    // Connection rate limits, resolved per client as identity -> role -> server group -> default
+    lb.rate_limits.default = RateLimitPolicy::new(10.0 / 30.0, 10.0);
+    lb.rate_limits.by_role.insert("batch".into(), RateLimitPolicy::new(1.0, 20.0));
+    lb.rate_limits.by_group.insert(1, RateLimitPolicy::new(1.0 / 30.0, 5.0));
+
+    lb.add_client(Client::new("first@first.com".into(), 0));
+    lb.add_client(Client::new("second@second.com".into(), 1));
+    lb.add_client(Client::new("third@third.com".into(), 2));
+
+    // Bulk transfer client, limited to 1MB/s with a 4MB burst
+    let mut fourth = Client::new("fourth@fourth.com".into(), 3);
+    fourth.set_role("batch".into());
+    fourth.set_common_name("fourth".into());
+    fourth.set_bandwidth_limit(RateLimitPolicy::new(1024.0 * 1024.0, 4.0 * 1024.0 * 1024.0));
+    lb.add_client(fourth);
 
     let mut sg0 = ServerGroup::new(0);
 
-    sg0.add_server(0, "127.0.0.1:2500".into());
-    sg0.add_server(1, "127.0.0.1:2501".into());
-    sg0.add_server(2, "127.0.0.1:2502".into());
+    sg0.set_strategy(Strategy::PEAK_EWMA);
+    sg0.set_health_check(HealthCheckConfig
+                         {
+                             interval    : Duration::from_secs(10),
+                             timeout     : Duration::from_secs(1),
+                             jitter      : Duration::from_secs(2),
+                             rise        : 2,
+                             fall        : 3,
+                             max_backoff : Duration::from_secs(120),
+                             ..HealthCheckConfig::default()
+                         });
+    sg0.set_slow_start(Duration::from_secs(60));
+    sg0.set_passive_health(PassiveHealthConfig { max_failures: 5, window: Duration::from_secs(30) });
+    sg0.set_retry_policy(RetryPolicy { max_retries: 2, budget: Duration::from_millis(250) });
+    sg0.set_circuit_breaker(BreakerConfig
+                            {
+                                failure_threshold   : 3,
+                                latency_threshold   : Some(Duration::from_millis(50)),
+                                open_for            : Duration::from_secs(5),
+                                half_open_probes    : 2,
+                            });
+
+    // Server 0 is the big machine, server 2 a small one
+    sg0.set_no_healthy_policy(NoHealthyPolicy::QUEUE(Duration::from_secs(5)));
+    sg0.set_backup_policy(BackupPolicy { activate_below: Threshold::PERCENT(50), failback_after: Duration::from_secs(30) });
+    sg0.add_server_with_options(0, "127.0.0.1:2500".into(), ServerOptions { weight: 4, max_connections: None, tier: Tier::PRIMARY });
+    sg0.add_server_with_options(1, "127.0.0.1:2501".into(), ServerOptions { weight: 2, max_connections: None, tier: Tier::PRIMARY });
+    sg0.add_server_with_options(2, "127.0.0.1:2502".into(), ServerOptions { weight: 1, max_connections: Some(100), tier: Tier::BACKUP });
+
+    sg0.set_bandwidth_limit(RateLimitPolicy::new(10.0 * 1024.0 * 1024.0, 20.0 * 1024.0 * 1024.0));
+
+    // Servers or the whole group can start out in maintenance, closing what is left after the deadline, e.g.
+    // sg0.drain_server(&1, Some(Duration::from_secs(300)));
+    // sg0.drain_group(None);
+    // At runtime see LoadBalancer::admin_command and the --maintenance-file option.
 
     lb.server_groups.insert(0, sg0);
     
     let mut sg1 = ServerGroup::new(1);
 
+    // Upstreams in this group keep per user caches
+    sg1.set_strategy(Strategy::CONSISTENT_HASH(HashKey::IDENTITY));
+    sg1.set_health_check(HealthCheckConfig
+                         {
+                             kind        : ProbeKind::TCP_CONNECT,
+                             port        : None,
+                             interval    : Duration::from_secs(30),
+                             timeout     : Duration::from_secs(1),
+                             jitter      : Duration::from_secs(5),
+                             rise        : 1,
+                             fall        : 2,
+                             max_backoff : Duration::from_secs(300),
+                             proxy_protocol : false, // set from the group
+                         });
+    sg1.set_passive_health(PassiveHealthConfig { max_failures: 3, window: Duration::from_secs(30) });
+    sg1.set_retry_policy(RetryPolicy { max_retries: 1, budget: Duration::from_millis(200) });
+    sg1.set_no_healthy_policy(NoHealthyPolicy::FALLBACK(0));
+    // upstreams see the client address and identity in a PROXY protocol v2 header
+    sg1.set_proxy_protocol(true);
+
     sg1.add_server(3, "127.0.0.1:2503".into());
     sg1.add_server(4, "127.0.0.1:2504".into());
     sg1.add_server(5, "127.0.0.1:2505".into());
 
+    sg1.set_timeouts(Timeouts
+                     {
+                         handshake       : Duration::from_secs(10),
+                         down_idle       : Duration::from_secs(60),
+                         up_idle         : Duration::from_secs(60),
+                         max_lifetime    : Duration::from_secs(600),
+                     });
+
     lb.server_groups.insert(1, sg1);
 
-    return Ok(lb);
+    // Groups can also be filled and kept up to date from DNS, see add_discovery, e.g.
+    // add_discovery(&mut lb, "2=srv:_upstream._tcp.example.com")?;
+    // or from the files written out by the orchestration
+    // add_discovery(&mut lb, "3=dir:batch:/etc/lb/services")?;
+
+    Ok(lb)
+}
+
+// Keep a configured server group filled from a discovery source given as
+// <group>=srv:<name> for SRV records, <group>=host:<name>:<port> for A/AAAA records,
+// <group>=file:<service>:<path> for a JSON file of services or
+// <group>=dir:<service>:<path> for a directory with a JSON file per service
+pub fn add_discovery(lb: &mut LoadBalancer, spec: &str) -> Result<(), Box<dyn std::error::Error>>
+{
+    let (group, source) = spec.split_once('=').ok_or_else(|| format!("discovery {spec}: expected <group>=<source>"))?;
+    let group : u32 = group.trim().parse().map_err(|e| format!("discovery {spec}: bad group: {e}"))?;
+
+    if !lb.server_groups.contains_key(&group)
+    {
+        return Err(format!("discovery {spec}: unknown server group {group}").into());
+    }
+
+    let provider : Box<dyn Discovery> = match source.split_once(':')
+    {
+        Some(("srv", name)) => Box::new(DnsDiscovery::new(Resolver::system(), DnsName::SRV(name.into()))),
+        Some(("host", name)) =>
+        {
+            let (host, port) = name.rsplit_once(':').ok_or_else(|| format!("discovery {spec}: expected host:<name>:<port>"))?;
+            let port : u16 = port.parse().map_err(|e| format!("discovery {spec}: bad port: {e}"))?;
+
+            Box::new(DnsDiscovery::new(Resolver::system(), DnsName::HOST(host.into(), port)))
+        },
+        Some((kind @ ("file" | "dir"), name)) =>
+        {
+            let (service, path) = name.split_once(':').ok_or_else(|| format!("discovery {spec}: expected {kind}:<service>:<path>"))?;
+            let source = if kind == "file" { FileSource::FILE(path.into()) } else { FileSource::DIRECTORY(path.into()) };
+
+            Box::new(FileDiscovery::new(source, service.into(), FILE_DISCOVERY_INTERVAL))
+        },
+        _ => return Err(format!("discovery {spec}: unknown source, expected srv:, host:, file: or dir:").into()),
+    };
+
+    lb.add_discovery(group, provider)
 }
 
 
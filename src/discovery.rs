@@ -0,0 +1,337 @@
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+
+use std::time::Duration;
+
+use log::{info, warn, error};
+
+use crate::dns::{DnsName, Resolver};
+use crate::server::{ServerOptions, Tier};
+
+// How long to wait before trying again after a failed lookup
+const DISCOVERY_RETRY : Duration = Duration::from_secs(5);
+
+// A server as reported by a discovery provider
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiscoveredServer
+{
+    pub addr    : String,
+    pub opts    : ServerOptions,
+}
+
+// Source of the servers of a group. Providers run on their own worker
+// thread and may block, e.g. on a DNS query.
+pub trait Discovery: Send
+{
+    // The current servers, sorted by address, and how long until the next look
+    fn discover(&mut self) -> Result<(Vec<DiscoveredServer>, Duration), Box<dyn std::error::Error>>;
+
+    fn name(&self) -> String;
+}
+
+// Servers of a group resolved from A/AAAA or SRV records, refreshed when the TTL runs out.
+// SRV records with the lowest priority are primaries, the rest are backups.
+pub struct DnsDiscovery
+{
+    resolver    : Resolver,
+    name        : DnsName,
+}
+
+impl DnsDiscovery
+{
+    pub fn new(resolver: Resolver, name: DnsName) -> Self
+    {
+        Self { resolver, name }
+    }
+}
+
+impl Discovery for DnsDiscovery
+{
+    fn discover(&mut self) -> Result<(Vec<DiscoveredServer>, Duration), Box<dyn std::error::Error>>
+    {
+        let resolved = self.resolver.resolve(&self.name)?;
+
+        let top_priority = resolved.servers.iter().map(|v| v.priority).min().unwrap_or(0);
+
+        let mut servers : Vec<DiscoveredServer> = resolved.servers.iter().map(|v|
+        {
+            let tier = if v.priority == top_priority { Tier::PRIMARY } else { Tier::BACKUP };
+
+            DiscoveredServer { addr: v.addr.to_string(), opts: ServerOptions { weight: (v.weight as u32).max(1), tier, ..ServerOptions::default() } }
+        }).collect();
+
+        servers.sort_by(|a, b| a.addr.cmp(&b.addr));
+        servers.dedup_by(|a, b| a.addr == b.addr);
+
+        Ok((servers, resolved.ttl))
+    }
+
+    fn name(&self) -> String
+    {
+        format!("dns {:?}", self.name)
+    }
+}
+
+// Where a FileDiscovery reads its servers from
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileSource
+{
+    // One JSON file for all services: { "<service>": [ <server>, .. ], .. }
+    FILE(PathBuf),
+    // A directory with one JSON file per service: <dir>/<service>.json holding [ <server>, .. ]
+    DIRECTORY(PathBuf),
+}
+
+// Servers of a group as written out by the orchestration. A server is
+// { "addr": "10.0.0.1:2500", "weight": 2, "max_connections": 100, "backup": false }
+// where everything but addr is optional. The file is read on every look
+// but only parsed again once its contents change.
+pub struct FileDiscovery
+{
+    source      : FileSource,
+    service     : String,
+    interval    : Duration,
+    last_seen   : Option<u64>, // hash of the contents
+    servers     : Vec<DiscoveredServer>,
+}
+
+impl FileDiscovery
+{
+    pub fn new(source: FileSource, service: String, interval: Duration) -> Self
+    {
+        Self { source, service, interval, last_seen: None, servers: vec![] }
+    }
+
+    fn get_path(&self) -> PathBuf
+    {
+        match &self.source
+        {
+            FileSource::FILE(path) => path.clone(),
+            FileSource::DIRECTORY(dir) => dir.join(format!("{}.json", self.service)),
+        }
+    }
+
+    fn parse(&self, contents: &str) -> Result<Vec<DiscoveredServer>, Box<dyn std::error::Error>>
+    {
+        let json : serde_json::Value = serde_json::from_str(contents)?;
+
+        let list = match &self.source
+        {
+            FileSource::FILE(_) => json.get(&self.service).ok_or_else(|| format!("service {} not in file", self.service))?,
+            FileSource::DIRECTORY(_) => &json,
+        };
+
+        let list = list.as_array().ok_or_else(|| format!("servers of service {} are not a list", self.service))?;
+
+        let mut servers = vec![];
+
+        for entry in list.iter()
+        {
+            let addr = entry.get("addr").and_then(|v| v.as_str()).ok_or("server without an addr")?;
+
+            addr.parse::<std::net::SocketAddr>().map_err(|e| format!("server addr {addr}: {e}"))?;
+
+            let mut opts = ServerOptions::default();
+
+            if let Some(weight) = entry.get("weight")
+            {
+                opts.weight = weight.as_u64().ok_or_else(|| format!("server {addr} weight is not a number"))?.max(1) as u32;
+            }
+
+            if let Some(max_connections) = entry.get("max_connections")
+            {
+                opts.max_connections = Some(max_connections.as_u64().ok_or_else(|| format!("server {addr} max_connections is not a number"))? as usize);
+            }
+
+            if entry.get("backup").and_then(|v| v.as_bool()).unwrap_or(false)
+            {
+                opts.tier = Tier::BACKUP;
+            }
+
+            servers.push(DiscoveredServer { addr: addr.to_string(), opts });
+        }
+
+        servers.sort_by(|a, b| a.addr.cmp(&b.addr));
+        servers.dedup_by(|a, b| a.addr == b.addr);
+
+        Ok(servers)
+    }
+}
+
+impl Discovery for FileDiscovery
+{
+    fn discover(&mut self) -> Result<(Vec<DiscoveredServer>, Duration), Box<dyn std::error::Error>>
+    {
+        let path = self.get_path();
+
+        let contents = std::fs::read_to_string(&path)?;
+
+        // modification times are too coarse to catch quick rewrites of the same size
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        contents.hash(&mut hasher);
+        let seen = hasher.finish();
+
+        if self.last_seen != Some(seen)
+        {
+            // keeps the previous servers if the new file does not parse
+            self.servers   = self.parse(&contents)?;
+            self.last_seen = Some(seen);
+        }
+
+        Ok((self.servers.clone(), self.interval))
+    }
+
+    fn name(&self) -> String
+    {
+        format!("file {} service {}", self.get_path().display(), self.service)
+    }
+}
+
+// A changed server list for a group
+pub struct DiscoveryUpdate
+{
+    pub group_id    : u32,
+    pub servers     : Vec<DiscoveredServer>,
+}
+
+// Runs a provider on its own thread and publishes the server list whenever it changes.
+// The thread stops once the worker is dropped.
+pub struct DiscoveryWorker
+{
+    #[allow(dead_code)] // never sent on, dropping it stops the worker
+    stop : Sender<()>,
+}
+
+impl DiscoveryWorker
+{
+    pub fn spawn(group_id: u32, discovery: Box<dyn Discovery>, updates: Sender<DiscoveryUpdate>) -> std::io::Result<Self>
+    {
+        let (stop, stop_rx) = channel();
+
+        std::thread::Builder::new()
+            .name(format!("discovery-{group_id}"))
+            .spawn(move || Self::run(group_id, discovery, stop_rx, updates))?;
+
+        Ok(Self { stop })
+    }
+
+    fn run(group_id: u32, mut discovery: Box<dyn Discovery>, stop: Receiver<()>, updates: Sender<DiscoveryUpdate>)
+    {
+        let mut last : Option<Vec<DiscoveredServer>> = None;
+
+        info!("Server group {group_id} discovering servers from {}", discovery.name());
+
+        loop
+        {
+            let wait = match discovery.discover()
+            {
+                // more likely a broken registry or file than a group with no servers left,
+                // draining every server on it would take the whole group down
+                Ok((servers, refresh)) if servers.is_empty() =>
+                {
+                    warn!("Server group {group_id} discovery from {} found no servers, keeping the last list", discovery.name());
+                    refresh
+                },
+                Ok((servers, refresh)) =>
+                {
+                    if last.as_ref() != Some(&servers)
+                    {
+                        last = Some(servers.clone());
+
+                        if updates.send(DiscoveryUpdate { group_id, servers }).is_err()
+                        {
+                            return;
+                        }
+                    }
+
+                    refresh
+                },
+                Err(e) =>
+                {
+                    // keep the servers we have
+                    error!("Server group {group_id} discovery from {} failed: {e}", discovery.name());
+                    DISCOVERY_RETRY
+                }
+            };
+
+            match stop.recv_timeout(wait)
+            {
+                Err(RecvTimeoutError::Timeout) => {},
+                _ => return,
+            }
+        }
+    }
+}
+
+#[test]
+fn test_file_discovery()
+{
+    let dir = std::env::temp_dir().join(format!("lb_test_discovery_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let file = dir.join("services.json");
+
+    std::fs::write(&file, r#"{ "web": [ { "addr": "127.0.0.1:2501", "weight": 2 }, { "addr": "127.0.0.1:2500", "backup": true } ] }"#).unwrap();
+    std::fs::write(dir.join("api.json"), r#"[ { "addr": "127.0.0.1:2503", "max_connections": 10 } ]"#).unwrap();
+
+    let mut web = FileDiscovery::new(FileSource::FILE(file.clone()), "web".into(), Duration::from_secs(1));
+    let mut api = FileDiscovery::new(FileSource::DIRECTORY(dir.clone()), "api".into(), Duration::from_secs(1));
+
+    let (servers, refresh) = web.discover().unwrap();
+
+    assert!(refresh == Duration::from_secs(1));
+    assert!(servers == vec![ DiscoveredServer { addr: "127.0.0.1:2500".into(), opts: ServerOptions { tier: Tier::BACKUP, ..ServerOptions::default() } },
+                             DiscoveredServer { addr: "127.0.0.1:2501".into(), opts: ServerOptions { weight: 2, ..ServerOptions::default() } } ]);
+
+    let (servers, _) = api.discover().unwrap();
+    assert!(servers == vec![ DiscoveredServer { addr: "127.0.0.1:2503".into(), opts: ServerOptions { max_connections: Some(10), ..ServerOptions::default() } } ]);
+
+    // A broken file is an error, the last good servers are kept
+    std::fs::write(&file, r#"{ "web": [ { "weight": 2 } ] }"#).unwrap();
+    assert!(web.discover().is_err());
+    assert!(web.servers.len() == 2);
+
+    std::fs::write(&file, r#"{ "web": [ { "addr": "127.0.0.1:2502" } ] }"#).unwrap();
+    let (servers, _) = web.discover().unwrap();
+    assert!(servers.len() == 1 && servers[0].addr == "127.0.0.1:2502");
+
+    // A rewrite of the same size straight away is still picked up
+    std::fs::write(&file, r#"{ "web": [ { "addr": "127.0.0.1:2504" } ] }"#).unwrap();
+    let (servers, _) = web.discover().unwrap();
+    assert!(servers.len() == 1 && servers[0].addr == "127.0.0.1:2504");
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_discovery_worker_ignores_empty_list()
+{
+    struct Scripted(Vec<Vec<DiscoveredServer>>);
+
+    impl Discovery for Scripted
+    {
+        fn discover(&mut self) -> Result<(Vec<DiscoveredServer>, Duration), Box<dyn std::error::Error>>
+        {
+            let servers = if self.0.len() > 1 { self.0.remove(0) } else { self.0[0].clone() };
+
+            Ok((servers, Duration::from_millis(1)))
+        }
+
+        fn name(&self) -> String
+        {
+            "scripted".into()
+        }
+    }
+
+    let one = vec![ DiscoveredServer { addr: "127.0.0.1:2500".into(), opts: ServerOptions::default() } ];
+    let two = vec![ DiscoveredServer { addr: "127.0.0.1:2501".into(), opts: ServerOptions::default() } ];
+
+    let (updates, updates_rx) = channel();
+    let _worker = DiscoveryWorker::spawn(7, Box::new(Scripted(vec![ one.clone(), vec![], two.clone() ])), updates).unwrap();
+
+    // The empty list in between never goes out
+    assert!(updates_rx.recv_timeout(Duration::from_secs(1)).unwrap().servers == one);
+    assert!(updates_rx.recv_timeout(Duration::from_secs(1)).unwrap().servers == two);
+}
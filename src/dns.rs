@@ -0,0 +1,531 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::PathBuf;
+
+use std::time::Duration;
+
+
+use crate::balancer::XorShiftRng;
+
+const TYPE_A    : u16 = 1;
+const TYPE_AAAA : u16 = 28;
+const TYPE_SRV  : u16 = 33;
+const CLASS_IN  : u16 = 1;
+
+// Bounds on how long an answer is trusted
+const MIN_TTL : Duration = Duration::from_secs(1);
+const MAX_TTL : Duration = Duration::from_secs(300);
+
+// Hosts file entries carry no TTL
+const HOSTS_TTL : Duration = Duration::from_secs(30);
+
+// What a server group is resolved from
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DnsName
+{
+    // A and AAAA records, all on the given port
+    HOST(String, u16),
+    // SRV records, each carrying its own target, port, priority and weight
+    SRV(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvedServer
+{
+    pub addr        : SocketAddr,
+    pub priority    : u16,
+    pub weight      : u16,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolved
+{
+    pub servers : Vec<ResolvedServer>,
+    pub ttl     : Duration, // lowest TTL of the records used
+}
+
+// Minimal stub resolver: one UDP query per record type against a single
+// name server, with an optional hosts file that is looked at first.
+// Truncated answers are not retried over TCP.
+#[derive(Clone, Debug)]
+pub struct Resolver
+{
+    pub nameserver  : SocketAddr,
+    pub hosts_file  : Option<PathBuf>,
+    pub timeout     : Duration,
+}
+
+impl Resolver
+{
+    #[cfg(test)]
+    pub fn new(nameserver: SocketAddr) -> Self
+    {
+        Self { nameserver, hosts_file: None, timeout: Duration::from_secs(2) }
+    }
+
+    // First name server of /etc/resolv.conf and /etc/hosts
+    pub fn system() -> Self
+    {
+        let nameserver = std::fs::read_to_string("/etc/resolv.conf").ok()
+                         .and_then(|v| v.lines()
+                                        .filter_map(|l| l.trim().strip_prefix("nameserver"))
+                                        .filter_map(|l| l.trim().parse::<IpAddr>().ok())
+                                        .next())
+                         .unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+        Self { nameserver: SocketAddr::new(nameserver, 53), hosts_file: Some("/etc/hosts".into()), timeout: Duration::from_secs(2) }
+    }
+
+    pub fn resolve(&self, name: &DnsName) -> Result<Resolved, Box<dyn std::error::Error>>
+    {
+        match name
+        {
+            DnsName::HOST(host, port) =>
+            {
+                let (ips, ttl) = self.resolve_host(host)?;
+
+                let servers = ips.iter().map(|ip| ResolvedServer { addr: SocketAddr::new(*ip, *port), priority: 0, weight: 1 }).collect();
+
+                Ok(Resolved { servers, ttl })
+            },
+            DnsName::SRV(srv) => self.resolve_srv(srv),
+        }
+    }
+
+    fn resolve_host(&self, host: &str) -> Result<(Vec<IpAddr>, Duration), Box<dyn std::error::Error>>
+    {
+        if let Ok(ip) = host.parse::<IpAddr>()
+        {
+            return Ok((vec![ip], MAX_TTL));
+        }
+
+        if let Some(ips) = self.lookup_hosts_file(host)?
+        {
+            return Ok((ips, HOSTS_TTL));
+        }
+
+        let mut ips = vec![];
+        let mut ttl = MAX_TTL;
+
+        for qtype in [ TYPE_A, TYPE_AAAA ].iter()
+        {
+            let answer = self.query(host, *qtype)?;
+
+            for record in answer.records.iter().filter(|r| r.name.eq_ignore_ascii_case(host))
+            {
+                if let RecordData::ADDR(ip) = record.data
+                {
+                    ips.push(ip);
+                    ttl = ttl.min(record.ttl);
+                }
+            }
+        }
+
+        Ok((ips, ttl.max(MIN_TTL)))
+    }
+
+    fn resolve_srv(&self, srv: &str) -> Result<Resolved, Box<dyn std::error::Error>>
+    {
+        let answer = self.query(srv, TYPE_SRV)?;
+
+        let mut servers = vec![];
+        let mut ttl = MAX_TTL;
+
+        for record in answer.records.iter()
+        {
+            let (priority, weight, port, target) = match &record.data
+            {
+                RecordData::SRV { priority, weight, port, target } if record.name.eq_ignore_ascii_case(srv) => (*priority, *weight, *port, target),
+                _ => continue,
+            };
+
+            ttl = ttl.min(record.ttl);
+
+            // Addresses of the target usually come along in the additional section
+            let mut ips : Vec<IpAddr> = answer.records.iter()
+                                        .filter(|r| r.name.eq_ignore_ascii_case(target))
+                                        .filter_map(|r| match r.data { RecordData::ADDR(ip) => Some(ip), _ => None })
+                                        .collect();
+
+            if ips.is_empty()
+            {
+                let (target_ips, target_ttl) = self.resolve_host(target)?;
+                ips = target_ips;
+                ttl = ttl.min(target_ttl);
+            }
+
+            for ip in ips
+            {
+                servers.push(ResolvedServer { addr: SocketAddr::new(ip, port), priority, weight });
+            }
+        }
+
+        Ok(Resolved { servers, ttl: ttl.max(MIN_TTL) })
+    }
+
+    // None when the name is not in the hosts file
+    fn lookup_hosts_file(&self, host: &str) -> Result<Option<Vec<IpAddr>>, Box<dyn std::error::Error>>
+    {
+        let path = match &self.hosts_file
+        {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        let contents = match std::fs::read_to_string(path)
+        {
+            Ok(contents) => contents,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let ips : Vec<IpAddr> = contents.lines()
+                                .map(|l| l.split('#').next().unwrap_or(""))
+                                .filter_map(|l|
+                                {
+                                    let mut fields = l.split_whitespace();
+                                    let ip = fields.next()?.parse::<IpAddr>().ok()?;
+
+                                    if fields.any(|v| v.eq_ignore_ascii_case(host)) { Some(ip) } else { None }
+                                })
+                                .collect();
+
+        Ok(if ips.is_empty() { None } else { Some(ips) })
+    }
+
+    fn query(&self, name: &str, qtype: u16) -> Result<Message, Box<dyn std::error::Error>>
+    {
+        let bind_addr : SocketAddr = if self.nameserver.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.connect(self.nameserver)?;
+
+        let id = XorShiftRng::new(None).next_u64() as u16;
+
+        socket.send(&encode_query(id, name, qtype)?)?;
+
+        let mut buf : [u8; 4096] = [0; 4096];
+
+        loop
+        {
+            let n = socket.recv(&mut buf)?;
+
+            let message = decode_message(&buf[0..n])?;
+
+            // Not ours, keep waiting
+            if message.id != id
+            {
+                continue;
+            }
+
+            if message.truncated
+            {
+                return Err(format!("DNS answer for {name} truncated").into());
+            }
+
+            if message.rcode != 0
+            {
+                return Err(format!("DNS query for {name} failed with rcode {}", message.rcode).into());
+            }
+
+            return Ok(message);
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum RecordData
+{
+    ADDR(IpAddr),
+    SRV { priority: u16, weight: u16, port: u16, target: String },
+    OTHER,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Record
+{
+    name    : String,
+    ttl     : Duration,
+    data    : RecordData,
+}
+
+struct Message
+{
+    id          : u16,
+    truncated   : bool,
+    rcode       : u8,
+    records     : Vec<Record>, // answer and additional sections
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &str) -> Result<(), Box<dyn std::error::Error>>
+{
+    for label in name.trim_end_matches('.').split('.')
+    {
+        if label.is_empty() || label.len() > 63
+        {
+            return Err(format!("invalid DNS name {name}").into());
+        }
+
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+
+    out.push(0);
+
+    Ok(())
+}
+
+fn encode_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+{
+    let mut out = vec![];
+
+    out.extend_from_slice(&id.to_be_bytes());
+    out.extend_from_slice(&0x0100u16.to_be_bytes()); // recursion desired
+    out.extend_from_slice(&1u16.to_be_bytes());      // one question
+    out.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+
+    encode_name(&mut out, name)?;
+
+    out.extend_from_slice(&qtype.to_be_bytes());
+    out.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    Ok(out)
+}
+
+fn read_u16(msg: &[u8], pos: usize) -> Result<u16, Box<dyn std::error::Error>>
+{
+    match msg.get(pos..pos + 2)
+    {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => Err("DNS message too short".into()),
+    }
+}
+
+fn read_u32(msg: &[u8], pos: usize) -> Result<u32, Box<dyn std::error::Error>>
+{
+    Ok((read_u16(msg, pos)? as u32) << 16 | read_u16(msg, pos + 2)? as u32)
+}
+
+// Reads a possibly compressed name, returns it with the position after it
+fn read_name(msg: &[u8], mut pos: usize) -> Result<(String, usize), Box<dyn std::error::Error>>
+{
+    let mut labels : Vec<String> = vec![];
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop
+    {
+        let len = *msg.get(pos).ok_or("DNS name out of bounds")? as usize;
+
+        if len & 0xC0 == 0xC0
+        {
+            jumps += 1;
+
+            if jumps > 16
+            {
+                return Err("DNS name compression loop".into());
+            }
+
+            end.get_or_insert(pos + 2);
+            pos = (read_u16(msg, pos)? & 0x3FFF) as usize;
+        }
+        else if len == 0
+        {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        else
+        {
+            let label = msg.get(pos + 1..pos + 1 + len).ok_or("DNS label out of bounds")?;
+            labels.push(String::from_utf8_lossy(label).into_owned());
+            pos += 1 + len;
+        }
+    }
+}
+
+fn decode_message(msg: &[u8]) -> Result<Message, Box<dyn std::error::Error>>
+{
+    let id      = read_u16(msg, 0)?;
+    let flags   = read_u16(msg, 2)?;
+    let qdcount = read_u16(msg, 4)?;
+    let ancount = read_u16(msg, 6)?;
+    let nscount = read_u16(msg, 8)?;
+    let arcount = read_u16(msg, 10)?;
+
+    if flags & 0x8000 == 0
+    {
+        return Err("DNS message is not a response".into());
+    }
+
+    let mut pos = 12;
+
+    for _ in 0..qdcount
+    {
+        let (_, next) = read_name(msg, pos)?;
+        pos = next + 4;
+    }
+
+    let mut records = vec![];
+
+    for i in 0..(ancount as usize + nscount as usize + arcount as usize)
+    {
+        let (name, next) = read_name(msg, pos)?;
+
+        let rtype = read_u16(msg, next)?;
+        let ttl   = read_u32(msg, next + 4)?;
+        let rdlen = read_u16(msg, next + 8)? as usize;
+        let rdata = next + 10;
+
+        let data = msg.get(rdata..rdata + rdlen).ok_or("DNS record out of bounds")?;
+
+        let data = match (rtype, rdlen)
+        {
+            (TYPE_A, 4) => RecordData::ADDR(IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+            (TYPE_AAAA, 16) =>
+            {
+                let mut octets : [u8; 16] = [0; 16];
+                octets.copy_from_slice(data);
+                RecordData::ADDR(IpAddr::V6(Ipv6Addr::from(octets)))
+            },
+            (TYPE_SRV, _) =>
+            {
+                let (target, _) = read_name(msg, rdata + 6)?;
+                RecordData::SRV { priority: read_u16(msg, rdata)?, weight: read_u16(msg, rdata + 2)?, port: read_u16(msg, rdata + 4)?, target }
+            },
+            _ => RecordData::OTHER,
+        };
+
+        // authority records are only skipped over
+        let in_authority = i >= ancount as usize && i < ancount as usize + nscount as usize;
+
+        if !in_authority
+        {
+            records.push(Record { name, ttl: Duration::from_secs(ttl as u64), data });
+        }
+
+        pos = rdata + rdlen;
+    }
+
+    Ok(Message { id, truncated: flags & 0x0200 != 0, rcode: (flags & 0x000F) as u8, records })
+}
+
+// Builds an answer to a query the way a name server would, for the stub server in tests
+#[cfg(test)]
+fn encode_answer(query: &[u8], answers: &[Record], additional: &[Record]) -> Vec<u8>
+{
+    let (_, end) = read_name(query, 12).unwrap();
+
+    let mut out = vec![];
+    out.extend_from_slice(&query[0..2]);
+    out.extend_from_slice(&0x8180u16.to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes());
+    out.extend_from_slice(&(answers.len() as u16).to_be_bytes());
+    out.extend_from_slice(&0u16.to_be_bytes());
+    out.extend_from_slice(&(additional.len() as u16).to_be_bytes());
+    out.extend_from_slice(&query[12..end + 4]);
+
+    for record in answers.iter().chain(additional.iter())
+    {
+        encode_name(&mut out, &record.name).unwrap();
+
+        let (rtype, rdata) = match &record.data
+        {
+            RecordData::ADDR(IpAddr::V4(ip)) => (TYPE_A, ip.octets().to_vec()),
+            RecordData::ADDR(IpAddr::V6(ip)) => (TYPE_AAAA, ip.octets().to_vec()),
+            RecordData::SRV { priority, weight, port, target } =>
+            {
+                let mut rdata = vec![];
+                rdata.extend_from_slice(&priority.to_be_bytes());
+                rdata.extend_from_slice(&weight.to_be_bytes());
+                rdata.extend_from_slice(&port.to_be_bytes());
+                encode_name(&mut rdata, target).unwrap();
+                (TYPE_SRV, rdata)
+            },
+            RecordData::OTHER => (16, vec![]),
+        };
+
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&CLASS_IN.to_be_bytes());
+        out.extend_from_slice(&(record.ttl.as_secs() as u32).to_be_bytes());
+        out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        out.extend_from_slice(&rdata);
+    }
+
+    out
+}
+
+#[test]
+fn test_dns_resolve_host_and_srv()
+{
+    let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+
+    let handle = std::thread::spawn(move ||
+    {
+        // A, AAAA then SRV
+        for i in 0..3
+        {
+            let mut buf : [u8; 512] = [0; 512];
+            let (n, peer) = server.recv_from(&mut buf).unwrap();
+            let query = &buf[0..n];
+
+            let (name, end) = read_name(query, 12).unwrap();
+            let qtype = read_u16(query, end).unwrap();
+
+            let record = |name: &str, ttl: u64, data: RecordData| Record { name: name.into(), ttl: Duration::from_secs(ttl), data };
+
+            let response = match qtype
+            {
+                TYPE_A => encode_answer(query, &[ record(&name, 60, RecordData::ADDR("10.0.0.1".parse().unwrap())),
+                                                  record(&name, 20, RecordData::ADDR("10.0.0.2".parse().unwrap())) ], &[]),
+                TYPE_AAAA => encode_answer(query, &[ record(&name, 60, RecordData::ADDR("::1".parse().unwrap())) ], &[]),
+                _ => encode_answer(query, &[ record(&name, 45, RecordData::SRV { priority: 10, weight: 5, port: 2500, target: "a.example".into() }),
+                                             record(&name, 45, RecordData::SRV { priority: 20, weight: 1, port: 2501, target: "b.example".into() }) ],
+                                          &[ record("a.example", 45, RecordData::ADDR("10.0.1.1".parse().unwrap())),
+                                             record("b.example", 45, RecordData::ADDR("10.0.1.2".parse().unwrap())) ]),
+            };
+
+            server.send_to(&response, peer).unwrap();
+        }
+    });
+
+    let resolver = Resolver::new(addr);
+
+    let resolved = resolver.resolve(&DnsName::HOST("upstream.example".into(), 2500)).unwrap();
+
+    let addrs : Vec<SocketAddr> = resolved.servers.iter().map(|v| v.addr).collect();
+    assert!(addrs == vec![ "10.0.0.1:2500".parse().unwrap(), "10.0.0.2:2500".parse().unwrap(), "[::1]:2500".parse().unwrap() ]);
+    assert!(resolved.ttl == Duration::from_secs(20));
+
+    let resolved = resolver.resolve(&DnsName::SRV("_upstream._tcp.example".into())).unwrap();
+
+    assert!(resolved.servers == vec![ ResolvedServer { addr: "10.0.1.1:2500".parse().unwrap(), priority: 10, weight: 5 },
+                                      ResolvedServer { addr: "10.0.1.2:2501".parse().unwrap(), priority: 20, weight: 1 } ]);
+    assert!(resolved.ttl == Duration::from_secs(45));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_dns_hosts_file()
+{
+    let path = std::env::temp_dir().join(format!("lb_test_hosts_{}", std::process::id()));
+
+    std::fs::write(&path, "# comment\n127.0.0.1 localhost\n10.0.0.5 upstream upstream.local # trailing\n10.0.0.6 upstream.local\n").unwrap();
+
+    // Nothing listens here, everything has to come from the hosts file
+    let mut resolver = Resolver::new("127.0.0.1:9".parse().unwrap());
+    resolver.hosts_file = Some(path.clone());
+    resolver.timeout = Duration::from_millis(100);
+
+    let resolved = resolver.resolve(&DnsName::HOST("upstream.local".into(), 2500)).unwrap();
+
+    let addrs : Vec<SocketAddr> = resolved.servers.iter().map(|v| v.addr).collect();
+    assert!(addrs == vec![ "10.0.0.5:2500".parse().unwrap(), "10.0.0.6:2500".parse().unwrap() ]);
+    assert!(resolved.ttl == HOSTS_TTL);
+
+    assert!(resolver.resolve(&DnsName::HOST("missing.local".into(), 2500)).is_err());
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -8,37 +8,103 @@ use std::io::{Write, Read};
 use std::time::Duration;
 
 use std::sync::Arc;
-use rustls;
 
 use x509_parser::prelude::*;
 
-use log::{info, warn, error};
+use log::{debug, info, warn, error};
 
 pub mod config;
 mod client;
 mod server;
+mod rate_limit;
+mod metrics;
+mod balancer;
+mod probe;
+mod breaker;
+mod listener;
+mod acl;
+mod abuse;
+mod dns;
+mod discovery;
+pub mod proxy_protocol;
 
+pub use probe::{HEALTH_REQUEST, HEALTH_RESPONSE};
+
+
+// An authed connection waiting on a per identity cap or a healthy server
+struct QueuedConnection
+{
+    since       : std::time::Instant,
+    deadline    : std::time::Instant,
+    reason      : client::Rejection,
+    par_cxn     : client::PartialConnection,
+}
+
+// An admitted connection waiting on the connect to its upstream
+struct ConnectingConnection
+{
+    ctx          : balancer::PickContext,
+    queued_since : Option<std::time::Instant>,
+    proxy_header : Option<proxy_protocol::ProxyHeader>,
+    connect      : GroupConnect,
+    par_cxn      : client::PartialConnection,
+}
+
+// A connect to a server of a group, moved on to another server per the group's retry policy
+struct GroupConnect
+{
+    server_group_id : u32,
+    retry           : server::RetryPolicy,
+    started         : std::time::Instant,
+    tried           : Vec<u32>,
+    conn_id         : Option<u64>,
+    server_id       : u32,
+    upstream        : Option<client::UpstreamConnect>, // the attempt in progress
+    header          : Option<Vec<u8>>, // encoded PROXY protocol header, sent once the attempt connects
+}
 
 pub struct LoadBalancer
 {
     clients         : HashMap<String, client::Client>, // email address, Client
+    common_names    : HashMap<String, String>, // common name, email address
     server_groups   : HashMap<u32, server::ServerGroup>, // server group id, ServerGroup
     partial_conns   : Vec<client::PartialConnection>,
-    listener        : std::net::TcpListener,
-    config          : Arc<rustls::ServerConfig>,
+    queued_conns    : Vec<QueuedConnection>,
+    connecting_conns: Vec<ConnectingConnection>,
+    listeners       : Vec<listener::Listener>,
+    timeouts        : client::Timeouts,
+    rate_limits     : rate_limit::RateLimitPolicies,
+    limits          : rate_limit::ConcurrencyLimits,
+    metrics         : metrics::Metrics,
+    discovery       : (std::sync::mpsc::Sender<discovery::DiscoveryUpdate>, std::sync::mpsc::Receiver<discovery::DiscoveryUpdate>),
+    discovery_workers : Vec<discovery::DiscoveryWorker>,
+    next_conn_id    : u64, // sent to upstreams in the PROXY protocol header
+    client_polls    : usize, // rotates which client is polled first
+    maintenance_file : Option<std::path::PathBuf>, // admin commands run by reload_maintenance
+    abuse           : abuse::AbuseGuard, // per source IP limits and bans
 }
 
 impl LoadBalancer
 {
+    // Single listener on localhost
     fn new(config: Arc<rustls::ServerConfig>, port: u16) -> Result<Self, Box<dyn std::error::Error>>
     {
-        let listener = TcpListener::bind(format!("127.0.0.1:{port}"))?;
+        Self::with_listeners(vec![ listener::ListenerConfig::new("default".into(), format!("127.0.0.1:{port}").parse()?, config) ])
+    }
+
+    fn with_listeners(configs: Vec<listener::ListenerConfig>) -> Result<Self, Box<dyn std::error::Error>>
+    {
+        let mut listeners = vec![];
 
-		info!("Listening on 127.0.0.1:{port}");
-        
-		listener.set_nonblocking(true)?;
+        for config in configs
+        {
+            listeners.push(listener::Listener::bind(config)?);
+        }
 
-        Ok(Self { clients : HashMap::new(), server_groups : HashMap::new(), partial_conns: vec![], listener, config })
+        Ok(Self { clients : HashMap::new(), common_names: HashMap::new(), server_groups : HashMap::new(), partial_conns: vec![], queued_conns: vec![], connecting_conns: vec![], listeners, timeouts: client::Timeouts::default(),
+                  rate_limits: rate_limit::RateLimitPolicies::default(), limits: rate_limit::ConcurrencyLimits::default(), metrics: metrics::Metrics::new(Duration::from_secs(60)),
+                  discovery: std::sync::mpsc::channel(), discovery_workers: vec![], next_conn_id: 0, client_polls: 0, maintenance_file: None,
+                  abuse: abuse::AbuseGuard::new(abuse::AbuseConfig::default()) })
     }
 
     pub fn poll(&mut self) -> Result<(), Box<dyn std::error::Error>>
@@ -49,21 +115,187 @@ impl LoadBalancer
 
         self.handle_server_groups()?;
 
+        self.handle_queued_connections();
+
         self.handle_partial_connections();
 
+        self.handle_connecting_connections();
+
+        self.handle_metrics();
+
         Ok(())
     }
 
+    // Clients must be added after the rate limit policies are set up
+    // so the most specific policy is picked up
+    fn add_client(&mut self, mut client: client::Client)
+    {
+        let policy = self.rate_limits.resolve(client.get_email(), client.get_role(), client.get_server_group());
+
+        info!("Client {} rate limit: {:?}", client.get_email(), policy);
+
+        client.set_rate_limit(policy);
+
+        if let Some(common_name) = client.get_common_name()
+        {
+            self.common_names.insert(common_name.to_string(), client.get_email().to_string());
+        }
+
+        self.clients.insert(client.get_email().to_string(), client);
+    }
+
+    // The client record for an identity taken from a certificate
+    fn resolve_client_id(&self, source: listener::IdentitySource, identity: &str) -> Option<String>
+    {
+        match source
+        {
+            listener::IdentitySource::EMAIL => self.clients.contains_key(identity).then(|| identity.to_string()),
+            listener::IdentitySource::COMMON_NAME => self.common_names.get(identity).cloned(),
+        }
+    }
+
+    fn handle_metrics(&mut self)
+    {
+        if !self.metrics.should_report()
+        {
+            return;
+        }
+
+        self.metrics.set_gauge("pending_connections", self.partial_conns.len() as f64);
+        self.metrics.set_gauge("queued_connections", self.queued_conns.len() as f64);
+        self.metrics.set_gauge("connecting_connections", self.connecting_conns.len() as f64);
+        self.metrics.set_gauge("total_connections", self.get_total_connections() as f64);
+
+        self.abuse.prune();
+        self.metrics.set_gauge("banned_sources", self.abuse.get_banned().len() as f64);
+
+        for (k, v) in self.clients.iter_mut()
+        {
+            // one per configured client
+            self.metrics.set_gauge(&format!("rate_limit_tokens{{client=\"{}\"}}", metrics::label(k)), v.get_rate_limit_tokens());
+        }
+
+        info!("Metrics:\n{}", self.metrics.render());
+
+        info!("Status:\n{}", self.admin_status());
+    }
+
+    // Human readable snapshot of the load balancer state
+    pub fn admin_status(&mut self) -> String
+    {
+        let mut out = String::new();
+
+        for listener in self.listeners.iter()
+        {
+            let config = listener.get_config();
+
+            out.push_str(&format!("listener {} addr: {} identity: {:?} default_group: {:?}\n", listener.get_name(),
+                                  listener.local_addr().map(|v| v.to_string()).unwrap_or_default(), config.identity.source, config.default_group));
+        }
+
+        for (ip, remaining) in self.abuse.get_banned().iter()
+        {
+            out.push_str(&format!("banned {ip} for another {:.0}s\n", remaining.as_secs_f64()));
+        }
+
+        let mut client_ids : Vec<&String> = self.clients.keys().collect();
+        client_ids.sort();
+        let client_ids : Vec<String> = client_ids.into_iter().cloned().collect();
+
+        for id in client_ids.iter()
+        {
+            if let Some(client) = self.clients.get_mut(id)
+            {
+                let bandwidth = match client.get_bandwidth_tokens()
+                {
+                    Some(tokens) => format!("{tokens:.0} bytes"),
+                    None => "unlimited".to_string(),
+                };
+
+                out.push_str(&format!("client {id} group: {} connections: {} rate_limit_tokens: {:.2} bandwidth_tokens: {bandwidth} throttled: {}\n",
+                                      client.get_server_group(), client.get_connection_count(), client.get_rate_limit_tokens(), client.is_throttled()));
+            }
+        }
+
+        let mut group_ids : Vec<u32> = self.server_groups.keys().copied().collect();
+        group_ids.sort();
+
+        for id in group_ids.iter()
+        {
+            if let Some(server_group) = self.server_groups.get_mut(id)
+            {
+                let bandwidth = match server_group.get_bandwidth_mut()
+                {
+                    Some(bucket) => format!("{:.0} bytes", bucket.available()),
+                    None => "unlimited".to_string(),
+                };
+
+                let drain = match server_group.get_group_drain()
+                {
+                    Some(drain) => format!(" maintenance: draining for {:?} deadline: {:?}", drain.started.elapsed(), drain.deadline),
+                    None => String::new(),
+                };
+
+                out.push_str(&format!("server_group {id} bandwidth_tokens: {bandwidth}{drain}\n"));
+
+                for line in server_group.status_lines().iter()
+                {
+                    out.push_str(&format!("  {line}\n"));
+                }
+            }
+        }
+
+        out
+    }
+
     fn handle_listener(&mut self) -> Result<(), Box<dyn std::error::Error>>
     {
-        for stream_res in self.listener.incoming()
+        for i in 0..self.listeners.len()
+        {
+            if let Err(e) = self.accept_connections(i)
+            {
+                error!("Listener {} accept failed: {e}", self.listeners[i].get_name());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn accept_connections(&mut self, index: usize) -> Result<(), Box<dyn std::error::Error>>
+    {
+        loop
         {
-			match stream_res
+            // Leave new connections in the listen backlog while a queueing cap is hit
+            if let Some(reason) = self.check_listener_caps(rate_limit::OverloadPolicy::QUEUE)
+            {
+                debug!("Not accepting new connections: {:?}", reason);
+                break;
+            }
+
+            let listener = &self.listeners[index];
+            let name = listener.get_name().to_string();
+
+			match listener.get_socket().accept()
 			{
-				Ok(stream) =>
+				Ok((stream, peer_addr)) =>
 				{
 					// Handle new stream
-                    info!("Client Connected!");
+                    info!("Client Connected! {peer_addr} on {name}");
+
+                    // A trusted front load balancer is screened on the client address from its PROXY protocol header
+                    let proxied = self.listeners[index].expects_proxy_header(&peer_addr);
+
+                    let screened = if proxied { Ok(()) } else { self.screen_source(index, &peer_addr.ip()) };
+
+                    if let Some(reason) = screened.err().or_else(|| self.check_listener_caps(rate_limit::OverloadPolicy::REJECT))
+                    {
+                        warn!("Connection from {peer_addr} on {name} rejected reason: {:?}", reason);
+
+                        self.metrics.incr(&format!("connections_rejected{{listener=\"{}\",reason=\"{:?}\"}}", metrics::label(&name), reason));
+
+                        // stream is dropped and closed, no TLS work is done for it
+                        continue;
+                    }
                     
 					// Set values to ensure the stream is non-blocking
                     // and that data is sent immediately
@@ -72,9 +304,21 @@ impl LoadBalancer
                     stream.set_nonblocking(true)?;
                     stream.set_nodelay(true)?;
 	
-					let tls_conn = rustls::ServerConnection::new(Arc::clone(&self.config))?;
+                    let config = self.listeners[index].get_config();
 
-                    self.partial_conns.push(client::PartialConnection::new(stream, tls_conn));
+					let tls_conn = rustls::ServerConnection::new(Arc::clone(&config.tls))?;
+
+                    let mut par_cxn = client::PartialConnection::new(stream, tls_conn, self.timeouts.handshake);
+                    par_cxn.set_listener(index, config.identity.source);
+
+                    if proxied
+                    {
+                        par_cxn.expect_proxy_header();
+                    }
+
+                    self.partial_conns.push(par_cxn);
+
+                    self.metrics.incr(&format!("connections_opened{{listener=\"{}\"}}", metrics::label(&name)));
 				},
     			Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock =>
     			{
@@ -91,11 +335,149 @@ impl LoadBalancer
         Ok(())
     }
 
+    // Group a client's connections on a listener go to.
+    // A listener's default group only applies to clients allowed in it.
+    fn get_route(&self, client: &client::Client, listener: usize) -> Result<u32, client::Rejection>
+    {
+        match self.listeners.get(listener).and_then(|v| v.get_config().default_group)
+        {
+            Some(group) if client.is_server_group_allowed(group) => Ok(group),
+            Some(group) =>
+            {
+                warn!("Client {} is not allowed in group {group} of listener {}", client.get_email(), self.listeners[listener].get_name());
+                Err(client::Rejection::GROUP_NOT_ALLOWED)
+            },
+            None => Ok(client.get_server_group()),
+        }
+    }
+
+    // Returns the cap that has been hit, only caps with the given policy are checked
+    fn check_listener_caps(&self, policy: rate_limit::OverloadPolicy) -> Option<client::Rejection>
+    {
+        if let Some(cap) = &self.limits.pending
+        {
+            if cap.policy == policy && cap.is_reached(self.partial_conns.len())
+            {
+                return Some(client::Rejection::PENDING_LIMIT);
+            }
+        }
+
+        if let Some(cap) = &self.limits.total
+        {
+            if cap.policy == policy && cap.is_reached(self.get_total_connections())
+            {
+                return Some(client::Rejection::TOTAL_LIMIT);
+            }
+        }
+
+        None
+    }
+
+    // Listener allow/deny lists first, then the per source limits and bans
+    fn screen_source(&mut self, listener: usize, ip: &std::net::IpAddr) -> Result<(), client::Rejection>
+    {
+        if let Some(listener) = self.listeners.get(listener)
+        {
+            if !listener.get_config().acl.is_allowed(ip)
+            {
+                return Err(client::Rejection::IP_DENIED);
+            }
+        }
+
+        self.abuse.check_accept(ip)
+    }
+
+    fn record_auth_failure(&mut self, ip: &std::net::IpAddr)
+    {
+        if self.abuse.record_failure(ip)
+        {
+            self.metrics.incr("sources_banned");
+        }
+    }
+
+    fn get_total_connections(&self) -> usize
+    {
+        let established : usize = self.clients.values().map(|v| v.get_connection_count()).sum();
+
+        established + self.partial_conns.len() + self.queued_conns.len() + self.connecting_conns.len()
+    }
+
     fn handle_clients(&mut self) -> Result<(), Box<dyn std::error::Error>>
     {
-        for (k,v) in self.clients.iter_mut()
+        // A group's bandwidth is split between the active clients with connections routed to it,
+        // each taking a share of what is left by the ones polled before
+        let mut sharing : HashMap<u32, usize> = HashMap::new();
+
+        for v in self.clients.values()
         {
-            v.poll()?;
+            for group in v.get_active_server_groups()
+            {
+                *sharing.entry(group).or_insert(0) += 1;
+            }
+        }
+
+        let mut ids : Vec<String> = self.clients.keys().cloned().collect();
+        ids.sort();
+
+        if !ids.is_empty()
+        {
+            let first = self.client_polls % ids.len();
+            ids.rotate_left(first);
+        }
+
+        self.client_polls = self.client_polls.wrapping_add(1);
+
+        for k in ids.iter()
+        {
+            let v = match self.clients.get_mut(k)
+            {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let mut group_budgets : HashMap<u32, usize> = HashMap::new();
+
+            for group in v.get_active_server_groups()
+            {
+                let clients_left = match sharing.get_mut(&group)
+                {
+                    Some(n) =>
+                    {
+                        let left = *n;
+                        *n = n.saturating_sub(1);
+                        left
+                    },
+                    None => 1,
+                };
+
+                if let Some(bucket) = self.server_groups.get_mut(&group).and_then(|g| g.get_bandwidth_mut())
+                {
+                    group_budgets.insert(group, bucket.available() as usize / clients_left.max(1));
+                }
+            }
+
+            v.poll(&group_budgets)?;
+
+            for ((server_group_id, server_id), bytes) in v.drain_server_bytes().iter()
+            {
+                self.metrics.add(&format!("bytes_relayed{{group=\"{server_group_id}\"}}"), *bytes);
+
+                // charged to the group the connection was routed to
+                if let Some(server_group) = self.server_groups.get_mut(server_group_id)
+                {
+                    server_group.add_bytes(server_id, *bytes);
+
+                    if let Some(bucket) = server_group.get_bandwidth_mut()
+                    {
+                        bucket.consume(*bytes as f64);
+                    }
+                }
+            }
+
+            if v.is_throttled()
+            {
+                self.metrics.incr("bandwidth_throttled_polls");
+            }
 
             for v in v.cleanup_connections().iter()
             {
@@ -103,129 +485,1461 @@ impl LoadBalancer
                 if let Some(server_group) = self.server_groups.get_mut(&v.get_upstream_server_group())
                 {
                     server_group.remove_connection(&v.get_upstream_server_id());
+                    server_group.remove_bytes(&v.get_upstream_server_id(), v.get_bytes_relayed());
+
+                    if v.get_state() == client::ConnState::UP_RESET && server_group.report_failure(&v.get_upstream_server_id())
+                    {
+                        self.metrics.incr(&format!("upstream_ejected{{group=\"{}\",server=\"{}\"}}", v.get_upstream_server_group(), v.get_upstream_server_id()));
+                    }
                 }
 
-                info!("Client {k}: removing connection from server group: {} id: {} reason: {:?}.", v.get_upstream_server_group(), v.get_upstream_server_group(), v.get_state());
+                info!("Client {k}: removing connection from server group: {} id: {} reason: {:?} lifetime: {:?}.", v.get_upstream_server_group(), v.get_upstream_server_id(), v.get_state(), v.get_lifetime());
             }
         }
 
         Ok(())
     }
 
-    fn handle_server_groups(&mut self) -> Result<(), Box<dyn std::error::Error>>
+    // Fill a server group from a discovery provider, running on its own thread
+    fn add_discovery(&mut self, group_id: u32, provider: Box<dyn discovery::Discovery>) -> Result<(), Box<dyn std::error::Error>>
     {
-        for (_k, v) in self.server_groups.iter_mut()
-        {
-            v.poll()?;
-        }
+        let worker = discovery::DiscoveryWorker::spawn(group_id, provider, self.discovery.0.clone())?;
+
+        self.discovery_workers.push(worker);
 
         Ok(())
     }
 
-    fn handle_partial_connections(&mut self)
+    fn handle_server_groups(&mut self) -> Result<(), Box<dyn std::error::Error>>
     {
-        let mut to_remove : Vec<usize> = vec![];
-        let mut to_complete : Vec<usize> = vec![];
-
-        for (i,v) in self.partial_conns.iter_mut().enumerate()
+        while let Ok(update) = self.discovery.1.try_recv()
         {
-            match v.poll()
+            match self.server_groups.get_mut(&update.group_id)
             {
-                Ok(()) =>
+                Some(server_group) =>
                 {
-                    if v.is_completed()
-                    {
-						info!("Partial connection is complete and authed to client {:?}", v.client_id());
+                    let (added, updated, removed) = server_group.reconcile(&update.servers);
 
-                        to_complete.push(i);
-                    }
+                    info!("Server group {} discovered {} servers: {added} added, {updated} updated, {removed} removed", update.group_id, update.servers.len());
+
+                    self.metrics.incr(&format!("discovery_updates{{group=\"{}\"}}", update.group_id));
                 },
-                Err(_e) =>
+                None =>
                 {
-                    to_remove.push(i);
+                    error!("Discovered servers for unknown server group {}", update.group_id);
                 }
             }
         }
 
-        for i in to_remove.iter().rev()
+        for (_k, v) in self.server_groups.iter_mut()
         {
-            self.partial_conns.remove(*i);
-            info!("removing partial connection {}", i);
+            v.poll()?;
         }
 
-        // Remove from Vec in reverse order
-        // to keep index ordering intact
-        for i in to_complete.iter().rev()
-        {
-            let par_cxn = self.partial_conns.remove(*i);
+        self.close_expired_drains();
+
+        Ok(())
+    }
 
-            if let Some(id) = par_cxn.client_id()
+    // Connections still open on a drained server at its deadline are closed
+    fn close_expired_drains(&mut self)
+    {
+        for (group_id, server_group) in self.server_groups.iter()
+        {
+            for server_id in server_group.get_expired_drains().iter()
             {
-                if let Some(client) = self.clients.get(&id)
-                {
-                    if let Some(server_group) = self.server_groups.get_mut(&client.get_server_group())
-                    {
-                        // get least connected and healthy upstream
-                        if let Some(server_id) = server_group.find_min_and_healthy()
-                        {
-                            if let Some(upstream_addr) = server_group.get_server_address(&server_id)
-                            {
-                                // create connection
-                                match client::Connection::from_partial_connection(par_cxn, client.get_server_group(), server_id, upstream_addr)
-                                {
-                                    Ok(conn) =>
-                                    {
-                                        info!("Full connection made: {id} {} {server_id} {upstream_addr}", client.get_server_group());
-                                        // Add server connection to server stats
-                                        server_group.add_connection(&server_id);
-                                        // add to client connections list
-                                        self.insert_connection(&id, conn);
-                                    },
-                                    Err(e) =>
-                                    {
-                                        error!("Partial Connection conversion failed: {e}");
-                                        error!("id: {id} s_group: {} s_id: {server_id} addr: {upstream_addr}", client.get_server_group());
-                                    }
-                                }
-                            }
-                            else
-                            {
-                                error!("No server address found for server id {} in server group {} .. dropping", server_id, client.get_server_group());
-                            }
-                        }
-                        else
-                        {
-                            error!("No healthy server found in server group {} .. dropping", client.get_server_group());
-                        }
-                    }
-                    else
-                    {
-                        error!("Server group {} not found on server for client {} .. dropping", client.get_server_group(), id);
-                    }
-                }
-                else
+                let closed : usize = self.clients.values_mut().map(|v| v.close_server_connections(*group_id, *server_id, client::ConnState::DRAIN_DEADLINE)).sum();
+
+                if closed > 0
                 {
-                    error!("Client id {} not found in server records .. dropping", id);
+                    warn!("Server group {group_id} server {server_id} drain deadline passed, closed {closed} connections");
+
+                    self.metrics.add(&format!("drain_closed_connections{{group=\"{group_id}\",server=\"{server_id}\"}}"), closed as u64);
                 }
-            }
-			else
-			{
-                error!("No email found for complete partial connection.. dropping.");
             }
         }
     }
 
-    fn insert_connection(&mut self, client_id: &String, cxn: client::Connection)
+    pub fn drain_server(&mut self, group_id: u32, server_id: u32, deadline: Option<Duration>) -> Result<(), Box<dyn std::error::Error>>
     {
-        if let Some(client) = self.clients.get_mut(client_id)
+        let server_group = self.server_groups.get_mut(&group_id).ok_or_else(|| format!("unknown server group {group_id}"))?;
+
+        if !server_group.drain_server(&server_id, deadline)
         {
-            client.add_connection(cxn);
+            return Err(format!("unknown server {server_id} in server group {group_id}").into());
         }
-        else
+
+        Ok(())
+    }
+
+    pub fn resume_server(&mut self, group_id: u32, server_id: u32) -> Result<bool, Box<dyn std::error::Error>>
+    {
+        let server_group = self.server_groups.get_mut(&group_id).ok_or_else(|| format!("unknown server group {group_id}"))?;
+
+        Ok(server_group.resume_server(&server_id))
+    }
+
+    pub fn drain_group(&mut self, group_id: u32, deadline: Option<Duration>) -> Result<(), Box<dyn std::error::Error>>
+    {
+        self.server_groups.get_mut(&group_id).ok_or_else(|| format!("unknown server group {group_id}"))?.drain_group(deadline);
+
+        Ok(())
+    }
+
+    pub fn resume_group(&mut self, group_id: u32) -> Result<bool, Box<dyn std::error::Error>>
+    {
+        Ok(self.server_groups.get_mut(&group_id).ok_or_else(|| format!("unknown server group {group_id}"))?.resume_group())
+    }
+
+    // Admin commands, one per line:
+    //   drain <group>[/<server>] [<deadline in seconds>]
+    //   resume <group>[/<server>]
+    //   status
+    // Empty lines and lines starting with # do nothing.
+    pub fn admin_command(&mut self, line: &str) -> Result<String, Box<dyn std::error::Error>>
+    {
+        let fields : Vec<&str> = line.split_whitespace().collect();
+
+        if fields.first().map(|v| v.starts_with('#')).unwrap_or(true)
         {
-            error!("Client {client_id} not found to insert connection .. dropping connection")
+            return Ok(String::new());
         }
-    }
-}
 
+        let target = |v: &str| -> Result<(u32, Option<u32>), Box<dyn std::error::Error>>
+        {
+            match v.split_once('/')
+            {
+                Some((group, server)) => Ok((group.parse()?, Some(server.parse()?))),
+                None => Ok((v.parse()?, None)),
+            }
+        };
+
+        match fields.as_slice()
+        {
+            [ "status" ] => Ok(self.admin_status()),
+            [ "drain", what, rest @ .. ] if rest.len() <= 1 =>
+            {
+                let deadline = match rest.first()
+                {
+                    Some(secs) => Some(Duration::from_secs(secs.parse()?)),
+                    None => None,
+                };
+
+                match target(what)?
+                {
+                    (group, Some(server)) => self.drain_server(group, server, deadline)?,
+                    (group, None) => self.drain_group(group, deadline)?,
+                }
 
+                Ok(format!("draining {what}"))
+            },
+            [ "resume", what ] =>
+            {
+                let resumed = match target(what)?
+                {
+                    (group, Some(server)) => self.resume_server(group, server)?,
+                    (group, None) => self.resume_group(group)?,
+                };
+
+                Ok(if resumed { format!("resumed {what}") } else { format!("{what} was not draining") })
+            },
+            _ => Err(format!("unknown admin command: {line}").into()),
+        }
+    }
+
+    pub fn set_maintenance_file(&mut self, path: std::path::PathBuf)
+    {
+        self.maintenance_file = Some(path);
+    }
+
+    // Run the admin commands in the maintenance file, e.g. on a signal.
+    // A missing file is nothing to do, a bad line is logged and skipped.
+    pub fn reload_maintenance(&mut self)
+    {
+        let path = match &self.maintenance_file
+        {
+            Some(path) => path.clone(),
+            None => return,
+        };
+
+        let contents = match std::fs::read_to_string(&path)
+        {
+            Ok(contents) => contents,
+            Err(e) =>
+            {
+                warn!("Maintenance file {} not read: {e}", path.display());
+                return;
+            }
+        };
+
+        for line in contents.lines()
+        {
+            match self.admin_command(line)
+            {
+                Ok(res) if res.is_empty() => {},
+                Ok(res) => info!("Maintenance: {res}"),
+                Err(e) => error!("Maintenance file {}: {e}", path.display()),
+            }
+        }
+    }
+
+    fn handle_partial_connections(&mut self)
+    {
+        self.screen_proxied_connections();
+
+        let mut to_remove : Vec<usize> = vec![];
+        let mut to_complete : Vec<usize> = vec![];
+
+        for (i,v) in self.partial_conns.iter_mut().enumerate()
+        {
+            match v.poll()
+            {
+                Ok(()) =>
+                {
+                    if v.is_completed()
+                    {
+						info!("Partial connection is complete and authed as {:?}", v.get_identity());
+
+                        to_complete.push(i);
+                    }
+                    else if v.is_failed()
+                    {
+                        to_remove.push(i);
+                    }
+                },
+                Err(_e) =>
+                {
+                    to_remove.push(i);
+                }
+            }
+        }
+
+        for i in to_remove.iter().rev()
+        {
+            let par_cxn = self.partial_conns.remove(*i);
+            info!("removing partial connection {} reason: {}", i, par_cxn.get_state_name());
+
+            if let (true, Some(addr)) = (par_cxn.is_auth_failure(), par_cxn.get_peer_addr())
+            {
+                self.record_auth_failure(&addr.ip());
+            }
+        }
+
+        // Remove from Vec in reverse order
+        // to keep index ordering intact
+        for i in to_complete.iter().rev()
+        {
+            let mut par_cxn = self.partial_conns.remove(*i);
+
+            let identity = match par_cxn.get_identity()
+            {
+                Some(identity) => identity,
+                None =>
+                {
+                    error!("No identity found for complete partial connection.. dropping.");
+                    par_cxn.reject();
+                    continue;
+                }
+            };
+
+            let source = match self.listeners.get(par_cxn.get_listener())
+            {
+                Some(listener) =>
+                {
+                    // the allowed list is in the listener's own identity terms
+                    if !listener.get_config().identity.is_allowed(&identity)
+                    {
+                        warn!("Client {identity} is not allowed on listener {}", listener.get_name());
+                        self.reject_partial_connection(&identity, par_cxn, client::Rejection::IDENTITY_NOT_ALLOWED);
+                        continue;
+                    }
+
+                    listener.get_config().identity.source
+                },
+                None => listener::IdentitySource::EMAIL,
+            };
+
+            match self.resolve_client_id(source, &identity)
+            {
+                Some(id) =>
+                {
+                    par_cxn.set_client_id(id.clone());
+                    self.complete_partial_connection(&id, par_cxn, None, false);
+                },
+                None =>
+                {
+                    error!("Client id {} not found in server records .. dropping", identity);
+                    self.reject_partial_connection(&identity, par_cxn, client::Rejection::UNKNOWN_CLIENT);
+                }
+            }
+        }
+    }
+
+    // Client addresses learnt from a PROXY protocol header go through the same checks
+    // as direct connections before their TLS handshake is started
+    fn screen_proxied_connections(&mut self)
+    {
+        let mut i = 0;
+
+        while i < self.partial_conns.len()
+        {
+            let par_cxn = &mut self.partial_conns[i];
+
+            let (listener, addr) = match (par_cxn.take_needs_screening(), par_cxn.get_peer_addr())
+            {
+                (true, Some(addr)) => (par_cxn.get_listener(), addr),
+                _ =>
+                {
+                    i += 1;
+                    continue;
+                }
+            };
+
+            match self.screen_source(listener, &addr.ip())
+            {
+                Ok(()) => { i += 1; },
+                Err(reason) =>
+                {
+                    let name = self.listeners.get(listener).map(|v| v.get_name()).unwrap_or("").to_string();
+
+                    warn!("Connection from {addr} on {name} rejected reason: {:?}", reason);
+
+                    self.metrics.incr(&format!("connections_rejected{{listener=\"{}\",reason=\"{:?}\"}}", metrics::label(&name), reason));
+
+                    // dropped before the handshake
+                    self.partial_conns.remove(i);
+                }
+            }
+        }
+    }
+
+    // Retry queued connections once what they wait on may have cleared:
+    // a connection closed below the cap or a server of their route back in rotation
+    fn handle_queued_connections(&mut self)
+    {
+        let queued = std::mem::take(&mut self.queued_conns);
+
+        for queued_cxn in queued
+        {
+            let id = match queued_cxn.par_cxn.client_id()
+            {
+                Some(id) => id,
+                None => continue,
+            };
+
+            let waiting = match queued_cxn.reason
+            {
+                client::Rejection::NO_HEALTHY_SERVER => !self.has_healthy_route(&id, queued_cxn.par_cxn.get_listener()),
+                _ => self.is_at_identity_cap(&id),
+            };
+
+            if !waiting
+            {
+                // already passed the rate limit when it was queued for a server
+                let admitted = queued_cxn.reason == client::Rejection::NO_HEALTHY_SERVER;
+
+                self.complete_partial_connection(&id, queued_cxn.par_cxn, Some(queued_cxn.since), admitted);
+            }
+            else if std::time::Instant::now() >= queued_cxn.deadline
+            {
+                self.reject_partial_connection(&id, queued_cxn.par_cxn, client::Rejection::QUEUE_TIMEOUT);
+            }
+            else
+            {
+                self.queued_conns.push(queued_cxn);
+            }
+        }
+    }
+
+    // Connections still waiting on their upstream connect count against the cap
+    fn is_at_identity_cap(&self, id: &str) -> bool
+    {
+        match (&self.limits.per_identity, self.clients.get(id))
+        {
+            (Some(cap), Some(client)) => cap.is_reached(client.get_connection_count() + self.connecting_conns.iter().filter(|v| v.ctx.identity == id).count()),
+            _ => false,
+        }
+    }
+
+    // Whether the route of a client, fallbacks included, has a server to pick
+    fn has_healthy_route(&self, id: &str, listener: usize) -> bool
+    {
+        let mut server_group_id = match self.clients.get(id).map(|v| self.get_route(v, listener))
+        {
+            Some(Ok(server_group_id)) => server_group_id,
+            _ => return true, // left to admit_and_connect to reject
+        };
+
+        let mut visited : Vec<u32> = vec![];
+
+        loop
+        {
+            let server_group = match self.server_groups.get(&server_group_id)
+            {
+                Some(server_group) => server_group,
+                None => return true,
+            };
+
+            if server_group.has_available_server()
+            {
+                return true;
+            }
+
+            match server_group.get_no_healthy_policy()
+            {
+                server::NoHealthyPolicy::FALLBACK(fallback) if !visited.contains(fallback) && *fallback != server_group_id =>
+                {
+                    visited.push(server_group_id);
+                    server_group_id = *fallback;
+                },
+                _ => return false,
+            }
+        }
+    }
+
+    // queued_since is set for a retry of a queued connection
+    fn complete_partial_connection(&mut self, id: &String, par_cxn: client::PartialConnection, queued_since: Option<std::time::Instant>, admitted: bool)
+    {
+        let ctx = balancer::PickContext { identity: id.clone(), source_ip: par_cxn.get_peer_addr().map(|v| v.ip()), listener: par_cxn.get_listener() };
+
+        let proxy_header = match par_cxn.get_proxy_header()
+        {
+            Ok(header) => Some(header),
+            Err(e) =>
+            {
+                debug!("Client {id}: no PROXY protocol header: {e}");
+                None
+            }
+        };
+
+        match self.admit_and_connect(&ctx, admitted, proxy_header.as_ref())
+        {
+            Ok(connect) =>
+            {
+                self.connecting_conns.push(ConnectingConnection { ctx, queued_since, proxy_header, connect, par_cxn });
+            },
+            Err(rejection) =>
+            {
+                self.handle_connect_rejection(id, par_cxn, queued_since, rejection);
+            }
+        }
+    }
+
+    // Upstream connects are polled like partial connections, the connection is made
+    // once one of them completes and rejected once the group's retries run out
+    fn handle_connecting_connections(&mut self)
+    {
+        let connecting = std::mem::take(&mut self.connecting_conns);
+
+        for mut cxn in connecting
+        {
+            match self.poll_group_connect(&mut cxn.connect, &cxn.ctx, cxn.proxy_header.as_ref())
+            {
+                Ok(None) =>
+                {
+                    self.connecting_conns.push(cxn);
+                },
+                Ok(Some(up_stream)) =>
+                {
+                    self.complete_connection(cxn.par_cxn, &cxn.ctx, cxn.connect.server_group_id, cxn.connect.server_id, up_stream);
+                },
+                Err(rejection) =>
+                {
+                    let id = cxn.ctx.identity.clone();
+
+                    self.handle_connect_rejection(&id, cxn.par_cxn, cxn.queued_since, (rejection, Some(cxn.connect.server_group_id)));
+                }
+            }
+        }
+    }
+
+    // The server slot was taken when the connect was started
+    fn complete_connection(&mut self, par_cxn: client::PartialConnection, ctx: &balancer::PickContext, server_group_id: u32, server_id: u32, up_stream: TcpStream)
+    {
+        let id = &ctx.identity;
+        let peer_addr = par_cxn.get_peer_addr();
+        let timeouts = self.server_groups.get(&server_group_id).and_then(|v| v.get_timeouts()).unwrap_or(&self.timeouts);
+
+        match client::Connection::from_partial_connection(par_cxn, up_stream, server_group_id, server_id, timeouts)
+        {
+            Ok(conn) =>
+            {
+                info!("Full connection made: {id} {server_group_id} {server_id}");
+
+                // add to client connections list
+                if let Some(client) = self.clients.get_mut(id)
+                {
+                    client.add_connection(conn);
+                }
+
+                let listener = self.listeners.get(ctx.listener).map(|v| v.get_name()).unwrap_or("");
+
+                self.metrics.incr(&format!("connections_accepted{{listener=\"{}\"}}", metrics::label(listener)));
+
+                if let Some(addr) = peer_addr
+                {
+                    self.abuse.record_success(&addr.ip());
+                }
+            },
+            Err(e) =>
+            {
+                error!("Partial Connection conversion failed: {e}");
+                error!("id: {id} s_group: {server_group_id} s_id: {server_id}");
+
+                if let Some(server_group) = self.server_groups.get_mut(&server_group_id)
+                {
+                    server_group.remove_connection(&server_id);
+                }
+            }
+        }
+    }
+
+    fn handle_connect_rejection(&mut self, id: &String, par_cxn: client::PartialConnection, queued_since: Option<std::time::Instant>, rejection: (client::Rejection, Option<u32>))
+    {
+        match rejection
+        {
+            (client::Rejection::CONCURRENCY_LIMIT, _) if self.limits.per_identity.as_ref().map(|v| v.policy) == Some(rate_limit::OverloadPolicy::QUEUE) =>
+            {
+                let timeout = self.limits.queue_timeout;
+
+                self.queue_connection(id, par_cxn, queued_since, timeout, client::Rejection::CONCURRENCY_LIMIT);
+            },
+            (client::Rejection::NO_HEALTHY_SERVER, group_id) =>
+            {
+                // the policy of the group the fallbacks ended at
+                let policy = group_id.and_then(|v| self.server_groups.get(&v)).map(|v| v.get_no_healthy_policy().clone());
+
+                match policy
+                {
+                    Some(server::NoHealthyPolicy::QUEUE(timeout)) =>
+                    {
+                        self.queue_connection(id, par_cxn, queued_since, timeout, client::Rejection::NO_HEALTHY_SERVER);
+                    },
+                    _ =>
+                    {
+                        self.reject_partial_connection(id, par_cxn, client::Rejection::NO_HEALTHY_SERVER);
+                    }
+                }
+            },
+            (rejection, _) =>
+            {
+                self.reject_partial_connection(id, par_cxn, rejection);
+            }
+        }
+    }
+
+    // A connection is logged and counted when it is first queued, its retries are quiet
+    fn queue_connection(&mut self, id: &String, par_cxn: client::PartialConnection, queued_since: Option<std::time::Instant>, timeout: Duration, reason: client::Rejection)
+    {
+        let since = queued_since.unwrap_or_else(std::time::Instant::now);
+
+        if since.elapsed() >= timeout
+        {
+            self.reject_partial_connection(id, par_cxn, client::Rejection::QUEUE_TIMEOUT);
+            return;
+        }
+
+        if queued_since.is_none()
+        {
+            info!("Client {id}: connection queued for up to {:?} reason: {:?}", timeout, reason);
+
+            self.metrics.incr(&format!("connections_queued{{reason=\"{:?}\"}}", reason));
+        }
+
+        self.queued_conns.push(QueuedConnection { since, deadline: since + timeout, reason, par_cxn });
+    }
+
+    fn reject_partial_connection(&mut self, id: &String, par_cxn: client::PartialConnection, rejection: client::Rejection)
+    {
+        // a certificate from our CA that does not belong here counts like a failed handshake
+        if let (client::Rejection::UNKNOWN_CLIENT | client::Rejection::IDENTITY_NOT_ALLOWED, Some(addr)) = (&rejection, par_cxn.get_peer_addr())
+        {
+            self.record_auth_failure(&addr.ip());
+        }
+
+        warn!("Client {id}: connection rejected reason: {:?}", rejection);
+
+        let listener = self.listeners.get(par_cxn.get_listener()).map(|v| v.get_name()).unwrap_or("");
+
+        self.metrics.incr(&format!("connections_rejected{{listener=\"{}\",reason=\"{:?}\"}}", metrics::label(listener), rejection));
+
+        par_cxn.reject();
+    }
+
+    // Admission checks run before an upstream is picked so that a rejected
+    // connection never takes an upstream slot or opens an upstream socket.
+    // Connections queued for a healthy server have been admitted already and skip the rate limit.
+    // A rejection comes with the last server group tried, if any, which is where a fallback ended up.
+    fn admit_and_connect(&mut self, ctx: &balancer::PickContext, admitted: bool, proxy_header: Option<&proxy_protocol::ProxyHeader>) -> Result<GroupConnect, (client::Rejection, Option<u32>)>
+    {
+        let id = &ctx.identity;
+
+        let client = match self.clients.get(id)
+        {
+            Some(client) => client,
+            None =>
+            {
+                error!("Client id {} not found in server records .. dropping", id);
+                return Err((client::Rejection::UNKNOWN_CLIENT, None));
+            }
+        };
+
+        if let Some(cap) = &self.limits.per_identity
+        {
+            if self.is_at_identity_cap(id)
+            {
+                warn!("Client {id} has reached its limit of {} concurrent connections", cap.max);
+                return Err((client::Rejection::CONCURRENCY_LIMIT, None));
+            }
+        }
+
+        let mut server_group_id = self.get_route(client, ctx.listener).map_err(|e| (e, None))?;
+
+        if !admitted
+        {
+            if let Some(client) = self.clients.get_mut(id)
+            {
+                client.check_admission().map_err(|e| (e, None))?;
+            }
+        }
+
+        let mut visited : Vec<u32> = vec![];
+
+        loop
+        {
+            match self.start_group_connect(server_group_id, ctx, proxy_header)
+            {
+                Err(client::Rejection::NO_HEALTHY_SERVER) =>
+                {
+                    let fallback = match self.server_groups.get(&server_group_id).map(|v| v.get_no_healthy_policy())
+                    {
+                        Some(server::NoHealthyPolicy::FALLBACK(fallback)) if !visited.contains(fallback) && *fallback != server_group_id => *fallback,
+                        _ => return Err((client::Rejection::NO_HEALTHY_SERVER, Some(server_group_id))),
+                    };
+
+                    warn!("No healthy server in group {server_group_id}, falling back to group {fallback} for client {}", ctx.identity);
+
+                    self.metrics.incr(&format!("group_fallbacks{{group=\"{server_group_id}\",fallback=\"{fallback}\"}}"));
+
+                    visited.push(server_group_id);
+                    server_group_id = fallback;
+                },
+                res => return res.map_err(|e| (e, Some(server_group_id))),
+            }
+        }
+    }
+
+    // Start connecting to a server of the group. NO_HEALTHY_SERVER if the group has none to pick.
+    fn start_group_connect(&mut self, server_group_id: u32, ctx: &balancer::PickContext, proxy_header: Option<&proxy_protocol::ProxyHeader>) -> Result<GroupConnect, client::Rejection>
+    {
+        let retry = match self.server_groups.get(&server_group_id)
+        {
+            Some(server_group) => server_group.get_retry_policy().clone(),
+            None =>
+            {
+                error!("Server group {} not found on server for client {} .. dropping", server_group_id, ctx.identity);
+                return Err(client::Rejection::UNKNOWN_SERVER_GROUP);
+            }
+        };
+
+        let mut connect = GroupConnect { server_group_id, retry, started: std::time::Instant::now(), tried: vec![], conn_id: None, server_id: 0, upstream: None, header: None };
+
+        self.connect_next_server(&mut connect, ctx, proxy_header)?;
+
+        Ok(connect)
+    }
+
+    // Pick a server of the group that has not been tried and start a connect to it.
+    // A connect in progress holds a slot on its server, so least connections and
+    // connection caps see it. Groups that take the PROXY protocol get the header once connected.
+    fn connect_next_server(&mut self, connect: &mut GroupConnect, ctx: &balancer::PickContext, proxy_header: Option<&proxy_protocol::ProxyHeader>) -> Result<(), client::Rejection>
+    {
+        let id = &ctx.identity;
+        let server_group_id = connect.server_group_id;
+
+        loop
+        {
+            let server_group = match self.server_groups.get_mut(&server_group_id)
+            {
+                Some(server_group) => server_group,
+                None =>
+                {
+                    error!("Server group {} not found on server for client {} .. dropping", server_group_id, id);
+                    return Err(client::Rejection::UNKNOWN_SERVER_GROUP);
+                }
+            };
+
+            // get a healthy upstream using the group's balancing strategy, skipping the ones that failed
+            let server_id = match server_group.select_server_excluding(ctx, &connect.tried)
+            {
+                Some(server_id) => server_id,
+                None if connect.tried.is_empty() =>
+                {
+                    error!("No healthy server found in server group {} .. dropping", server_group_id);
+                    return Err(client::Rejection::NO_HEALTHY_SERVER);
+                },
+                None =>
+                {
+                    error!("No healthy server left to retry in server group {} .. dropping", server_group_id);
+                    return Err(client::Rejection::UPSTREAM_CONNECT_FAILED);
+                }
+            };
+
+            let upstream_addr = match server_group.get_server_address(&server_id)
+            {
+                Some(upstream_addr) => upstream_addr.clone(),
+                None =>
+                {
+                    error!("No server address found for server id {} in server group {} .. dropping", server_id, server_group_id);
+                    return Err(client::Rejection::NO_HEALTHY_SERVER);
+                }
+            };
+
+            connect.header = match proxy_header
+            {
+                Some(header) if server_group.get_proxy_protocol() =>
+                {
+                    // one id per connection, taken once a server is picked
+                    if connect.conn_id.is_none()
+                    {
+                        self.next_conn_id += 1;
+                        connect.conn_id = Some(self.next_conn_id);
+                    }
+
+                    let mut header = header.clone();
+                    header.add_tlv(proxy_protocol::TLV_UNIQUE_ID, connect.conn_id.unwrap_or_default().to_string().as_bytes());
+
+                    // the client's fault, not the server's
+                    match header.encode()
+                    {
+                        Ok(header) => Some(header),
+                        Err(e) =>
+                        {
+                            error!("PROXY protocol header for client {id} to server group {server_group_id} failed: {e} .. dropping");
+                            return Err(client::Rejection::UPSTREAM_CONNECT_FAILED);
+                        }
+                    }
+                },
+                None if server_group.get_proxy_protocol() =>
+                {
+                    error!("No PROXY protocol header for client {id} to send to server group {server_group_id} .. dropping");
+                    return Err(client::Rejection::UPSTREAM_CONNECT_FAILED);
+                },
+                _ => None,
+            };
+
+            let remaining = connect.retry.budget.saturating_sub(connect.started.elapsed()).max(Duration::from_millis(1));
+
+            connect.server_id = server_id;
+
+            match client::UpstreamConnect::start(&upstream_addr, remaining.min(client::UPSTREAM_CONNECT_TIMEOUT))
+            {
+                Ok(upstream) =>
+                {
+                    server_group.add_connection(&server_id);
+                    connect.upstream = Some(upstream);
+
+                    return Ok(());
+                },
+                Err(e) =>
+                {
+                    error!("Unable to connect to upstream {upstream_addr} (s_group: {server_group_id} s_id: {server_id}): {e}");
+
+                    self.connect_failed(connect, ctx)?;
+                }
+            }
+        }
+    }
+
+    // Poll the connect in progress. Returns the stream once connected, with the header sent
+    // if the group takes one, and moves on to another server when the attempt fails.
+    fn poll_group_connect(&mut self, connect: &mut GroupConnect, ctx: &balancer::PickContext, proxy_header: Option<&proxy_protocol::ProxyHeader>) -> Result<Option<TcpStream>, client::Rejection>
+    {
+        let upstream = match connect.upstream.as_mut()
+        {
+            Some(upstream) => upstream,
+            None => return Err(client::Rejection::UPSTREAM_CONNECT_FAILED),
+        };
+
+        let connected = match upstream.poll()
+        {
+            Ok(None) => return Ok(None),
+            Ok(Some(up_stream)) => Ok((up_stream, upstream.elapsed())),
+            Err(e) => Err(e),
+        };
+
+        // a server that does not take the header is retried like one that does not connect
+        let connected = match (connected, connect.header.as_ref())
+        {
+            (Ok((mut up_stream, rtt)), Some(header)) => client::send_proxy_header(&mut up_stream, header).map(|_| (up_stream, rtt)),
+            (connected, _) => connected,
+        };
+
+        let server_group_id = connect.server_group_id;
+        let server_id = connect.server_id;
+        let upstream_addr = *upstream.get_addr();
+
+        connect.upstream = None;
+
+        if let Some(server_group) = self.server_groups.get_mut(&server_group_id)
+        {
+            match connected
+            {
+                Ok((up_stream, rtt)) =>
+                {
+                    server_group.report_success(&server_id, rtt);
+
+                    return Ok(Some(up_stream));
+                },
+                Err(e) =>
+                {
+                    error!("Unable to connect to upstream {upstream_addr} (s_group: {server_group_id} s_id: {server_id}): {e}");
+
+                    // give back the slot taken for the attempt
+                    server_group.remove_connection(&server_id);
+                }
+            }
+        }
+
+        self.connect_failed(connect, ctx)?;
+        self.connect_next_server(connect, ctx, proxy_header)?;
+
+        Ok(None)
+    }
+
+    // Count a failed attempt against the server and the retry policy.
+    // UPSTREAM_CONNECT_FAILED once the retries or the budget are used up.
+    fn connect_failed(&mut self, connect: &mut GroupConnect, ctx: &balancer::PickContext) -> Result<(), client::Rejection>
+    {
+        let server_group_id = connect.server_group_id;
+        let server_id = connect.server_id;
+
+        if let Some(server_group) = self.server_groups.get_mut(&server_group_id)
+        {
+            if server_group.report_failure(&server_id)
+            {
+                self.metrics.incr(&format!("upstream_ejected{{group=\"{server_group_id}\",server=\"{server_id}\"}}"));
+            }
+        }
+
+        connect.tried.push(server_id);
+
+        if connect.tried.len() > connect.retry.max_retries as usize || connect.started.elapsed() >= connect.retry.budget
+        {
+            return Err(client::Rejection::UPSTREAM_CONNECT_FAILED);
+        }
+
+        warn!("Retrying client {} on another server of group {server_group_id} (attempt {} of {})", ctx.identity, connect.tried.len() + 1, connect.retry.max_retries + 1);
+
+        self.metrics.incr(&format!("upstream_retries{{group=\"{server_group_id}\"}}"));
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_load_balancer_pending_caps()
+{
+    let config = config::create_server_tls_config(false).unwrap();
+
+    let policies = [ rate_limit::OverloadPolicy::REJECT, rate_limit::OverloadPolicy::QUEUE ];
+
+    for policy in policies.iter()
+    {
+        let mut lb = LoadBalancer::new(Arc::clone(&config), 0).unwrap();
+        let addr = lb.listeners[0].local_addr().unwrap();
+
+        lb.limits.pending = Some(rate_limit::ConcurrencyCap::new(2, *policy));
+
+        let mut streams : Vec<TcpStream> = vec![];
+        for i in 0..4
+        {
+            streams.push(TcpStream::connect(addr).unwrap());
+        }
+
+        std::thread::sleep(Duration::from_millis(50));
+
+        lb.handle_listener().unwrap();
+
+        assert!(lb.partial_conns.len() == 2);
+
+        let rejected = lb.metrics.get_counter("connections_rejected{listener=\"default\",reason=\"PENDING_LIMIT\"}");
+
+        match policy
+        {
+            // Extra connections are accepted and closed
+            rate_limit::OverloadPolicy::REJECT => { assert!(rejected == 2); },
+            // Extra connections are left in the listen backlog
+            rate_limit::OverloadPolicy::QUEUE  => { assert!(rejected == 0); },
+        }
+    }
+}
+
+#[test]
+fn test_load_balancer_admin_maintenance()
+{
+    let config = config::create_server_tls_config(false).unwrap();
+
+    let mut lb = LoadBalancer::new(config, 0).unwrap();
+
+    let mut sg = server::ServerGroup::new(0);
+    sg.add_server(0, "127.0.0.1:2500".into());
+    sg.add_server(1, "127.0.0.1:2501".into());
+    lb.server_groups.insert(0, sg);
+
+    assert!(lb.admin_command("drain 0/1 300").unwrap() == "draining 0/1");
+    assert!(lb.server_groups[&0].get_server_drain(&1).unwrap().deadline == Some(Duration::from_secs(300)));
+    assert!(lb.admin_command("status").unwrap().contains("(maintenance)"));
+
+    assert!(lb.admin_command("resume 0/1").unwrap() == "resumed 0/1");
+    assert!(lb.admin_command("resume 0/1").unwrap() == "0/1 was not draining");
+
+    assert!(lb.admin_command("# comment").unwrap().is_empty());
+    assert!(lb.admin_command("drain 0/9").is_err());
+    assert!(lb.admin_command("drain 3").is_err());
+    assert!(lb.admin_command("drain 0 soon").is_err());
+    assert!(lb.admin_command("reboot").is_err());
+
+    // The maintenance file runs the same commands, bad lines are skipped
+    let file = std::env::temp_dir().join(format!("lb_test_maintenance_{}", std::process::id()));
+    std::fs::write(&file, "# patching\ndrain 0\nbogus\ndrain 0/0\n").unwrap();
+
+    lb.set_maintenance_file(file.clone());
+    lb.reload_maintenance();
+
+    assert!(lb.server_groups[&0].get_group_drain().map(|v| v.deadline) == Some(None));
+    assert!(lb.server_groups[&0].get_server_drain(&0).is_some());
+    assert!(lb.admin_status().contains("server_group 0 bandwidth_tokens: unlimited maintenance: draining"));
+
+    std::fs::remove_file(&file).unwrap();
+}
+
+#[test]
+fn test_load_balancer_source_screening()
+{
+    let config = config::create_server_tls_config(false).unwrap();
+
+    let mut listener = listener::ListenerConfig::new("screened".into(), "127.0.0.1:0".parse().unwrap(), Arc::clone(&config));
+    listener.acl = acl::IpAcl { allow: None, deny: acl::parse_cidrs(&[ "127.0.0.2" ]).unwrap() };
+
+    let mut proxied = listener::ListenerConfig::new("proxied".into(), "127.0.0.1:0".parse().unwrap(), Arc::clone(&config));
+    proxied.accept_proxy = Some(acl::parse_cidrs(&[ "127.0.0.1" ]).unwrap());
+    proxied.acl = acl::IpAcl { allow: Some(acl::parse_cidrs(&[ "10.0.0.0/8" ]).unwrap()), deny: vec![] };
+
+    let mut lb = LoadBalancer::with_listeners(vec![ listener, proxied ]).unwrap();
+    let (screened, proxied) = (lb.listeners[0].local_addr().unwrap(), lb.listeners[1].local_addr().unwrap());
+
+    lb.abuse.set_config(abuse::AbuseConfig { ban_after: Some(2), ..abuse::AbuseConfig::default() });
+
+    let rejected = |lb: &LoadBalancer, listener: &str, reason: &str| lb.metrics.get_counter(&format!("connections_rejected{{listener=\"{listener}\",reason=\"{reason}\"}}"));
+
+    // Denied sources never get a partial connection
+    let local = std::net::SocketAddr::from(([127, 0, 0, 2], 0));
+    let socket = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+    socket.bind(&local.into()).unwrap();
+    socket.connect(&screened.into()).unwrap();
+
+    let _allowed = TcpStream::connect(screened).unwrap();
+
+    std::thread::sleep(Duration::from_millis(20));
+    lb.handle_listener().unwrap();
+
+    assert!(lb.partial_conns.len() == 1);
+    assert!(rejected(&lb, "screened", "IP_DENIED") == 1);
+
+    // Failed authentications get the source banned
+    lb.partial_conns.clear();
+    lb.record_auth_failure(&"127.0.0.1".parse().unwrap());
+    lb.record_auth_failure(&"127.0.0.1".parse().unwrap());
+    assert!(lb.metrics.get_counter("sources_banned") == 1);
+    assert!(lb.admin_status().contains("banned 127.0.0.1"));
+
+    let _banned = TcpStream::connect(screened).unwrap();
+
+    std::thread::sleep(Duration::from_millis(20));
+    lb.handle_listener().unwrap();
+
+    assert!(lb.partial_conns.is_empty());
+    assert!(rejected(&lb, "screened", "IP_BANNED") == 1);
+
+    // A trusted proxy is screened on the client address it passes on instead of its own
+    let mut outside = TcpStream::connect(proxied).unwrap();
+    let mut inside = TcpStream::connect(proxied).unwrap();
+
+    outside.write_all(format!("PROXY TCP4 192.0.2.5 127.0.0.1 51000 {}\r\n", proxied.port()).as_bytes()).unwrap();
+    inside.write_all(format!("PROXY TCP4 10.1.2.3 127.0.0.1 51000 {}\r\n", proxied.port()).as_bytes()).unwrap();
+
+    std::thread::sleep(Duration::from_millis(20));
+    lb.handle_listener().unwrap();
+    assert!(lb.partial_conns.len() == 2);
+
+    for partial in lb.partial_conns.iter_mut()
+    {
+        partial.poll().unwrap();
+    }
+
+    lb.handle_partial_connections();
+
+    assert!(lb.partial_conns.len() == 1);
+    assert!(lb.partial_conns[0].get_peer_addr() == Some("10.1.2.3:51000".parse().unwrap()));
+    assert!(rejected(&lb, "proxied", "IP_DENIED") == 1);
+}
+
+#[test]
+fn test_load_balancer_early_close_not_banned()
+{
+    let config = config::create_server_tls_config(false).unwrap();
+
+    let mut lb = LoadBalancer::with_listeners(vec![ listener::ListenerConfig::new("default".into(), "127.0.0.1:0".parse().unwrap(), config) ]).unwrap();
+    let addr = lb.listeners[0].local_addr().unwrap();
+
+    lb.abuse.set_config(abuse::AbuseConfig { ban_after: Some(2), ..abuse::AbuseConfig::default() });
+    lb.timeouts.handshake = Duration::from_millis(50);
+
+    let drain = |lb: &mut LoadBalancer|
+    {
+        // past the handshake timeout
+        for _ in 0..40
+        {
+            lb.handle_listener().unwrap();
+            lb.handle_partial_connections();
+            lb.handle_connecting_connections();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    };
+
+    // Port scans and L4 health checks connect and close without a word
+    for _ in 0..3
+    {
+        drop(TcpStream::connect(addr).unwrap());
+    }
+
+    // or connect and never speak
+    let _silent = TcpStream::connect(addr).unwrap();
+
+    drain(&mut lb);
+
+    assert!(lb.partial_conns.is_empty());
+    assert!(!lb.abuse.is_banned(&addr.ip()));
+    assert!(lb.metrics.get_counter("sources_banned") == 0);
+
+    // A handshake that was started and failed does count
+    for _ in 0..2
+    {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all("GET / HTTP/1.1\r\n\r\n".as_bytes()).unwrap();
+    }
+
+    drain(&mut lb);
+
+    assert!(lb.abuse.is_banned(&addr.ip()));
+}
+
+#[test]
+fn test_load_balancer_common_name_identity()
+{
+    let config = config::create_server_tls_config(false).unwrap();
+
+    let mut by_name = listener::ListenerConfig::new("by_name".into(), "127.0.0.1:0".parse().unwrap(), config);
+    by_name.identity = listener::IdentityRules { source: listener::IdentitySource::COMMON_NAME, allowed: Some(vec![ "fourth".into() ]) };
+
+    let mut lb = LoadBalancer::with_listeners(vec![ by_name ]).unwrap();
+    let addr = lb.listeners[0].local_addr().unwrap();
+
+    let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let health = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let mut sg = server::ServerGroup::new(3);
+    sg.set_health_check(server::HealthCheckConfig { kind: probe::ProbeKind::TCP_CONNECT, port: Some(health.local_addr().unwrap().port()), interval: Duration::from_millis(10), ..server::HealthCheckConfig::default() });
+    sg.add_server(0, upstream.local_addr().unwrap().to_string());
+    lb.server_groups.insert(3, sg);
+
+    let started = std::time::Instant::now();
+
+    while lb.server_groups[&3].find_min_and_healthy().is_none() && started.elapsed() < Duration::from_secs(5)
+    {
+        lb.handle_server_groups().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    let mut fourth = client::Client::new("fourth@fourth.com".into(), 3);
+    fourth.set_common_name("fourth".into());
+    lb.add_client(fourth);
+
+    let handshake = tls_connect(addr, "fourth");
+
+    let started = std::time::Instant::now();
+
+    while lb.clients["fourth@fourth.com"].get_connection_count() == 0 && started.elapsed() < Duration::from_secs(5)
+    {
+        lb.handle_listener().unwrap();
+        lb.handle_partial_connections();
+        lb.handle_connecting_connections();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    let _stream = handshake.join().unwrap();
+
+    // The certificate's common name is mapped to the client record keyed by email
+    assert!(lb.clients["fourth@fourth.com"].get_connection_count() == 1);
+    assert!(upstream.accept().is_ok());
+    assert!(lb.metrics.get_counter("connections_rejected{listener=\"by_name\",reason=\"UNKNOWN_CLIENT\"}") == 0);
+    assert!(lb.metrics.get_counter("connections_accepted{listener=\"by_name\"}") == 1);
+}
+
+#[test]
+fn test_load_balancer_proxy_protocol_upstream()
+{
+    let config = config::create_server_tls_config(false).unwrap();
+
+    let mut by_name = listener::ListenerConfig::new("by_name".into(), "127.0.0.1:0".parse().unwrap(), config);
+    by_name.identity = listener::IdentityRules { source: listener::IdentitySource::COMMON_NAME, allowed: None };
+
+    let mut lb = LoadBalancer::with_listeners(vec![ by_name ]).unwrap();
+    let addr = lb.listeners[0].local_addr().unwrap();
+
+    let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let health = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let mut sg = server::ServerGroup::new(3);
+    sg.set_health_check(server::HealthCheckConfig { kind: probe::ProbeKind::TCP_CONNECT, port: Some(health.local_addr().unwrap().port()), interval: Duration::from_secs(30), ..server::HealthCheckConfig::default() });
+    sg.set_proxy_protocol(true);
+    sg.add_server(0, upstream.local_addr().unwrap().to_string());
+    lb.server_groups.insert(3, sg);
+
+    let started = std::time::Instant::now();
+
+    while lb.server_groups[&3].find_min_and_healthy().is_none() && started.elapsed() < Duration::from_secs(5)
+    {
+        lb.handle_server_groups().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    // The health check opens with a LOCAL header
+    let (mut probe, _) = health.accept().unwrap();
+    let header = proxy_protocol::ProxyHeader::read_from(&mut probe).unwrap();
+    assert!(header.source.is_none() && header.destination.is_none());
+
+    let mut fourth = client::Client::new("fourth@fourth.com".into(), 3);
+    fourth.set_common_name("fourth".into());
+    lb.add_client(fourth);
+
+    let handshake = tls_connect(addr, "fourth");
+
+    let started = std::time::Instant::now();
+
+    while lb.clients["fourth@fourth.com"].get_connection_count() == 0 && started.elapsed() < Duration::from_secs(5)
+    {
+        lb.handle_listener().unwrap();
+        lb.handle_partial_connections();
+        lb.handle_connecting_connections();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    let stream = handshake.join().unwrap();
+
+    // The upstream gets the client's address and identity before any data
+    let (mut up_stream, _) = upstream.accept().unwrap();
+    let header = proxy_protocol::ProxyHeader::read_from(&mut up_stream).unwrap();
+
+    assert!(header.source == Some(stream.local_addr().unwrap()));
+    assert!(header.destination == Some(addr));
+    assert!(header.get_tlv(proxy_protocol::TLV_IDENTITY) == Some("fourth".as_bytes()));
+    assert!(header.get_tlv(proxy_protocol::TLV_UNIQUE_ID).is_some());
+}
+
+#[test]
+fn test_load_balancer_connect_retries()
+{
+    let mut lb = LoadBalancer::with_listeners(vec![]).unwrap();
+
+    // the first two have nothing listening but pass the health check on the shared health port
+    let dead : Vec<std::net::SocketAddr> = (0..2).map(|_| std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()).collect();
+    let live = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let health = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let mut sg = server::ServerGroup::new(4);
+    sg.set_health_check(server::HealthCheckConfig { kind: probe::ProbeKind::TCP_CONNECT, port: Some(health.local_addr().unwrap().port()), interval: Duration::from_secs(30), ..server::HealthCheckConfig::default() });
+    sg.add_server(0, dead[0].to_string());
+    sg.add_server(1, dead[1].to_string());
+    sg.add_server(2, live.local_addr().unwrap().to_string());
+    lb.server_groups.insert(4, sg);
+
+    let ctx = balancer::PickContext { identity: "first@first.com".into(), source_ip: None, listener: 0 };
+    let started = std::time::Instant::now();
+
+    // until each server can be picked on its own
+    while (0..3).any(|i| lb.server_groups.get_mut(&4).unwrap().select_server_excluding(&ctx, &[ (i + 1) % 3, (i + 2) % 3 ]) != Some(i)) && started.elapsed() < Duration::from_secs(5)
+    {
+        lb.handle_server_groups().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    let retries = "upstream_retries{group=\"4\"}";
+
+    // Least connections tries the dead servers first, by id
+    lb.server_groups.get_mut(&4).unwrap().set_retry_policy(server::RetryPolicy { max_retries: 2, budget: Duration::from_secs(1) });
+    assert!(matches!(connect_in_group(&mut lb, 4, &ctx), Ok((2, _))));
+    assert!(live.accept().is_ok());
+    assert!(lb.metrics.get_counter(retries) == 2);
+
+    // One retry is not enough to reach the live one
+    lb.server_groups.get_mut(&4).unwrap().set_retry_policy(server::RetryPolicy { max_retries: 1, budget: Duration::from_secs(1) });
+    assert!(matches!(connect_in_group(&mut lb, 4, &ctx), Err(client::Rejection::UPSTREAM_CONNECT_FAILED)));
+    assert!(lb.metrics.get_counter(retries) == 3);
+
+    // Out of budget after the first attempt, whatever the retries left
+    lb.server_groups.get_mut(&4).unwrap().set_retry_policy(server::RetryPolicy { max_retries: 2, budget: Duration::ZERO });
+    assert!(matches!(connect_in_group(&mut lb, 4, &ctx), Err(client::Rejection::UPSTREAM_CONNECT_FAILED)));
+    assert!(lb.metrics.get_counter(retries) == 3);
+}
+
+#[test]
+fn test_load_balancer_listener_default_group()
+{
+    let config = config::create_server_tls_config(false).unwrap();
+
+    let mut batch = listener::ListenerConfig::new("batch".into(), "127.0.0.1:0".parse().unwrap(), Arc::clone(&config));
+    batch.default_group = Some(1);
+
+    let plain = listener::ListenerConfig::new("plain".into(), "127.0.0.1:0".parse().unwrap(), config);
+
+    let mut lb = LoadBalancer::with_listeners(vec![ batch, plain ]).unwrap();
+
+    let mut allowed = client::Client::new("first@first.com".into(), 0);
+    allowed.allow_server_group(1);
+    lb.add_client(allowed);
+    lb.add_client(client::Client::new("second@second.com".into(), 0));
+
+    // Routed to the listener's group only when allowed in it
+    assert!(lb.get_route(&lb.clients["first@first.com"], 0) == Ok(1));
+    assert!(lb.get_route(&lb.clients["second@second.com"], 0) == Err(client::Rejection::GROUP_NOT_ALLOWED));
+
+    // Otherwise the client's own group
+    assert!(lb.get_route(&lb.clients["first@first.com"], 1) == Ok(0));
+    assert!(lb.get_route(&lb.clients["second@second.com"], 1) == Ok(0));
+
+    let ctx = balancer::PickContext { identity: "second@second.com".into(), source_ip: None, listener: 0 };
+
+    assert!(matches!(lb.admit_and_connect(&ctx, false, None), Err((client::Rejection::GROUP_NOT_ALLOWED, None))));
+}
+
+#[test]
+fn test_load_balancer_group_bandwidth_by_route()
+{
+    let config = config::create_server_tls_config(false).unwrap();
+
+    let mut batch = listener::ListenerConfig::new("batch".into(), "127.0.0.1:0".parse().unwrap(), config);
+    batch.default_group = Some(1);
+
+    let mut lb = LoadBalancer::with_listeners(vec![ batch ]).unwrap();
+    let addr = lb.listeners[0].local_addr().unwrap();
+
+    let upstream = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let health = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+    // No refill so whatever is used stays used
+    let mut sg0 = server::ServerGroup::new(0);
+    sg0.set_bandwidth_limit(rate_limit::RateLimitPolicy::new(0.0, 100000.0));
+    lb.server_groups.insert(0, sg0);
+
+    let mut sg1 = server::ServerGroup::new(1);
+    sg1.set_bandwidth_limit(rate_limit::RateLimitPolicy::new(0.0, 100000.0));
+    sg1.set_health_check(server::HealthCheckConfig { kind: probe::ProbeKind::TCP_CONNECT, port: Some(health.local_addr().unwrap().port()), interval: Duration::from_secs(30), ..server::HealthCheckConfig::default() });
+    sg1.add_server(0, upstream.local_addr().unwrap().to_string());
+    lb.server_groups.insert(1, sg1);
+
+    let mut first = client::Client::new("first@first.com".into(), 0);
+    first.allow_server_group(1);
+    lb.add_client(first);
+
+    let started = std::time::Instant::now();
+
+    while lb.server_groups[&1].find_min_and_healthy().is_none() && started.elapsed() < Duration::from_secs(5)
+    {
+        lb.handle_server_groups().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    let handshake = tls_connect(addr, "first");
+
+    let started = std::time::Instant::now();
+
+    while lb.clients["first@first.com"].get_connection_count() == 0 && started.elapsed() < Duration::from_secs(5)
+    {
+        lb.poll().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    let _stream = handshake.join().unwrap();
+
+    let (mut up_stream, _) = upstream.accept().unwrap();
+    up_stream.write_all(&[0; 3000]).unwrap();
+
+    let started = std::time::Instant::now();
+
+    while lb.server_groups.get_mut(&1).unwrap().get_bandwidth_mut().unwrap().available() > 97000.0 && started.elapsed() < Duration::from_secs(5)
+    {
+        lb.handle_clients().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    // Charged to the group the listener routed to, not the client's own
+    assert!(lb.server_groups.get_mut(&1).unwrap().get_bandwidth_mut().unwrap().available() <= 97000.0);
+    assert!(lb.server_groups.get_mut(&0).unwrap().get_bandwidth_mut().unwrap().available() == 100000.0);
+}
+
+#[test]
+fn test_load_balancer_discovery()
+{
+    let mut lb = LoadBalancer::with_listeners(vec![]).unwrap();
+
+    let mut sg = server::ServerGroup::new(2);
+    sg.add_server(0, "127.0.0.1:2500".into());
+    lb.server_groups.insert(2, sg);
+
+    assert!(config::add_discovery(&mut lb, "9=host:127.0.0.1:2600").is_err());
+    assert!(config::add_discovery(&mut lb, "2=host:127.0.0.1").is_err());
+    assert!(config::add_discovery(&mut lb, "2=mdns:upstream.local").is_err());
+    assert!(lb.discovery_workers.is_empty());
+
+    config::add_discovery(&mut lb, "2=host:127.0.0.1:2600").unwrap();
+
+    let started = std::time::Instant::now();
+
+    while lb.metrics.get_counter("discovery_updates{group=\"2\"}") == 0 && started.elapsed() < Duration::from_secs(5)
+    {
+        lb.handle_server_groups().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    // The resolved server replaces the configured one, which goes once drained
+    lb.handle_server_groups().unwrap();
+
+    assert!(lb.metrics.get_counter("discovery_updates{group=\"2\"}") == 1);
+    assert!(lb.server_groups[&2].get_server_address(&0).is_none());
+    assert!(lb.server_groups[&2].get_server_address(&1) == Some(&"127.0.0.1:2600".to_string()));
+}
+
+#[test]
+fn test_load_balancer_file_discovery()
+{
+    let mut lb = LoadBalancer::with_listeners(vec![]).unwrap();
+
+    lb.server_groups.insert(3, server::ServerGroup::new(3));
+
+    let dir = std::env::temp_dir().join(format!("lb_test_file_discovery_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("batch.json"), r#"[ { "addr": "127.0.0.1:2700" }, { "addr": "127.0.0.1:2701", "backup": true } ]"#).unwrap();
+
+    assert!(config::add_discovery(&mut lb, &format!("3=dir:{}", dir.display())).is_err());
+
+    config::add_discovery(&mut lb, &format!("3=dir:batch:{}", dir.display())).unwrap();
+
+    let started = std::time::Instant::now();
+
+    while lb.metrics.get_counter("discovery_updates{group=\"3\"}") == 0 && started.elapsed() < Duration::from_secs(5)
+    {
+        lb.handle_server_groups().unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+    }
+
+    assert!(lb.server_groups[&3].get_server_address(&0) == Some(&"127.0.0.1:2700".to_string()));
+    assert!(lb.server_groups[&3].get_server_address(&1) == Some(&"127.0.0.1:2701".to_string()));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_load_balancer_fallback_group_policy()
+{
+    let mut lb = LoadBalancer::with_listeners(vec![]).unwrap();
+
+    // Nothing healthy anywhere, group 0 falls back to group 1 which queues
+    let mut sg0 = server::ServerGroup::new(0);
+    sg0.set_no_healthy_policy(server::NoHealthyPolicy::FALLBACK(1));
+    lb.server_groups.insert(0, sg0);
+
+    let mut sg1 = server::ServerGroup::new(1);
+    sg1.set_no_healthy_policy(server::NoHealthyPolicy::QUEUE(Duration::from_secs(5)));
+    lb.server_groups.insert(1, sg1);
+
+    lb.add_client(client::Client::new("first@first.com".into(), 0));
+
+    let ctx = balancer::PickContext { identity: "first@first.com".into(), source_ip: None, listener: 0 };
+
+    assert!(matches!(lb.admit_and_connect(&ctx, false, None), Err((client::Rejection::NO_HEALTHY_SERVER, Some(1)))));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let _client_stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (down_stream, _) = listener.accept().unwrap();
+
+    let mut par_cxn = client::PartialConnection::new(down_stream, rustls::ServerConnection::new(config::create_server_tls_config(false).unwrap()).unwrap(), Duration::from_secs(10));
+    par_cxn.set_client_id("first@first.com".into());
+
+    lb.complete_partial_connection(&"first@first.com".into(), par_cxn, None, false);
+
+    // Queued per the policy of group 1, not failed per group 0's
+    assert!(lb.queued_conns.len() == 1);
+    assert!(lb.metrics.get_counter("group_fallbacks{group=\"0\",fallback=\"1\"}") == 2);
+
+    // Nothing is tried again while the route has no server, it is only counted once
+    for _ in 0..20
+    {
+        lb.handle_queued_connections();
+    }
+
+    assert!(lb.queued_conns.len() == 1);
+    assert!(lb.metrics.get_counter("group_fallbacks{group=\"0\",fallback=\"1\"}") == 2);
+    assert!(lb.metrics.get_counter("connections_queued{reason=\"NO_HEALTHY_SERVER\"}") == 1);
+    assert!(lb.next_conn_id == 0);
+
+    // Out of time, rejected without another try
+    lb.queued_conns[0].deadline = std::time::Instant::now();
+    lb.handle_queued_connections();
+
+    assert!(lb.queued_conns.is_empty());
+    assert!(lb.metrics.get_counter("group_fallbacks{group=\"0\",fallback=\"1\"}") == 2);
+}
+
+// TLS client for the tests, the stream is returned once the handshake is done
+#[cfg(test)]
+fn tls_connect(addr: std::net::SocketAddr, name: &str) -> std::thread::JoinHandle<TcpStream>
+{
+    let client_config = config::create_client_tls_config(false, &name.to_string()).unwrap();
+
+    std::thread::spawn(move ||
+    {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut tls_conn = rustls::ClientConnection::new(client_config, "localhost".try_into().unwrap()).unwrap();
+
+        while tls_conn.is_handshaking()
+        {
+            tls_conn.complete_io(&mut stream).unwrap();
+        }
+
+        // the client's certificate and finished are still queued
+        while tls_conn.wants_write()
+        {
+            tls_conn.write_tls(&mut stream).unwrap();
+        }
+
+        stream
+    })
+}
+
+// Start a connect in the group and poll it until it is made or rejected
+#[cfg(test)]
+fn connect_in_group(lb: &mut LoadBalancer, server_group_id: u32, ctx: &balancer::PickContext) -> Result<(u32, TcpStream), client::Rejection>
+{
+    let mut connect = lb.start_group_connect(server_group_id, ctx, None)?;
+
+    loop
+    {
+        if let Some(up_stream) = lb.poll_group_connect(&mut connect, ctx, None)?
+        {
+            return Ok((connect.server_id, up_stream));
+        }
+
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
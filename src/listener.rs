@@ -0,0 +1,200 @@
+use std::net::{SocketAddr, TcpListener};
+
+use std::sync::Arc;
+
+use x509_parser::prelude::*;
+
+use log::info;
+
+use crate::acl::{self, Cidr, IpAcl};
+
+// Listen backlog of every listener
+const LISTEN_BACKLOG : i32 = 1024;
+
+// Which field of the client certificate names the client
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdentitySource
+{
+    // emailAddress of the subject
+    EMAIL,
+    // CN of the subject
+    #[allow(dead_code)] // for listeners set up in config.rs
+    COMMON_NAME,
+}
+
+impl IdentitySource
+{
+    pub fn extract(&self, cert: &[u8]) -> Result<String, Box<dyn std::error::Error>>
+    {
+        let (_, cert) = X509Certificate::from_der(cert)?;
+
+        let value = match self
+        {
+            IdentitySource::EMAIL => cert.subject().iter_email().next(),
+            IdentitySource::COMMON_NAME => cert.subject().iter_common_name().next(),
+        };
+
+        match value
+        {
+            Some(value) => Ok(value.as_str()?.to_string()),
+            None => Err(format!("No {:?} found", self).into()),
+        }
+    }
+}
+
+// Who may connect through a listener
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdentityRules
+{
+    pub source  : IdentitySource,
+    pub allowed : Option<Vec<String>>, // None lets every known client in
+}
+
+impl Default for IdentityRules
+{
+    fn default() -> Self
+    {
+        Self { source: IdentitySource::EMAIL, allowed: None }
+    }
+}
+
+impl IdentityRules
+{
+    pub fn is_allowed(&self, identity: &str) -> bool
+    {
+        self.allowed.as_ref().map(|v| v.iter().any(|a| a == identity)).unwrap_or(true)
+    }
+}
+
+pub struct ListenerConfig
+{
+    pub name            : String, // shows up in logs and metrics
+    pub bind            : SocketAddr,
+    pub v6_only         : bool, // an IPv6 bind address without it is dual stack
+    pub tls             : Arc<rustls::ServerConfig>,
+    pub identity        : IdentityRules,
+    pub default_group   : Option<u32>, // connections go to this group instead of the client's own
+    pub accept_proxy    : Option<Vec<Cidr>>, // read a PROXY protocol header from connections of these sources
+    pub acl             : IpAcl, // checked right after accept, before any TLS work
+}
+
+impl ListenerConfig
+{
+    pub fn new(name: String, bind: SocketAddr, tls: Arc<rustls::ServerConfig>) -> Self
+    {
+        Self { name, bind, v6_only: false, tls, identity: IdentityRules::default(), default_group: None, accept_proxy: None, acl: IpAcl::default() }
+    }
+}
+
+pub struct Listener
+{
+    config  : ListenerConfig,
+    socket  : TcpListener,
+}
+
+impl Listener
+{
+    pub fn bind(config: ListenerConfig) -> Result<Self, Box<dyn std::error::Error>>
+    {
+        let domain = socket2::Domain::for_address(config.bind);
+
+        let socket = socket2::Socket::new(domain, socket2::Type::STREAM, Some(socket2::Protocol::TCP))?;
+
+        if config.bind.is_ipv6()
+        {
+            socket.set_only_v6(config.v6_only)?;
+        }
+
+        socket.set_reuse_address(true)?;
+        socket.bind(&config.bind.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        socket.set_nonblocking(true)?;
+
+        let socket : TcpListener = socket.into();
+
+        info!("Listener {} listening on {}{}", config.name, socket.local_addr()?,
+              if config.bind.is_ipv6() && !config.v6_only { " (dual stack)" } else { "" });
+
+        Ok(Self { config, socket })
+    }
+
+    pub fn get_name(&self) -> &str
+    {
+        &self.config.name
+    }
+
+    pub fn get_config(&self) -> &ListenerConfig
+    {
+        &self.config
+    }
+
+    pub fn get_socket(&self) -> &TcpListener
+    {
+        &self.socket
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr>
+    {
+        self.socket.local_addr()
+    }
+
+    // Only trusted sources may tell us the client address, everyone else is taken at face value
+    pub fn expects_proxy_header(&self, peer_addr: &SocketAddr) -> bool
+    {
+        self.config.accept_proxy.as_ref().map(|v| acl::any_contains(v, &peer_addr.ip())).unwrap_or(false)
+    }
+}
+
+#[test]
+fn test_listener_ipv6_dual_stack()
+{
+    let tls = crate::config::create_server_tls_config(false).unwrap();
+
+    let listener = match Listener::bind(ListenerConfig::new("v6".into(), "[::]:0".parse().unwrap(), tls))
+    {
+        Ok(listener) => listener,
+        Err(e) =>
+        {
+            // no IPv6 in this environment
+            log::warn!("Skipping IPv6 listener test: {e}");
+            return;
+        }
+    };
+
+    let port = listener.local_addr().unwrap().port();
+
+    assert!(listener.get_name() == "v6");
+    assert!(port != 0);
+
+    // Both families reach a dual stack listener
+    let v4 = std::net::TcpStream::connect(("127.0.0.1", port));
+    let v6 = std::net::TcpStream::connect(("::1", port));
+
+    std::thread::sleep(std::time::Duration::from_millis(20));
+
+    let mut accepted = 0;
+    while listener.get_socket().accept().is_ok()
+    {
+        accepted += 1;
+    }
+
+    assert!(accepted == v4.is_ok() as usize + v6.is_ok() as usize);
+    assert!(v4.is_ok());
+}
+
+#[test]
+fn test_listener_identity_rules()
+{
+    let rules = IdentityRules { source: IdentitySource::EMAIL, allowed: Some(vec!["first@first.com".into()]) };
+
+    assert!(rules.is_allowed("first@first.com"));
+    assert!(!rules.is_allowed("second@second.com"));
+    assert!(IdentityRules::default().is_allowed("second@second.com"));
+
+    let cert = std::fs::read("certs/first.crt").unwrap();
+    let der = rustls_pemfile::certs(&mut std::io::BufReader::new(&cert[..])).unwrap().remove(0);
+
+    assert!(IdentitySource::EMAIL.extract(&der).unwrap() == "first@first.com");
+    assert!(IdentitySource::COMMON_NAME.extract(&der).unwrap() == "first");
+}
@@ -0,0 +1,106 @@
+use std::collections::*;
+
+use std::time::{Duration, Instant};
+
+
+// Simple in process counters and gauges.
+// Names carry their labels, e.g. connections_rejected{listener="default",reason="RATE_LIMITED"}
+// and are rendered in a prometheus like text format. Counters stay off labels that
+// grow with the clients, e.g. an identity from a certificate, so there is one line per
+// listener, group or server rather than one per client.
+pub struct Metrics
+{
+    counters        : BTreeMap<String, u64>,
+    gauges          : BTreeMap<String, f64>,
+    last_report     : Instant,
+    report_interval : Duration,
+}
+
+// Label values are quoted, so quotes, backslashes and new lines in them are escaped
+pub fn label(value: &str) -> String
+{
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Metrics
+{
+    pub fn new(report_interval: Duration) -> Self
+    {
+        Self { counters: BTreeMap::new(), gauges: BTreeMap::new(), last_report: Instant::now(), report_interval }
+    }
+
+    pub fn incr(&mut self, name: &str)
+    {
+        self.add(name, 1);
+    }
+
+    pub fn add(&mut self, name: &str, n: u64)
+    {
+        *self.counters.entry(name.to_string()).or_insert(0) += n;
+    }
+
+    pub fn set_gauge(&mut self, name: &str, value: f64)
+    {
+        self.gauges.insert(name.to_string(), value);
+    }
+
+    #[cfg(test)]
+    pub fn get_counter(&self, name: &str) -> u64
+    {
+        *self.counters.get(name).unwrap_or(&0)
+    }
+
+    #[cfg(test)]
+    pub fn get_gauge(&self, name: &str) -> Option<f64>
+    {
+        self.gauges.get(name).copied()
+    }
+
+    pub fn render(&self) -> String
+    {
+        let mut out = String::new();
+
+        for (k, v) in self.counters.iter()
+        {
+            out.push_str(&format!("{k} {v}\n"));
+        }
+
+        for (k, v) in self.gauges.iter()
+        {
+            out.push_str(&format!("{k} {v:.3}\n"));
+        }
+
+        out
+    }
+
+    // Returns true once every report interval
+    pub fn should_report(&mut self) -> bool
+    {
+        if self.last_report.elapsed() >= self.report_interval
+        {
+            self.last_report = Instant::now();
+            return true;
+        }
+
+        false
+    }
+}
+
+#[test]
+fn test_metrics_render()
+{
+    let mut metrics = Metrics::new(Duration::from_secs(60));
+
+    metrics.incr("rejected{client=\"a\"}");
+    metrics.incr("rejected{client=\"a\"}");
+    metrics.add("accepted", 5);
+    metrics.set_gauge("tokens{client=\"a\"}", 2.5);
+
+    assert!(metrics.get_counter("rejected{client=\"a\"}") == 2);
+    assert!(metrics.get_counter("missing") == 0);
+
+    assert!(metrics.render() == "accepted 5\nrejected{client=\"a\"} 2\ntokens{client=\"a\"} 2.500\n");
+
+    assert!(label("plain") == "plain");
+    assert!(label("a\"b\\c\nd") == "a\\\"b\\\\c\\nd");
+}
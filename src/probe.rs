@@ -0,0 +1,587 @@
+use std::net::{SocketAddr, TcpStream};
+
+use std::io::{Write, Read};
+
+use std::time::{Duration, Instant};
+
+use std::sync::Arc;
+
+
+use crate::proxy_protocol::ProxyHeader;
+
+// The health check exchange answered by the upstream, see src/bin/upstream.rs
+pub const HEALTH_REQUEST  : &[u8] = b"HEALTH";
+pub const HEALTH_RESPONSE : &[u8] = b"OK";
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ProbeStatus
+{
+    PENDING,
+    PASSED,
+    FAILED(String),
+}
+
+// A single health check run against one server.
+// Polled until it passes or fails, the HealthChecker owning it
+// enforces the timeout and drops it afterwards.
+// Probes are Send as they run on the health worker threads.
+pub trait Probe: Send
+{
+    fn poll(&mut self) -> ProbeStatus;
+
+    // Round trip of the TCP handshake, timed around the connect itself
+    // so it does not depend on how often the probe is polled
+    fn get_rtt(&self) -> Option<Duration>
+    {
+        None
+    }
+}
+
+// Health check protocol of a server group
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[allow(dead_code)] // picked per group in config.rs
+#[derive(Clone)]
+pub enum ProbeKind
+{
+    // Passes once a TCP connection is made
+    TCP_CONNECT,
+    // Sends the bytes and passes when the reply starts with expect
+    SEND_EXPECT { send: Vec<u8>, expect: Vec<u8> },
+    // Passes when the status line has the expected status code
+    HTTP_GET { path: String, host: String, status: u16 },
+    // Passes when a TLS handshake completes
+    TLS { server_name: String, config: Arc<rustls::ClientConfig> },
+    // Passes when the command exits with 0.
+    // The server address is passed in the HEALTH_CHECK_ADDR environment variable.
+    EXEC { command: String, args: Vec<String> },
+}
+
+impl Default for ProbeKind
+{
+    fn default() -> Self
+    {
+        ProbeKind::SEND_EXPECT { send: HEALTH_REQUEST.to_vec(), expect: HEALTH_RESPONSE.to_vec() }
+    }
+}
+
+impl std::fmt::Debug for ProbeKind
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            ProbeKind::TCP_CONNECT                   => write!(f, "TCP_CONNECT"),
+            ProbeKind::SEND_EXPECT { send, expect }  => write!(f, "SEND_EXPECT({:?}, {:?})", String::from_utf8_lossy(send), String::from_utf8_lossy(expect)),
+            ProbeKind::HTTP_GET { path, status, .. } => write!(f, "HTTP_GET({path}, {status})"),
+            ProbeKind::TLS { server_name, .. }       => write!(f, "TLS({server_name})"),
+            ProbeKind::EXEC { command, args }        => write!(f, "EXEC({command} {})", args.join(" ")),
+        }
+    }
+}
+
+impl ProbeKind
+{
+    // With proxy_protocol the network probes open with a PROXY protocol LOCAL header,
+    // as servers that expect the header turn away connections without one
+    pub fn create(&self, addr: SocketAddr, connect_timeout: Duration, proxy_protocol: bool) -> Box<dyn Probe>
+    {
+        let conn = Connector { addr, connect_timeout, proxy_protocol, stream: None, rtt: None };
+
+        match self
+        {
+            ProbeKind::TCP_CONNECT =>
+            {
+                Box::new(TcpConnectProbe { conn })
+            },
+            ProbeKind::SEND_EXPECT { send, expect } =>
+            {
+                Box::new(SendExpectProbe { conn, send: send.clone(), expect: expect.clone(), sent: false, received: vec![] })
+            },
+            ProbeKind::HTTP_GET { path, host, status } =>
+            {
+                let request = format!("GET {path} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: lb-health-check\r\nConnection: close\r\n\r\n");
+
+                Box::new(HttpGetProbe { inner: SendExpectProbe { conn, send: request.into_bytes(), expect: vec![], sent: false, received: vec![] }, status: *status })
+            },
+            ProbeKind::TLS { server_name, config } =>
+            {
+                Box::new(TlsProbe { conn, server_name: server_name.clone(), config: Arc::clone(config), tls_conn: None })
+            },
+            ProbeKind::EXEC { command, args } =>
+            {
+                Box::new(ExecProbe { command: command.clone(), args: args.clone(), addr, child: None })
+            },
+        }
+    }
+}
+
+// Shared first step of the network probes
+struct Connector
+{
+    addr            : SocketAddr,
+    connect_timeout : Duration,
+    proxy_protocol  : bool,
+    stream          : Option<TcpStream>,
+    rtt             : Option<Duration>,
+}
+
+impl Connector
+{
+    // Returns Ok(true) once connected
+    fn connect(&mut self) -> Result<bool, String>
+    {
+        if self.stream.is_some()
+        {
+            return Ok(true);
+        }
+
+        // Blocking call, bounded by the connect timeout. Probes run on the health worker threads.
+        let started = Instant::now();
+        let stream = TcpStream::connect_timeout(&self.addr, self.connect_timeout).map_err(|e| format!("connect failed: {e}"))?;
+        self.rtt = Some(started.elapsed());
+
+        if self.proxy_protocol
+        {
+            // the socket is fresh so the header fits in its send buffer
+            let local = ProxyHeader { source: None, destination: None, tlvs: vec![] };
+            let local = local.encode().map_err(|e| format!("PROXY protocol header failed: {e}"))?;
+            (&stream).write_all(&local).map_err(|e| format!("PROXY protocol header failed: {e}"))?;
+        }
+
+        stream.set_nonblocking(true).map_err(|e| e.to_string())?;
+        stream.set_nodelay(true).map_err(|e| e.to_string())?;
+
+        self.stream = Some(stream);
+
+        // Give the server a poll to accept before sending anything
+        Ok(false)
+    }
+}
+
+struct TcpConnectProbe
+{
+    conn : Connector,
+}
+
+impl Probe for TcpConnectProbe
+{
+    fn poll(&mut self) -> ProbeStatus
+    {
+        match self.conn.connect()
+        {
+            Ok(_) => ProbeStatus::PASSED,
+            Err(e) => ProbeStatus::FAILED(e),
+        }
+    }
+
+    fn get_rtt(&self) -> Option<Duration>
+    {
+        self.conn.rtt
+    }
+}
+
+struct SendExpectProbe
+{
+    conn        : Connector,
+    send        : Vec<u8>,
+    expect      : Vec<u8>,
+    sent        : bool,
+    received    : Vec<u8>,
+}
+
+impl SendExpectProbe
+{
+    // Returns the bytes received so far once the reply is complete enough to judge.
+    // Reads until expect.len() bytes have arrived, or the server closes
+    // the connection when there is nothing to expect.
+    fn exchange(&mut self) -> Result<Option<&[u8]>, String>
+    {
+        if !self.conn.connect()?
+        {
+            return Ok(None);
+        }
+
+        let stream = match &mut self.conn.stream
+        {
+            Some(stream) => stream,
+            None => return Err("not connected".into()),
+        };
+
+        if !self.sent
+        {
+            if !self.send.is_empty()
+            {
+                match stream.write_all(&self.send)
+                {
+                    Ok(()) => {},
+                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => { return Ok(None); },
+                    Err(e) => { return Err(format!("send failed: {e}")); }
+                }
+            }
+
+            self.sent = true;
+
+            return Ok(None);
+        }
+
+        let mut buf : [u8; 1024] = [0; 1024];
+
+        match stream.read(&mut buf)
+        {
+            Ok(0) =>
+            {
+                if self.expect.is_empty() && !self.received.is_empty()
+                {
+                    return Ok(Some(&self.received));
+                }
+
+                Err("connection closed before reply".into())
+            },
+            Ok(n) =>
+            {
+                self.received.extend_from_slice(&buf[0..n]);
+
+                if !self.expect.is_empty() && self.received.len() >= self.expect.len()
+                {
+                    return Ok(Some(&self.received));
+                }
+
+                Ok(None)
+            },
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(format!("read failed: {e}")),
+        }
+    }
+}
+
+impl Probe for SendExpectProbe
+{
+    fn poll(&mut self) -> ProbeStatus
+    {
+        let expect = self.expect.clone();
+
+        match self.exchange()
+        {
+            Ok(Some(reply)) if reply.starts_with(&expect) => ProbeStatus::PASSED,
+            Ok(Some(reply)) => ProbeStatus::FAILED(format!("unexpected reply: {:?}", String::from_utf8_lossy(reply))),
+            Ok(None) => ProbeStatus::PENDING,
+            Err(e) => ProbeStatus::FAILED(e),
+        }
+    }
+
+    fn get_rtt(&self) -> Option<Duration>
+    {
+        self.conn.rtt
+    }
+}
+
+struct HttpGetProbe
+{
+    inner   : SendExpectProbe,
+    status  : u16,
+}
+
+impl Probe for HttpGetProbe
+{
+    fn poll(&mut self) -> ProbeStatus
+    {
+        if let Err(e) = self.inner.exchange()
+        {
+            // A closed connection still leaves the status line to check
+            if self.inner.received.is_empty()
+            {
+                return ProbeStatus::FAILED(e);
+            }
+        }
+
+        // Only the status line is needed: HTTP/1.1 200 OK\r\n
+        let received = String::from_utf8_lossy(&self.inner.received).to_string();
+
+        let status_line = match received.split_once("\r\n")
+        {
+            Some((line, _)) => line.to_string(),
+            None => return ProbeStatus::PENDING,
+        };
+
+        let status = status_line.split_whitespace().nth(1).and_then(|v| v.parse::<u16>().ok());
+
+        match status
+        {
+            Some(status) if status == self.status => ProbeStatus::PASSED,
+            Some(status) => ProbeStatus::FAILED(format!("HTTP status {status}, expected {}", self.status)),
+            None => ProbeStatus::FAILED(format!("bad status line: {status_line}")),
+        }
+    }
+
+    fn get_rtt(&self) -> Option<Duration>
+    {
+        self.inner.conn.rtt
+    }
+}
+
+struct TlsProbe
+{
+    conn        : Connector,
+    server_name : String,
+    config      : Arc<rustls::ClientConfig>,
+    tls_conn    : Option<rustls::ClientConnection>,
+}
+
+impl Probe for TlsProbe
+{
+    fn poll(&mut self) -> ProbeStatus
+    {
+        match self.conn.connect()
+        {
+            Ok(true) => {},
+            Ok(false) => return ProbeStatus::PENDING,
+            Err(e) => return ProbeStatus::FAILED(e),
+        }
+
+        if self.tls_conn.is_none()
+        {
+            let server_name = match self.server_name.as_str().try_into()
+            {
+                Ok(server_name) => server_name,
+                Err(e) => return ProbeStatus::FAILED(format!("invalid server name {}: {e}", self.server_name)),
+            };
+
+            match rustls::ClientConnection::new(Arc::clone(&self.config), server_name)
+            {
+                Ok(tls_conn) => { self.tls_conn = Some(tls_conn); },
+                Err(e) => return ProbeStatus::FAILED(format!("TLS setup failed: {e}")),
+            }
+        }
+
+        if let (Some(tls_conn), Some(stream)) = (&mut self.tls_conn, &mut self.conn.stream)
+        {
+            match tls_conn.complete_io(stream)
+            {
+                Ok(_) => {},
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {},
+                Err(e) => { return ProbeStatus::FAILED(format!("TLS handshake failed: {e}")); }
+            }
+
+            if !tls_conn.is_handshaking()
+            {
+                tls_conn.send_close_notify();
+                let _ = tls_conn.write_tls(stream);
+
+                return ProbeStatus::PASSED;
+            }
+        }
+
+        ProbeStatus::PENDING
+    }
+
+    fn get_rtt(&self) -> Option<Duration>
+    {
+        self.conn.rtt
+    }
+}
+
+struct ExecProbe
+{
+    command : String,
+    args    : Vec<String>,
+    addr    : SocketAddr,
+    child   : Option<std::process::Child>,
+}
+
+impl Probe for ExecProbe
+{
+    fn poll(&mut self) -> ProbeStatus
+    {
+        if self.child.is_none()
+        {
+            let child = std::process::Command::new(&self.command)
+                        .args(&self.args)
+                        .env("HEALTH_CHECK_ADDR", self.addr.to_string())
+                        .stdin(std::process::Stdio::null())
+                        .stdout(std::process::Stdio::null())
+                        .stderr(std::process::Stdio::null())
+                        .spawn();
+
+            match child
+            {
+                Ok(child) => { self.child = Some(child); },
+                Err(e) => return ProbeStatus::FAILED(format!("unable to run {}: {e}", self.command)),
+            }
+        }
+
+        if let Some(child) = &mut self.child
+        {
+            match child.try_wait()
+            {
+                Ok(Some(status)) if status.success() => return ProbeStatus::PASSED,
+                Ok(Some(status)) => return ProbeStatus::FAILED(format!("{} exited with {status}", self.command)),
+                Ok(None) => {},
+                Err(e) => return ProbeStatus::FAILED(format!("unable to wait on {}: {e}", self.command)),
+            }
+        }
+
+        ProbeStatus::PENDING
+    }
+}
+
+impl Drop for ExecProbe
+{
+    // Do not leave timed out commands behind
+    fn drop(&mut self)
+    {
+        if let Some(child) = &mut self.child
+        {
+            if let Ok(None) = child.try_wait()
+            {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+}
+
+// Poll a probe until it is done, for tests
+#[cfg(test)]
+fn run_probe(probe: &mut Box<dyn Probe>) -> ProbeStatus
+{
+    for _ in 0..500
+    {
+        let status = probe.poll();
+
+        if status != ProbeStatus::PENDING
+        {
+            return status;
+        }
+
+        std::thread::sleep(Duration::from_millis(2));
+    }
+
+    ProbeStatus::PENDING
+}
+
+#[test]
+fn test_probe_tcp_connect_and_send_expect()
+{
+    // a port that was just free
+    let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+    let mut probe = ProbeKind::TCP_CONNECT.create(closed, Duration::from_millis(100), false);
+    assert!(run_probe(&mut probe) != ProbeStatus::PASSED);
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let handle = std::thread::spawn(move ||
+    {
+        // first connection is the TCP probe, second one is the send / expect probe
+        let _tcp = listener.accept().unwrap();
+
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf : [u8; 5] = [0; 5];
+        stream.read_exact(&mut buf).unwrap();
+        assert!(buf == "HELLO".as_bytes());
+        stream.write_all("READY\n".as_bytes()).unwrap();
+    });
+
+    let mut probe = ProbeKind::TCP_CONNECT.create(addr, Duration::from_millis(100), false);
+    assert!(run_probe(&mut probe) == ProbeStatus::PASSED);
+
+    let mut probe = ProbeKind::SEND_EXPECT { send: "HELLO".as_bytes().to_vec(), expect: "READY".as_bytes().to_vec() }.create(addr, Duration::from_millis(100), false);
+    assert!(run_probe(&mut probe) == ProbeStatus::PASSED);
+
+    // Taken once connected, the bound only catches a runaway timer
+    let rtt = probe.get_rtt().unwrap();
+    assert!(rtt > Duration::ZERO && rtt < Duration::from_secs(1));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_probe_http_get()
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let handle = std::thread::spawn(move ||
+    {
+        for status in [ "200 OK", "503 Service Unavailable" ].iter()
+        {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf : [u8; 1024] = [0; 1024];
+            let n = stream.read(&mut buf).unwrap();
+            assert!(buf[0..n].starts_with("GET /healthz HTTP/1.1\r\n".as_bytes()));
+            stream.write_all(format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").as_bytes()).unwrap();
+        }
+    });
+
+    let kind = ProbeKind::HTTP_GET { path: "/healthz".into(), host: "localhost".into(), status: 200 };
+
+    let mut probe = kind.create(addr, Duration::from_millis(100), false);
+    assert!(run_probe(&mut probe) == ProbeStatus::PASSED);
+
+    let mut probe = kind.create(addr, Duration::from_millis(100), false);
+    assert!(run_probe(&mut probe) == ProbeStatus::FAILED("HTTP status 503, expected 200".into()));
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_probe_tls_handshake()
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let server_config = crate::config::create_server_tls_config(false).unwrap();
+    let client_config = crate::config::create_client_tls_config(false, &"first".to_string()).unwrap();
+
+    let handle = std::thread::spawn(move ||
+    {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut tls_conn = rustls::ServerConnection::new(server_config).unwrap();
+
+        while tls_conn.is_handshaking()
+        {
+            tls_conn.complete_io(&mut stream).unwrap();
+        }
+    });
+
+    let mut probe = ProbeKind::TLS { server_name: "localhost".into(), config: Arc::clone(&client_config) }.create(addr, Duration::from_millis(100), false);
+    assert!(run_probe(&mut probe) == ProbeStatus::PASSED);
+
+    handle.join().unwrap();
+
+    // A peer which does not speak TLS fails the probe instead of hanging it
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let handle = std::thread::spawn(move ||
+    {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut buf : [u8; 1024] = [0; 1024];
+        let _ = stream.read(&mut buf).unwrap();
+        stream.write_all("HTTP/1.1 400 Bad Request\r\n\r\n".as_bytes()).unwrap();
+    });
+
+    let mut probe = ProbeKind::TLS { server_name: "localhost".into(), config: client_config }.create(addr, Duration::from_millis(100), false);
+
+    match run_probe(&mut probe)
+    {
+        ProbeStatus::FAILED(e) => assert!(e.starts_with("TLS handshake failed")),
+        _ => panic!("TLS probe should fail"),
+    }
+
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_probe_exec()
+{
+    let addr : SocketAddr = "127.0.0.1:25026".parse().unwrap();
+
+    let mut probe = ProbeKind::EXEC { command: "sh".into(), args: vec!["-c".into(), "test \"$HEALTH_CHECK_ADDR\" = 127.0.0.1:25026".into()] }.create(addr, Duration::from_millis(100), false);
+    assert!(run_probe(&mut probe) == ProbeStatus::PASSED);
+
+    let mut probe = ProbeKind::EXEC { command: "sh".into(), args: vec!["-c".into(), "exit 3".into()] }.create(addr, Duration::from_millis(100), false);
+    assert!(run_probe(&mut probe) != ProbeStatus::PASSED);
+}
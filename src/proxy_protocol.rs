@@ -0,0 +1,435 @@
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use std::io::Read;
+
+// PROXY protocol, see https://www.haproxy.org/download/2.8/doc/proxy-protocol.txt
+// Headers are sent as v2, both v1 and v2 are accepted.
+const SIGNATURE : [u8; 12] = [ 0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A ];
+
+const VERSION_2     : u8 = 0x20;
+const CMD_LOCAL     : u8 = 0x00;
+const CMD_PROXY     : u8 = 0x01;
+const FAM_UNSPEC    : u8 = 0x00;
+const FAM_TCP4      : u8 = 0x11;
+const FAM_TCP6      : u8 = 0x21;
+
+// Header size before the addresses and TLVs
+const FIXED_LEN : usize = 16;
+
+const V1_PREFIX  : &[u8] = b"PROXY ";
+const V1_MAX_LEN : usize = 107; // including the CRLF
+
+// TLV types, the standard ones and our own from the range kept for applications
+pub const TLV_ALPN          : u8 = 0x01;
+pub const TLV_AUTHORITY     : u8 = 0x02; // SNI sent by the client
+pub const TLV_UNIQUE_ID     : u8 = 0x05; // connection id
+pub const TLV_IDENTITY      : u8 = 0xE0; // identity from the client certificate
+pub const TLV_CERT_SERIAL   : u8 = 0xE1; // serial of the client certificate, hex
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyHeader
+{
+    pub source      : Option<SocketAddr>, // None for a LOCAL header, e.g. a health check
+    pub destination : Option<SocketAddr>,
+    pub tlvs        : Vec<(u8, Vec<u8>)>,
+}
+
+impl ProxyHeader
+{
+    pub fn new(source: SocketAddr, destination: SocketAddr) -> Self
+    {
+        Self { source: Some(source), destination: Some(destination), tlvs: vec![] }
+    }
+
+    // Empty values are left out
+    pub fn add_tlv(&mut self, tlv_type: u8, value: &[u8])
+    {
+        if !value.is_empty()
+        {
+            self.tlvs.push((tlv_type, value.to_vec()));
+        }
+    }
+
+    pub fn get_tlv(&self, tlv_type: u8) -> Option<&[u8]>
+    {
+        self.tlvs.iter().find(|(t, _)| *t == tlv_type).map(|(_, v)| v.as_slice())
+    }
+
+    // Text value of a TLV for logs
+    pub fn get_tlv_str(&self, tlv_type: u8) -> String
+    {
+        self.get_tlv(tlv_type).map(|v| String::from_utf8_lossy(v).into_owned()).unwrap_or_else(|| "-".to_string())
+    }
+
+    // Fails rather than truncate a length that does not fit in 16 bits
+    pub fn encode(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>>
+    {
+        let mut out = SIGNATURE.to_vec();
+
+        let mut body = vec![];
+
+        let fam = match (self.source, self.destination)
+        {
+            (Some(SocketAddr::V4(src)), Some(SocketAddr::V4(dst))) =>
+            {
+                body.extend_from_slice(&src.ip().octets());
+                body.extend_from_slice(&dst.ip().octets());
+                body.extend_from_slice(&src.port().to_be_bytes());
+                body.extend_from_slice(&dst.port().to_be_bytes());
+                FAM_TCP4
+            },
+            (Some(src), Some(dst)) =>
+            {
+                // mixed families are sent as IPv6 with IPv4 mapped addresses
+                body.extend_from_slice(&to_ipv6(src.ip()).octets());
+                body.extend_from_slice(&to_ipv6(dst.ip()).octets());
+                body.extend_from_slice(&src.port().to_be_bytes());
+                body.extend_from_slice(&dst.port().to_be_bytes());
+                FAM_TCP6
+            },
+            _ => FAM_UNSPEC,
+        };
+
+        for (tlv_type, value) in self.tlvs.iter()
+        {
+            let len = u16::try_from(value.len()).map_err(|_| format!("TLV {tlv_type:#x} of {} bytes is too long", value.len()))?;
+
+            body.push(*tlv_type);
+            body.extend_from_slice(&len.to_be_bytes());
+            body.extend_from_slice(value);
+        }
+
+        let len = u16::try_from(body.len()).map_err(|_| format!("PROXY protocol header of {} bytes is too long", body.len()))?;
+
+        let cmd = if fam == FAM_UNSPEC { CMD_LOCAL } else { CMD_PROXY };
+
+        out.push(VERSION_2 | cmd);
+        out.push(fam);
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(&body);
+
+        Ok(out)
+    }
+
+    // Ok(None) while buf does not hold a whole header yet,
+    // otherwise the header and the number of bytes it took up
+    pub fn decode(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, Box<dyn std::error::Error>>
+    {
+        let n = buf.len().min(SIGNATURE.len());
+
+        if buf[0..n] != SIGNATURE[0..n]
+        {
+            return Err("not a PROXY protocol v2 header".into());
+        }
+
+        if buf.len() < FIXED_LEN
+        {
+            return Ok(None);
+        }
+
+        let ver_cmd = buf[12];
+        let fam     = buf[13];
+        let len     = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+        if ver_cmd & 0xF0 != VERSION_2
+        {
+            return Err(format!("unsupported PROXY protocol version {:#x}", ver_cmd >> 4).into());
+        }
+
+        if buf.len() < FIXED_LEN + len
+        {
+            return Ok(None);
+        }
+
+        let body = &buf[FIXED_LEN..FIXED_LEN + len];
+
+        let (source, destination, addr_len) = match (ver_cmd & 0x0F, fam)
+        {
+            (CMD_PROXY, FAM_TCP4) if len >= 12 =>
+            {
+                let src = IpAddr::from([body[0], body[1], body[2], body[3]]);
+                let dst = IpAddr::from([body[4], body[5], body[6], body[7]]);
+                let src_port = u16::from_be_bytes([body[8], body[9]]);
+                let dst_port = u16::from_be_bytes([body[10], body[11]]);
+                (Some(SocketAddr::new(src, src_port)), Some(SocketAddr::new(dst, dst_port)), 12)
+            },
+            (CMD_PROXY, FAM_TCP6) if len >= 36 =>
+            {
+                let mut src : [u8; 16] = [0; 16];
+                let mut dst : [u8; 16] = [0; 16];
+                src.copy_from_slice(&body[0..16]);
+                dst.copy_from_slice(&body[16..32]);
+                let src_port = u16::from_be_bytes([body[32], body[33]]);
+                let dst_port = u16::from_be_bytes([body[34], body[35]]);
+                (Some(SocketAddr::new(from_ipv6(src.into()), src_port)), Some(SocketAddr::new(from_ipv6(dst.into()), dst_port)), 36)
+            },
+            (CMD_LOCAL, _) => (None, None, 0),
+            _ => return Err(format!("unsupported PROXY protocol family {fam:#x}").into()),
+        };
+
+        let mut tlvs = vec![];
+        let mut pos = addr_len;
+
+        // Unix socket addresses and the like are skipped whole
+        if fam != FAM_TCP4 && fam != FAM_TCP6
+        {
+            pos = len;
+        }
+
+        while pos + 3 <= len
+        {
+            let tlv_type = body[pos];
+            let tlv_len  = u16::from_be_bytes([body[pos + 1], body[pos + 2]]) as usize;
+
+            let value = body.get(pos + 3..pos + 3 + tlv_len).ok_or("PROXY protocol TLV out of bounds")?;
+
+            tlvs.push((tlv_type, value.to_vec()));
+            pos += 3 + tlv_len;
+        }
+
+        Ok(Some((ProxyHeader { source, destination, tlvs }, FIXED_LEN + len)))
+    }
+
+    // Text header: "PROXY TCP4 <src> <dst> <src port> <dst port>\r\n" or "PROXY UNKNOWN ...\r\n"
+    pub fn decode_v1(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, Box<dyn std::error::Error>>
+    {
+        let n = buf.len().min(V1_PREFIX.len());
+
+        if buf[0..n] != V1_PREFIX[0..n]
+        {
+            return Err("not a PROXY protocol v1 header".into());
+        }
+
+        let end = match buf.windows(2).take(V1_MAX_LEN - 1).position(|v| v == b"\r\n")
+        {
+            Some(end) => end,
+            None if buf.len() < V1_MAX_LEN => return Ok(None),
+            None => return Err("PROXY protocol v1 header too long".into()),
+        };
+
+        let line = std::str::from_utf8(&buf[0..end])?;
+        let fields : Vec<&str> = line.split(' ').collect();
+
+        let header = match fields.as_slice()
+        {
+            [ "PROXY", "UNKNOWN", .. ] => ProxyHeader { source: None, destination: None, tlvs: vec![] },
+            [ "PROXY", proto @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port ] =>
+            {
+                let src : IpAddr = src.parse()?;
+                let dst : IpAddr = dst.parse()?;
+
+                if src.is_ipv4() != (*proto == "TCP4") || dst.is_ipv4() != (*proto == "TCP4")
+                {
+                    return Err(format!("PROXY protocol v1 addresses do not match {proto}").into());
+                }
+
+                ProxyHeader::new(SocketAddr::new(src, src_port.parse()?), SocketAddr::new(dst, dst_port.parse()?))
+            },
+            _ => return Err(format!("malformed PROXY protocol v1 header: {line}").into()),
+        };
+
+        Ok(Some((header, end + 2)))
+    }
+
+    // Whether buf starts like a v2 header, a start cut short of the whole signature included.
+    // Anything else is a connection without a header.
+    pub fn has_signature(buf: &[u8]) -> bool
+    {
+        let n = buf.len().min(SIGNATURE.len());
+
+        n > 0 && buf[0..n] == SIGNATURE[0..n]
+    }
+
+    // How many bytes to look at to hold the whole header starting in buf.
+    // A v2 header gives its length in its fixed part, up to 16 + 65535 bytes,
+    // anything else fits in the longest v1 header.
+    pub fn needed_len(buf: &[u8]) -> usize
+    {
+        if buf.len() >= FIXED_LEN && buf[0..SIGNATURE.len()] == SIGNATURE
+        {
+            return FIXED_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+        }
+
+        V1_MAX_LEN
+    }
+
+    // Either version, told apart by the first bytes
+    pub fn decode_any(buf: &[u8]) -> Result<Option<(ProxyHeader, usize)>, Box<dyn std::error::Error>>
+    {
+        if buf.first() == Some(&V1_PREFIX[0])
+        {
+            ProxyHeader::decode_v1(buf)
+        }
+        else
+        {
+            ProxyHeader::decode(buf)
+        }
+    }
+
+    // Blocking read of exactly one header, nothing after it is consumed
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<ProxyHeader, Box<dyn std::error::Error>>
+    {
+        let mut buf = vec![0; FIXED_LEN];
+        reader.read_exact(&mut buf)?;
+
+        // checks the signature
+        ProxyHeader::decode(&buf)?;
+
+        let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+        buf.resize(FIXED_LEN + len, 0);
+        reader.read_exact(&mut buf[FIXED_LEN..])?;
+
+        match ProxyHeader::decode(&buf)?
+        {
+            Some((header, _)) => Ok(header),
+            None => Err("PROXY protocol header incomplete".into()),
+        }
+    }
+}
+
+impl std::fmt::Display for ProxyHeader
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match (self.source, self.destination)
+        {
+            (Some(src), Some(dst)) => write!(f, "PROXY {src} -> {dst}")?,
+            _ => write!(f, "PROXY LOCAL")?,
+        }
+
+        write!(f, " identity: {} serial: {} sni: {} alpn: {} id: {}", self.get_tlv_str(TLV_IDENTITY), self.get_tlv_str(TLV_CERT_SERIAL),
+               self.get_tlv_str(TLV_AUTHORITY), self.get_tlv_str(TLV_ALPN), self.get_tlv_str(TLV_UNIQUE_ID))
+    }
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr
+{
+    match ip
+    {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn from_ipv6(ip: Ipv6Addr) -> IpAddr
+{
+    match ip.to_ipv4_mapped()
+    {
+        Some(ip) => IpAddr::V4(ip),
+        None => IpAddr::V6(ip),
+    }
+}
+
+#[test]
+fn test_proxy_protocol_v2_round_trip()
+{
+    let mut header = ProxyHeader::new("192.168.1.10:51000".parse().unwrap(), "10.0.0.1:8443".parse().unwrap());
+
+    header.add_tlv(TLV_IDENTITY, "first@first.com".as_bytes());
+    header.add_tlv(TLV_CERT_SERIAL, "01".as_bytes());
+    header.add_tlv(TLV_AUTHORITY, "localhost".as_bytes());
+    header.add_tlv(TLV_ALPN, "".as_bytes());
+    header.add_tlv(TLV_UNIQUE_ID, "42".as_bytes());
+
+    let mut bytes = header.encode().unwrap();
+
+    assert!(bytes[0..12] == SIGNATURE);
+    assert!(bytes[12] == 0x21 && bytes[13] == FAM_TCP4);
+
+    // Partial headers need more bytes
+    assert!(ProxyHeader::decode(&bytes[0..10]).unwrap().is_none());
+    assert!(ProxyHeader::decode(&bytes[0..20]).unwrap().is_none());
+
+    // Even a few bytes of the signature tell a header from plain data
+    assert!(ProxyHeader::has_signature(&bytes[0..5]) && ProxyHeader::has_signature(&bytes));
+    assert!(!ProxyHeader::has_signature("GET / HTTP/1.1\r\n".as_bytes()) && !ProxyHeader::has_signature(&[]));
+
+    let len = bytes.len();
+    bytes.extend_from_slice("PING".as_bytes());
+
+    let (decoded, used) = ProxyHeader::decode(&bytes).unwrap().unwrap();
+
+    assert!(decoded == header);
+    assert!(used == len);
+    assert!(decoded.get_tlv(TLV_ALPN).is_none());
+    assert!(decoded.to_string() == "PROXY 192.168.1.10:51000 -> 10.0.0.1:8443 identity: first@first.com serial: 01 sni: localhost alpn: - id: 42");
+
+    // read_from leaves the data after the header alone
+    let mut reader = std::io::Cursor::new(bytes);
+    assert!(ProxyHeader::read_from(&mut reader).unwrap() == header);
+    assert!(reader.position() as usize == len);
+
+    assert!(ProxyHeader::decode("GET / HTTP/1.1\r\n".as_bytes()).is_err());
+}
+
+#[test]
+fn test_proxy_protocol_v2_ipv6_and_local()
+{
+    // Mixed families go out as IPv6 and come back as they were
+    let header = ProxyHeader::new("[2001:db8::1]:51000".parse().unwrap(), "127.0.0.1:8443".parse().unwrap());
+
+    let bytes = header.encode().unwrap();
+    assert!(bytes[13] == FAM_TCP6);
+
+    let (decoded, _) = ProxyHeader::decode(&bytes).unwrap().unwrap();
+    assert!(decoded == header);
+
+    let local = ProxyHeader { source: None, destination: None, tlvs: vec![] };
+    let bytes = local.encode().unwrap();
+
+    assert!(bytes.len() == FIXED_LEN && bytes[12] == 0x20);
+    assert!(ProxyHeader::decode(&bytes).unwrap().unwrap().0 == local);
+}
+
+#[test]
+fn test_proxy_protocol_v1()
+{
+    let bytes = "PROXY TCP4 192.168.1.10 10.0.0.1 51000 8443\r\n\x16\x03\x01".as_bytes();
+
+    let (header, used) = ProxyHeader::decode_any(bytes).unwrap().unwrap();
+
+    assert!(header == ProxyHeader::new("192.168.1.10:51000".parse().unwrap(), "10.0.0.1:8443".parse().unwrap()));
+    assert!(used == bytes.len() - 3);
+
+    let (header, _) = ProxyHeader::decode_any("PROXY TCP6 2001:db8::1 ::1 51000 8443\r\n".as_bytes()).unwrap().unwrap();
+    assert!(header.source == Some("[2001:db8::1]:51000".parse().unwrap()));
+
+    let (header, _) = ProxyHeader::decode_any("PROXY UNKNOWN\r\n".as_bytes()).unwrap().unwrap();
+    assert!(header.source.is_none());
+
+    // Partial, malformed and oversized headers
+    assert!(ProxyHeader::decode_any("PROXY TCP4 192.168".as_bytes()).unwrap().is_none());
+    assert!(ProxyHeader::decode_any("PROXY TCP4 192.168.1.10 10.0.0.1 51000\r\n".as_bytes()).is_err());
+    assert!(ProxyHeader::decode_any("PROXY TCP4 2001:db8::1 10.0.0.1 51000 8443\r\n".as_bytes()).is_err());
+    assert!(ProxyHeader::decode_any(&[b'P'; 200]).is_err());
+
+    // v2 goes through the same entry point, anything else is an error
+    let v2 = ProxyHeader::new("192.168.1.10:51000".parse().unwrap(), "10.0.0.1:8443".parse().unwrap());
+    assert!(ProxyHeader::decode_any(&v2.encode().unwrap()).unwrap().unwrap().0 == v2);
+    assert!(ProxyHeader::decode_any(&[ 0x16, 0x03, 0x01 ]).is_err());
+}
+
+#[test]
+fn test_proxy_protocol_needed_len()
+{
+    let mut header = ProxyHeader::new("192.0.2.5:51000".parse().unwrap(), "127.0.0.1:8443".parse().unwrap());
+    header.add_tlv(TLV_IDENTITY, &[ b'a'; 60000 ]);
+
+    let bytes = header.encode().unwrap();
+
+    assert!(ProxyHeader::needed_len(&[]) == V1_MAX_LEN);
+    assert!(ProxyHeader::needed_len(&bytes[0..FIXED_LEN - 1]) == V1_MAX_LEN);
+    assert!(ProxyHeader::needed_len(&bytes[0..FIXED_LEN]) == bytes.len());
+    assert!(ProxyHeader::needed_len("PROXY TCP4 ".as_bytes()) == V1_MAX_LEN);
+
+    assert!(ProxyHeader::decode(&bytes).unwrap().unwrap().0 == header);
+
+    // Lengths that do not fit are an error, not a truncated header
+    header.add_tlv(TLV_CERT_SERIAL, &[ b'b'; 6000 ]);
+    assert!(header.encode().is_err());
+
+    let mut header = ProxyHeader::new("192.0.2.5:51000".parse().unwrap(), "127.0.0.1:8443".parse().unwrap());
+    header.add_tlv(TLV_IDENTITY, &[ b'a'; 70000 ]);
+    assert!(header.encode().is_err());
+}
@@ -0,0 +1,229 @@
+use std::collections::*;
+
+use std::time::{Duration, Instant};
+
+
+// Rate is in tokens per second, burst is the size of the bucket.
+// A client may use up the whole burst at once and then has to wait
+// for tokens to refill at the given rate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitPolicy
+{
+    pub rate    : f64,
+    pub burst   : f64,
+}
+
+impl RateLimitPolicy
+{
+    pub fn new(rate: f64, burst: f64) -> Self
+    {
+        Self { rate, burst }
+    }
+}
+
+impl Default for RateLimitPolicy
+{
+    // Same budget as the old fixed window: 10 connections every 30 seconds
+    fn default() -> Self
+    {
+        Self { rate: 10.0 / 30.0, burst: 10.0 }
+    }
+}
+
+pub struct TokenBucket
+{
+    policy      : RateLimitPolicy,
+    tokens      : f64,
+    last_refill : Instant,
+}
+
+impl TokenBucket
+{
+    pub fn new(policy: RateLimitPolicy) -> Self
+    {
+        let tokens = policy.burst;
+
+        Self { policy, tokens, last_refill: Instant::now() }
+    }
+
+    fn refill(&mut self)
+    {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+
+        self.tokens      = (self.tokens + elapsed * self.policy.rate).min(self.policy.burst);
+        self.last_refill = now;
+    }
+
+    pub fn try_take(&mut self, n: f64) -> bool
+    {
+        self.refill();
+
+        if self.tokens >= n
+        {
+            self.tokens -= n;
+            return true;
+        }
+
+        false
+    }
+
+    // Take tokens for work that has already been done, the bucket may not go negative
+    pub fn consume(&mut self, n: f64)
+    {
+        self.refill();
+
+        self.tokens = (self.tokens - n).max(0.0);
+    }
+
+    pub fn available(&mut self) -> f64
+    {
+        self.refill();
+
+        self.tokens
+    }
+
+    pub fn get_policy(&self) -> &RateLimitPolicy
+    {
+        &self.policy
+    }
+
+    // Keep the tokens already in the bucket when a policy changes
+    pub fn set_policy(&mut self, policy: RateLimitPolicy)
+    {
+        self.refill();
+        self.tokens = self.tokens.min(policy.burst);
+        self.policy = policy;
+    }
+}
+
+// Policies are looked up from the most specific to the least specific:
+// identity -> role -> server group -> default
+#[derive(Default)]
+pub struct RateLimitPolicies
+{
+    pub default     : RateLimitPolicy,
+    pub by_identity : HashMap<String, RateLimitPolicy>,
+    pub by_role     : HashMap<String, RateLimitPolicy>,
+    pub by_group    : HashMap<u32, RateLimitPolicy>,
+}
+
+impl RateLimitPolicies
+{
+    pub fn resolve(&self, identity: &str, role: Option<&str>, server_group: u32) -> RateLimitPolicy
+    {
+        if let Some(policy) = self.by_identity.get(identity)
+        {
+            return policy.clone();
+        }
+
+        if let Some(policy) = role.and_then(|r| self.by_role.get(r))
+        {
+            return policy.clone();
+        }
+
+        if let Some(policy) = self.by_group.get(&server_group)
+        {
+            return policy.clone();
+        }
+
+        self.default.clone()
+    }
+}
+
+// What to do with a connection that would go over a cap
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverloadPolicy
+{
+    // Turn the connection away straight away
+    REJECT,
+    // Hold the connection until a slot frees up
+    QUEUE,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConcurrencyCap
+{
+    pub max     : usize,
+    pub policy  : OverloadPolicy,
+}
+
+impl ConcurrencyCap
+{
+    pub fn new(max: usize, policy: OverloadPolicy) -> Self
+    {
+        Self { max, policy }
+    }
+
+    pub fn is_reached(&self, current: usize) -> bool
+    {
+        current >= self.max
+    }
+}
+
+// Caps on the number of simultaneous connections.
+// Pending connections are the ones still in the TLS handshake,
+// total includes pending, queued and established connections.
+//
+// Queueing a pending or total connection leaves it in the listen backlog,
+// queueing a per identity connection holds the authenticated connection
+// for at most queue_timeout.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimits
+{
+    pub per_identity    : Option<ConcurrencyCap>,
+    pub pending         : Option<ConcurrencyCap>,
+    pub total           : Option<ConcurrencyCap>,
+    pub queue_timeout   : Duration,
+}
+
+impl Default for ConcurrencyLimits
+{
+    fn default() -> Self
+    {
+        Self { per_identity: None, pending: None, total: None, queue_timeout: Duration::from_secs(5) }
+    }
+}
+
+#[test]
+fn test_token_bucket_burst_and_refill()
+{
+    let mut bucket = TokenBucket::new(RateLimitPolicy::new(100.0, 5.0));
+
+    let mut cnt = 0;
+    for i in 0..10
+    {
+        if bucket.try_take(1.0)
+        {
+            cnt += 1;
+        }
+    }
+
+    assert!(cnt == 5);
+
+    // 100 tokens a second -> at least one token after 20ms
+    std::thread::sleep(Duration::from_millis(20));
+
+    assert!(bucket.try_take(1.0));
+
+    // Never refills past the burst size
+    std::thread::sleep(Duration::from_millis(100));
+
+    assert!(bucket.available() <= 5.0);
+}
+
+#[test]
+fn test_rate_limit_policy_resolution()
+{
+    let mut policies = RateLimitPolicies::default();
+
+    policies.by_identity.insert("first@first.com".into(), RateLimitPolicy::new(1.0, 1.0));
+    policies.by_role.insert("batch".into(), RateLimitPolicy::new(2.0, 2.0));
+    policies.by_group.insert(1, RateLimitPolicy::new(3.0, 3.0));
+
+    assert!(policies.resolve("first@first.com", Some("batch"), 1) == RateLimitPolicy::new(1.0, 1.0));
+    assert!(policies.resolve("second@second.com", Some("batch"), 1) == RateLimitPolicy::new(2.0, 2.0));
+    assert!(policies.resolve("second@second.com", None, 1) == RateLimitPolicy::new(3.0, 3.0));
+    assert!(policies.resolve("second@second.com", None, 0) == RateLimitPolicy::default());
+}
@@ -5,571 +5,1931 @@ use std::collections::*;
 
 use std::io::{Write, Read};
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use std::sync::Arc;
-use rustls;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 
 use x509_parser::prelude::*;
 
 use log::{info, warn, error};
 
-// TODO: Server health should be put in a thread
-// TODO: Server health should complete a connection within some period
+use crate::rate_limit::{RateLimitPolicy, TokenBucket};
+use crate::balancer::{Balancer, Candidate, LatencyEwma, LeastConnections, PickContext, Strategy, XorShiftRng};
+use crate::probe::{Probe, ProbeKind, ProbeStatus};
+use crate::breaker::{BreakerConfig, CircuitBreaker};
+use crate::discovery::DiscoveredServer;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerOptions
+{
+    pub weight          : u32,
+    pub max_connections : Option<usize>, // server is skipped once at capacity
+    pub tier            : Tier,
+}
+
+impl Default for ServerOptions
+{
+    fn default() -> Self
+    {
+        Self { weight: 1, max_connections: None, tier: Tier::PRIMARY }
+    }
+}
+
+// Backup servers only take traffic while the group is short of healthy primaries
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tier
+{
+    PRIMARY,
+    BACKUP,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Threshold
+{
+    COUNT(usize),
+    PERCENT(u32),
+}
+
+impl Threshold
+{
+    // Whether healthy out of total is short of the threshold
+    pub fn is_below(&self, healthy: usize, total: usize) -> bool
+    {
+        match self
+        {
+            Threshold::COUNT(count) => healthy < *count,
+            Threshold::PERCENT(pct) => total == 0 || healthy * 100 < *pct as usize * total,
+        }
+    }
+}
+
+// Backups join the primaries once the healthy primaries drop below
+// activate_below. They stay until the primaries have been back above
+// it for failback_after, so a flapping primary does not flip the tiers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupPolicy
+{
+    pub activate_below  : Threshold,
+    pub failback_after  : Duration,
+}
+
+impl Default for BackupPolicy
+{
+    // Backups only once no primary is left
+    fn default() -> Self
+    {
+        Self { activate_below: Threshold::COUNT(1), failback_after: Duration::from_secs(30) }
+    }
+}
+
+// Eject a server after max_failures consecutive failures of live
+// connections within window. The active health check reinstates it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PassiveHealthConfig
+{
+    pub max_failures    : u32,
+    pub window          : Duration,
+}
+
+// Try up to max_retries other healthy servers of the group when the
+// picked one cannot be reached, as long as the budget has not run out.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy
+{
+    pub max_retries : u32,
+    pub budget      : Duration, // total time for all attempts
+}
+
+impl Default for RetryPolicy
+{
+    // No retries, a single attempt
+    fn default() -> Self
+    {
+        Self { max_retries: 0, budget: Duration::from_millis(100) }
+    }
+}
+
+// A server or a whole group out of rotation for maintenance. Open connections
+// carry on until they finish or the deadline passes, then they are closed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Drain
+{
+    pub started     : Instant,
+    pub deadline    : Option<Duration>, // None waits for the connections however long they take
+}
+
+impl Drain
+{
+    pub fn new(deadline: Option<Duration>) -> Self
+    {
+        Self { started: Instant::now(), deadline }
+    }
+
+    pub fn is_expired(&self) -> bool
+    {
+        self.deadline.map(|v| self.started.elapsed() >= v).unwrap_or(false)
+    }
+}
+
+// What happens to a connection when the group has no healthy server
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NoHealthyPolicy
+{
+    // Reject with a TLS alert straight away
+    FAIL_FAST,
+    // Hold the connection for up to this long until a server recovers
+    QUEUE(Duration),
+    // Send the connection to the group with this id
+    FALLBACK(u32),
+    // Ignore health once fewer than this percentage of servers are healthy
+    PANIC(u32),
+}
+
+// Lowest share of its weight a slow starting server is given
+const SLOW_START_MIN_RAMP : f64 = 0.1;
+
+// Time constant of the per server round trip averages
+const LATENCY_DECAY : Duration = Duration::from_secs(10);
+
+// How often a health worker polls its probe
+const HEALTH_POLL_INTERVAL : Duration = Duration::from_millis(10);
+
 // TODO: Connections need to be accounted for.
 pub struct ServerGroup
 {
     id              : u32,
     server_addrs    : HashMap<u32, String>,
+    server_opts     : HashMap<u32, ServerOptions>,
     cxn_cntr        : HashMap<u32, usize>,
-    server_health   : HashMap<u32, HealthChecker>,
+    server_bytes    : HashMap<u32, u64>, // bytes relayed by the server's current connections
+    server_health   : HashMap<u32, ServerHealth>,
+    health_check    : HealthCheckConfig,
+    health_events   : (Sender<HealthEvent>, Receiver<HealthEvent>),
+    healthy_since   : HashMap<u32, Instant>,
+    slow_start      : Option<Duration>, // time taken for a recovered server to ramp up to its full weight
+    passive_health  : Option<PassiveHealthConfig>,
+    retry           : RetryPolicy,
+    breaker         : Option<BreakerConfig>,
+    breakers        : HashMap<u32, CircuitBreaker>,
+    latency         : HashMap<u32, LatencyEwma>, // connect time of upstream connections, used for picking
+    probe_rtt       : HashMap<u32, LatencyEwma>, // round trip of passed health checks
+    backup          : BackupPolicy,
+    backups_active  : bool,
+    no_healthy      : NoHealthyPolicy,
+    draining        : HashSet<u32>, // removed servers kept until their connections finish
+    maintenance     : HashMap<u32, Drain>, // servers drained by an operator
+    group_drain     : Option<Drain>,
+    in_panic        : bool,
+    primaries_back  : Option<Instant>, // since when the primaries are above the threshold again
+    server_failures : HashMap<u32, (u32, Instant)>, // consecutive failures, time of the first one
+    balancer        : Box<dyn Balancer>,
+    timeouts        : Option<crate::client::Timeouts>, // overrides the listener timeouts
+    bandwidth       : Option<TokenBucket>, // bytes per second shared by all clients of the group
+    proxy_protocol  : bool, // send a PROXY protocol v2 header to upstreams
 }
 
 impl ServerGroup
 {
     pub fn new(id : u32) -> Self
     {
-        Self { id, server_addrs: HashMap::new(), cxn_cntr: HashMap::new(), server_health: HashMap::new() }
+        Self { id, server_addrs: HashMap::new(), server_opts: HashMap::new(), cxn_cntr: HashMap::new(), server_bytes: HashMap::new(), server_health: HashMap::new(),
+               health_check: HealthCheckConfig::default(), health_events: channel(), healthy_since: HashMap::new(), slow_start: None,
+               passive_health: None, retry: RetryPolicy::default(), breaker: None, breakers: HashMap::new(), latency: HashMap::new(), probe_rtt: HashMap::new(),
+               backup: BackupPolicy::default(), backups_active: false, primaries_back: None,
+               no_healthy: NoHealthyPolicy::FAIL_FAST, in_panic: false, draining: HashSet::new(), maintenance: HashMap::new(), group_drain: None, server_failures: HashMap::new(), balancer: Strategy::LEAST_CONNECTIONS.create(), timeouts: None, bandwidth: None,
+               proxy_protocol: false }
     }
 
-    pub fn add_connection(&mut self, id: &u32)
+    pub fn get_id(&self) -> u32
     {
-        if let Some(cxn_cnt) = self.cxn_cntr.get_mut(id)
+        self.id
+    }
+
+    pub fn set_strategy(&mut self, strategy: Strategy)
+    {
+        self.balancer = strategy.create();
+        self.update_balancer();
+
+        info!("Server group {} using {} balancing", self.id, self.balancer.name());
+    }
+
+    // Tell the balancer the servers it picks from and their weights
+    fn update_balancer(&mut self)
+    {
+        let mut servers : Vec<(u32, u32)> = self.server_addrs.keys().map(|id| (*id, self.server_opts.get(id).map(|v| v.weight).unwrap_or(1))).collect();
+        servers.sort();
+
+        self.balancer.set_servers(&servers);
+    }
+
+    // One line per server for the admin status
+    pub fn status_lines(&self) -> Vec<String>
+    {
+        let mut ids : Vec<u32> = self.server_addrs.keys().copied().collect();
+        ids.sort();
+
+        ids.iter().map(|id|
         {
-            *cxn_cnt += 1;
-        }
-        else
+            let state = self.server_health.get(id).map(|v| format!("{:?}", v.get_state())).unwrap_or_else(|| "-".to_string());
+
+            let breaker = self.breakers.get(id).map(|v| v.get_state_name()).unwrap_or("-");
+            let state = if self.draining.contains(id) { format!("{state} (draining)") } else { state };
+            let state = if self.maintenance.contains_key(id) { format!("{state} (maintenance)") } else { state };
+            let latency = self.latency.get(id).and_then(|v| v.get()).map(|v| format!("{v:.1}ms")).unwrap_or_else(|| "-".to_string());
+            let probe_rtt = self.probe_rtt.get(id).and_then(|v| v.get()).map(|v| format!("{v:.1}ms")).unwrap_or_else(|| "-".to_string());
+
+            format!("server {id} addr: {} tier: {:?} state: {state} breaker: {breaker} latency: {latency} probe_rtt: {probe_rtt} connections: {} bytes: {}",
+                    self.server_addrs.get(id).map(|v| v.as_str()).unwrap_or(""), self.get_tier(id), self.cxn_cntr.get(id).unwrap_or(&0), self.server_bytes.get(id).unwrap_or(&0))
+        }).collect()
+    }
+
+    pub fn set_health_check(&mut self, mut config: HealthCheckConfig)
+    {
+        config.proxy_protocol = self.proxy_protocol;
+
+        for (_k, v) in self.server_health.iter_mut()
         {
-            self.cxn_cntr.insert(*id, 1);
+            v.send(HealthCommand::CONFIG(config.clone()));
         }
+
+        self.health_check = config;
     }
 
-    pub fn remove_connection(&mut self, id: &u32)
+    pub fn set_no_healthy_policy(&mut self, policy: NoHealthyPolicy)
     {
-        if let Some(cxn_cnt) = self.cxn_cntr.get_mut(id)
+        self.no_healthy = policy;
+    }
+
+    pub fn get_no_healthy_policy(&self) -> &NoHealthyPolicy
+    {
+        &self.no_healthy
+    }
+
+    // Panic mode kicks in when too few servers are healthy for health to be trusted
+    fn update_panic(&mut self)
+    {
+        let threshold = match self.no_healthy
         {
-            if cxn_cnt > &mut 0
+            NoHealthyPolicy::PANIC(pct) => pct,
+            _ =>
             {
-                *cxn_cnt -= 1;
+                self.in_panic = false;
+                return;
+            }
+        };
+
+        let healthy = self.server_health.values().filter(|v| v.is_healthy()).count();
+        let in_panic = !self.server_addrs.is_empty() && Threshold::PERCENT(threshold).is_below(healthy, self.server_addrs.len());
+
+        if in_panic != self.in_panic
+        {
+            if in_panic
+            {
+                error!("Server group {} has {} of {} servers healthy, panic mode: ignoring health", self.id, healthy, self.server_addrs.len());
             }
             else
             {
-                // should not happen
-                // probably would log if not a coding challenge
+                info!("Server group {} left panic mode", self.id);
             }
         }
-        else
-        {
-            // should not happen
-            // probably would log if not a coding challenge
-        }
+
+        self.in_panic = in_panic;
     }
 
-    pub fn add_server(&mut self, serv_id: u32, addr: String)
+    pub fn set_backup_policy(&mut self, policy: BackupPolicy)
     {
-        self.server_addrs.insert(serv_id, addr.clone());
-        self.server_health.insert(serv_id, HealthChecker::new(serv_id, addr));
+        self.backup = policy;
     }
 
-    pub fn get_server_address(&self, serv_id: &u32) -> Option<&String>
+    fn get_tier(&self, serv_id: &u32) -> Tier
     {
-        self.server_addrs.get(serv_id)
+        self.server_opts.get(serv_id).map(|v| v.tier).unwrap_or(Tier::PRIMARY)
     }
 
-    pub fn find_min(&self) -> Option<u32>
+    // Switch the backup tier on or off from the health of the primaries
+    fn update_tiers(&mut self)
     {
-        // Not all servers have a connection yet
-        if self.cxn_cntr.len() != self.server_addrs.len()
+        let primaries : Vec<u32> = self.server_addrs.keys().filter(|id| self.get_tier(id) == Tier::PRIMARY).copied().collect();
+
+        // no backups, nothing to switch
+        if primaries.len() == self.server_addrs.len()
+        {
+            self.backups_active = false;
+            return;
+        }
+
+        // primaries in maintenance are covered by the backups too
+        let healthy = primaries.iter().filter(|id| !self.maintenance.contains_key(id) && self.server_health.get(id).map(|v| v.is_healthy()).unwrap_or(false)).count();
+
+        if self.backup.activate_below.is_below(healthy, primaries.len())
         {
-            let server_id_set : HashSet<&u32> = self.server_addrs.keys().collect();
-            let cxn_id_set    : HashSet<&u32> = self.cxn_cntr.keys().collect();
+            self.primaries_back = None;
 
-            // return the first server id that is not in the cxn_id_set but is in the server_id_set
-            for id in server_id_set.difference(&cxn_id_set)
+            if !self.backups_active
             {
-                return Some(**id);
+                self.backups_active = true;
+                warn!("Server group {} has {} of {} primaries healthy, backups activated", self.id, healthy, primaries.len());
             }
         }
-
-        // All servers are in the list.
-        // Find the least connected server.
-        let mut min_conns = usize::MAX;
-        let mut min_id : Option<u32> = None;
-        for (id, num_conns) in self.cxn_cntr.iter()
+        else if self.backups_active
         {
-            if min_conns > *num_conns
+            let since = *self.primaries_back.get_or_insert_with(Instant::now);
+
+            if since.elapsed() >= self.backup.failback_after
             {
-                min_conns   = *num_conns;
-                min_id      = Some(*id);
+                self.backups_active = false;
+                self.primaries_back = None;
+                info!("Server group {} primaries recovered, failing back from backups", self.id);
             }
         }
+    }
 
-        min_id
+    pub fn set_slow_start(&mut self, window: Duration)
+    {
+        self.slow_start = Some(window);
     }
 
-    pub fn find_min_and_healthy(&self) -> Option<u32>
+    // Share of its weight a server gets while it ramps up after becoming healthy
+    fn get_ramp(&self, serv_id: &u32) -> f64
+    {
+        match (self.slow_start, self.healthy_since.get(serv_id))
+        {
+            (Some(window), Some(since)) if since.elapsed() < window =>
+            {
+                (since.elapsed().as_secs_f64() / window.as_secs_f64()).max(SLOW_START_MIN_RAMP)
+            },
+            _ => 1.0,
+        }
+    }
+
+    pub fn set_passive_health(&mut self, config: PassiveHealthConfig)
+    {
+        self.passive_health = Some(config);
+    }
+
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy)
+    {
+        self.retry = retry;
+    }
+
+    pub fn get_retry_policy(&self) -> &RetryPolicy
+    {
+        &self.retry
+    }
+
+    // Give every server of the group its own circuit breaker
+    pub fn set_circuit_breaker(&mut self, config: BreakerConfig)
+    {
+        self.breakers = self.server_addrs.keys().map(|id| (*id, CircuitBreaker::new(config.clone()))).collect();
+        self.breaker  = Some(config);
+    }
+
+    fn update_breaker<F: FnOnce(&mut CircuitBreaker)>(&mut self, serv_id: &u32, update: F)
     {
-        // Not all servers have a connection yet
-        if self.cxn_cntr.len() != self.server_addrs.len()
+        if let Some(breaker) = self.breakers.get_mut(serv_id)
         {
-            let server_id_set : HashSet<&u32> = self.server_addrs.keys().collect();
-            let cxn_id_set    : HashSet<&u32> = self.cxn_cntr.keys().collect();
+            let before = breaker.get_state_name();
+
+            update(breaker);
 
-            // return the first server id that is not in the cxn_id_set but is in the server_id_set
-            for id in server_id_set.difference(&cxn_id_set)
+            let after = breaker.get_state_name();
+
+            if before != after
             {
-                if let Some(health_check) = self.server_health.get(*id)
-                {
-                    if health_check.is_healthy()
-                    {
-                        return Some(**id);
-                    }
-                }
+                warn!("Server group {} server {} circuit breaker {before} -> {after}", self.id, serv_id);
             }
         }
+    }
+
+    // A live connection to the server failed.
+    // Returns true if the server has been ejected.
+    pub fn report_failure(&mut self, serv_id: &u32) -> bool
+    {
+        self.update_breaker(serv_id, |v| v.record_failure());
+
+        let config = match &self.passive_health
+        {
+            Some(config) => config.clone(),
+            None => return false,
+        };
+
+        let now = Instant::now();
+
+        let failures = self.server_failures.entry(*serv_id).or_insert((0, now));
+
+        // start counting again once out of the window
+        if now.duration_since(failures.1) > config.window
+        {
+            *failures = (0, now);
+        }
+
+        failures.0 += 1;
+
+        warn!("Server group {} server {} failure {} of {}", self.id, serv_id, failures.0, config.max_failures);
+
+        if failures.0 < config.max_failures
+        {
+            return false;
+        }
 
-        // All servers are in the list.
-        // Find the least connected server that is also healthy
-        let mut min_conns = usize::MAX;
-        let mut min_id : Option<u32> = None;
-        for (id, num_conns) in self.cxn_cntr.iter()
+        self.server_failures.remove(serv_id);
+
+        if let Some(health) = self.server_health.get_mut(serv_id)
         {
-            if let Some(health) = self.server_health.get(id)
+            if health.is_healthy()
             {
-                if min_conns > *num_conns && health.is_healthy()
-                {
-                    min_conns   = *num_conns;
-                    min_id      = Some(*id);
-                }
+                health.eject();
+                error!("Server group {} server {} ejected after {} failures within {:?}", self.id, serv_id, config.max_failures, config.window);
+                return true;
             }
         }
 
-        min_id
+        false
     }
 
-    pub fn poll(&mut self) -> Result<(), Box<dyn std::error::Error>>
+    // A connection to the server succeeded, failures are no longer consecutive
+    pub fn report_success(&mut self, serv_id: &u32, latency: Duration)
     {
-        for (_k, v) in self.server_health.iter_mut()
-        {
-            v.poll()?;
-        }
+        self.update_breaker(serv_id, |v| v.record_success(Some(latency)));
+        self.observe_latency(serv_id, latency);
 
-        Ok(())
+        self.server_failures.remove(serv_id);
     }
-}
 
-#[test]
-fn test_server_group_find_min()
-{
-    let mut sg = ServerGroup::new(0);
+    // Connect time of an upstream connection
+    pub fn observe_latency(&mut self, serv_id: &u32, rtt: Duration)
+    {
+        self.latency.entry(*serv_id).or_insert_with(|| LatencyEwma::new(LATENCY_DECAY)).observe(rtt);
+    }
 
-    for i in 0..5
+    pub fn set_bandwidth_limit(&mut self, policy: RateLimitPolicy)
     {
-        sg.server_addrs.insert(i, "".into());
+        self.bandwidth = Some(TokenBucket::new(policy));
     }
 
-    let mut cnt : usize = 0;
-    for i in 0..10
+    pub fn get_bandwidth_mut(&mut self) -> Option<&mut TokenBucket>
     {
-        if let Some(id) = sg.find_min()
+        self.bandwidth.as_mut()
+    }
+
+    pub fn set_timeouts(&mut self, timeouts: crate::client::Timeouts)
+    {
+        self.timeouts = Some(timeouts);
+    }
+
+    pub fn get_timeouts(&self) -> Option<&crate::client::Timeouts>
+    {
+        self.timeouts.as_ref()
+    }
+
+    // Health checks follow, they get a LOCAL header
+    pub fn set_proxy_protocol(&mut self, enabled: bool)
+    {
+        self.proxy_protocol = enabled;
+
+        self.set_health_check(self.health_check.clone());
+    }
+
+    pub fn get_proxy_protocol(&self) -> bool
+    {
+        self.proxy_protocol
+    }
+
+    pub fn add_connection(&mut self, id: &u32)
+    {
+        if let Some(cxn_cnt) = self.cxn_cntr.get_mut(id)
         {
-            cnt += 1;
+            *cxn_cnt += 1;
+        }
+        else
+        {
+            self.cxn_cntr.insert(*id, 1);
+        }
+    }
 
-            sg.add_connection(&id);
+    pub fn remove_connection(&mut self, id: &u32)
+    {
+        if let Some(cxn_cnt) = self.cxn_cntr.get_mut(id)
+        {
+            if cxn_cnt > &mut 0
+            {
+                *cxn_cnt -= 1;
+            }
+            else
+            {
+                // should not happen
+                // probably would log if not a coding challenge
+            }
+        }
+        else
+        {
+            // should not happen
+            // probably would log if not a coding challenge
         }
+    }
 
+    pub fn add_bytes(&mut self, id: &u32, bytes: u64)
+    {
+        *self.server_bytes.entry(*id).or_insert(0) += bytes;
     }
 
-    assert!(cnt == 10);
+    pub fn remove_bytes(&mut self, id: &u32, bytes: u64)
+    {
+        if let Some(server_bytes) = self.server_bytes.get_mut(id)
+        {
+            *server_bytes = server_bytes.saturating_sub(bytes);
+        }
+    }
 
-    for i in 0..5
+    pub fn add_server(&mut self, serv_id: u32, addr: String)
     {
-        if let Some(cnt) = sg.cxn_cntr.get(&i)
+        self.add_server_with_options(serv_id, addr, ServerOptions::default());
+    }
+
+    pub fn add_server_with_options(&mut self, serv_id: u32, addr: String, opts: ServerOptions)
+    {
+        self.server_addrs.insert(serv_id, addr.clone());
+        self.server_opts.insert(serv_id, opts);
+        self.healthy_since.insert(serv_id, Instant::now());
+        self.server_health.insert(serv_id, ServerHealth::new());
+
+        if let Some(config) = &self.breaker
         {
-            assert!(cnt == &2);
+            self.breakers.insert(serv_id, CircuitBreaker::new(config.clone()));
         }
-        else
+
+        self.update_balancer();
+    }
+
+    // Take a server out of rotation, it is dropped once its connections are gone
+    pub fn remove_server(&mut self, serv_id: &u32)
+    {
+        if self.server_addrs.contains_key(serv_id) && self.draining.insert(*serv_id)
         {
-            // ID should be there
-            assert!(false);
+            info!("Server group {} server {} removed, draining {} connections", self.id, serv_id, self.cxn_cntr.get(serv_id).unwrap_or(&0));
         }
     }
-}
 
-#[test]
-fn test_server_group_add_and_remove_connections()
+    // Stop sending new connections to a server, e.g. before patching it.
+    // Draining it again keeps the original start and only moves the deadline.
+    pub fn drain_server(&mut self, serv_id: &u32, deadline: Option<Duration>) -> bool
+    {
+        if !self.server_addrs.contains_key(serv_id)
+        {
+            return false;
+        }
+
+        let drain = self.maintenance.entry(*serv_id).or_insert_with(|| Drain::new(deadline));
+        drain.deadline = deadline;
+
+        info!("Server group {} server {} in maintenance, draining {} connections (deadline: {:?})", self.id, serv_id, self.cxn_cntr.get(serv_id).unwrap_or(&0), deadline);
+
+        true
+    }
+
+    pub fn resume_server(&mut self, serv_id: &u32) -> bool
+    {
+        let resumed = self.maintenance.remove(serv_id).is_some();
+
+        if resumed
+        {
+            info!("Server group {} server {} back from maintenance", self.id, serv_id);
+        }
+
+        resumed
+    }
+
+    // The whole group gets no new connections, see NoHealthyPolicy for where they go instead
+    pub fn drain_group(&mut self, deadline: Option<Duration>)
+    {
+        let drain = self.group_drain.get_or_insert_with(|| Drain::new(deadline));
+        drain.deadline = deadline;
+
+        info!("Server group {} in maintenance, draining {} connections (deadline: {:?})", self.id, self.cxn_cntr.values().sum::<usize>(), deadline);
+    }
+
+    pub fn resume_group(&mut self) -> bool
+    {
+        let resumed = self.group_drain.take().is_some();
+
+        if resumed
+        {
+            info!("Server group {} back from maintenance", self.id);
+        }
+
+        resumed
+    }
+
+    pub fn get_group_drain(&self) -> Option<&Drain>
+    {
+        self.group_drain.as_ref()
+    }
+
+    pub fn get_server_drain(&self, serv_id: &u32) -> Option<&Drain>
+    {
+        self.maintenance.get(serv_id)
+    }
+
+    // Servers in maintenance past their deadline that still have connections to close
+    pub fn get_expired_drains(&self) -> Vec<u32>
+    {
+        let group_expired = self.group_drain.map(|v| v.is_expired()).unwrap_or(false);
+
+        let mut ids : Vec<u32> = self.cxn_cntr.iter()
+            .filter(|(id, cnt)| **cnt > 0 && (group_expired || self.maintenance.get(id).map(|v| v.is_expired()).unwrap_or(false)))
+            .map(|(id, _)| *id)
+            .collect();
+
+        ids.sort();
+        ids
+    }
+
+    fn drop_server(&mut self, serv_id: &u32)
+    {
+        self.server_addrs.remove(serv_id);
+        self.server_opts.remove(serv_id);
+        self.cxn_cntr.remove(serv_id);
+        self.server_bytes.remove(serv_id);
+        self.server_health.remove(serv_id); // stops its health worker
+        self.healthy_since.remove(serv_id);
+        self.server_failures.remove(serv_id);
+        self.breakers.remove(serv_id);
+        self.latency.remove(serv_id);
+        self.probe_rtt.remove(serv_id);
+        self.draining.remove(serv_id);
+        self.maintenance.remove(serv_id);
+        self.update_balancer();
+
+        info!("Server group {} server {} drained and dropped", self.id, serv_id);
+    }
+
+    // Bring the servers in line with a discovered list: new addresses are added
+    // under new ids, known ones get their options updated and the rest drain.
+    // Returns the number of servers added, updated and removed.
+    pub fn reconcile(&mut self, servers: &[DiscoveredServer]) -> (usize, usize, usize)
+    {
+        let (mut added, mut updated, mut removed) = (0, 0, 0);
+
+        let known : HashMap<String, u32> = self.server_addrs.iter().map(|(id, addr)| (addr.clone(), *id)).collect();
+
+        for server in servers.iter()
+        {
+            match known.get(&server.addr)
+            {
+                Some(id) =>
+                {
+                    if self.draining.remove(id)
+                    {
+                        info!("Server group {} server {} {} is back, no longer draining", self.id, id, server.addr);
+                    }
+
+                    if self.server_opts.get(id) != Some(&server.opts)
+                    {
+                        info!("Server group {} server {} {} updated to {:?}", self.id, id, server.addr, server.opts);
+                        self.server_opts.insert(*id, server.opts.clone());
+                        self.update_balancer();
+                        updated += 1;
+                    }
+                },
+                None =>
+                {
+                    let id = self.server_addrs.keys().max().map(|v| v + 1).unwrap_or(0);
+
+                    info!("Server group {} server {} {} discovered", self.id, id, server.addr);
+                    self.add_server_with_options(id, server.addr.clone(), server.opts.clone());
+                    added += 1;
+                }
+            }
+        }
+
+        for (addr, id) in known.iter()
+        {
+            if !servers.iter().any(|v| &v.addr == addr) && !self.draining.contains(id)
+            {
+                self.remove_server(id);
+                removed += 1;
+            }
+        }
+
+        (added, updated, removed)
+    }
+
+    pub fn get_server_address(&self, serv_id: &u32) -> Option<&String>
+    {
+        self.server_addrs.get(serv_id)
+    }
+
+    // Candidates are sorted by id so balancers break ties the same way every time
+    // In panic mode only health is ignored, open breakers and idle backups are still left out
+    fn get_candidates(&self, healthy_only: bool, in_panic: bool) -> Vec<Candidate>
+    {
+        let mut ids : Vec<u32> = self.server_addrs.keys().copied().collect();
+        ids.sort();
+
+        let default_opts = ServerOptions::default();
+
+        if self.group_drain.is_some()
+        {
+            return vec![];
+        }
+
+        ids.iter()
+           .filter(|id| !self.draining.contains(id) && !self.maintenance.contains_key(id))
+           .filter(|id| !healthy_only || in_panic || self.server_health.get(id).map(|v| v.is_healthy()).unwrap_or(false))
+           .filter(|id| !healthy_only || self.breakers.get(id).map(|v| v.is_available()).unwrap_or(true))
+           .filter(|id| !healthy_only || self.backups_active || self.get_tier(id) == Tier::PRIMARY)
+           .filter_map(|id|
+           {
+               let opts = self.server_opts.get(id).unwrap_or(&default_opts);
+               let connections = *self.cxn_cntr.get(id).unwrap_or(&0);
+
+               // at capacity
+               if opts.max_connections.map(|v| connections >= v).unwrap_or(false)
+               {
+                   return None;
+               }
+
+               let mut candidate = Candidate::new(*id, connections);
+               candidate.weight = opts.weight;
+               candidate.ramp   = self.get_ramp(id);
+               candidate.bytes  = *self.server_bytes.get(id).unwrap_or(&0);
+               candidate.latency      = self.latency.get(id).and_then(|v| v.get());
+               candidate.peak_latency = self.latency.get(id).and_then(|v| v.get_peak());
+               Some(candidate)
+           })
+           .collect()
+    }
+
+    // Least connected server regardless of health
+    pub fn find_min(&self) -> Option<u32>
+    {
+        LeastConnections {}.pick(&self.get_candidates(false, false), &PickContext::default())
+    }
+
+    // Whether a pick could find a server right now, without counting a pick
+    pub fn has_available_server(&self) -> bool
+    {
+        !self.get_candidates(true, self.in_panic).is_empty()
+    }
+
+    pub fn find_min_and_healthy(&self) -> Option<u32>
+    {
+        LeastConnections {}.pick(&self.get_candidates(true, false), &PickContext::default())
+    }
+
+    // Healthy server picked by the group's balancing strategy
+    pub fn select_server(&mut self, ctx: &PickContext) -> Option<u32>
+    {
+        self.select_server_excluding(ctx, &[])
+    }
+
+    // Same as select_server but skips servers that have already been tried
+    pub fn select_server_excluding(&mut self, ctx: &PickContext, excluded: &[u32]) -> Option<u32>
+    {
+        self.update_tiers();
+        self.update_panic();
+
+        let mut candidates = self.get_candidates(true, self.in_panic);
+        candidates.retain(|c| !excluded.contains(&c.id));
+
+        loop
+        {
+            let serv_id = self.balancer.pick(&candidates, ctx)?;
+
+            // counts the trial connections of a half open breaker
+            let mut allowed = true;
+            self.update_breaker(&serv_id, |v| allowed = v.allow());
+
+            if allowed
+            {
+                return Some(serv_id);
+            }
+
+            // out of trials, the others may still take it
+            candidates.retain(|c| c.id != serv_id);
+        }
+    }
+
+    // Start a health worker for every server that does not have one yet
+    fn start_health_workers(&mut self)
+    {
+        for (k, v) in self.server_health.iter_mut()
+        {
+            if v.worker.is_some()
+            {
+                continue;
+            }
+
+            let addr = self.server_addrs.get(k).cloned().unwrap_or_default();
+            let checker = HealthChecker::with_config(*k, addr, self.health_check.clone());
+
+            match HealthWorker::spawn(checker, v.epoch, self.health_events.0.clone())
+            {
+                Ok(worker) => { v.worker = Some(worker); },
+                Err(e) => { error!("Server group {} server {} health worker failed to start: {e}", self.id, k); }
+            }
+        }
+    }
+
+    // Apply the state changes published by the health workers
+    pub fn poll(&mut self) -> Result<(), Box<dyn std::error::Error>>
+    {
+        let drained : Vec<u32> = self.draining.iter().filter(|id| self.cxn_cntr.get(id).map(|v| *v == 0).unwrap_or(true)).copied().collect();
+
+        for id in drained.iter()
+        {
+            self.drop_server(id);
+        }
+
+        self.start_health_workers();
+
+        self.apply_health_events();
+
+        // failback runs on time, not only when a pick comes along
+        self.update_tiers();
+        self.update_panic();
+
+        Ok(())
+    }
+
+    fn apply_health_events(&mut self)
+    {
+        while let Ok(event) = self.health_events.1.try_recv()
+        {
+            if let Some(rtt) = event.rtt
+            {
+                self.probe_rtt.entry(event.server_id).or_insert_with(|| LatencyEwma::new(LATENCY_DECAY)).observe(rtt);
+            }
+
+            let health = match self.server_health.get_mut(&event.server_id)
+            {
+                Some(health) => health,
+                None => continue,
+            };
+
+            // sent before the worker saw the last eject
+            if event.epoch < health.epoch
+            {
+                continue;
+            }
+
+            let was_healthy = health.is_healthy();
+
+            health.state = event.state;
+
+            if !was_healthy && health.is_healthy()
+            {
+                // restart the slow start ramp
+                self.healthy_since.insert(event.server_id, Instant::now());
+
+                if let Some(window) = self.slow_start
+                {
+                    info!("Server group {} server {} recovered, slow starting over {:?}", self.id, event.server_id, window);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+#[allow(clippy::assertions_on_constants)]
+fn test_server_group_find_min()
+{
+    let mut sg = ServerGroup::new(0);
+
+    for i in 0..5
+    {
+        sg.server_addrs.insert(i, "".into());
+    }
+
+    let mut cnt : usize = 0;
+    for i in 0..10
+    {
+        if let Some(id) = sg.find_min()
+        {
+            cnt += 1;
+
+            sg.add_connection(&id);
+        }
+
+    }
+
+    assert!(cnt == 10);
+
+    for i in 0..5
+    {
+        if let Some(cnt) = sg.cxn_cntr.get(&i)
+        {
+            assert!(cnt == &2);
+        }
+        else
+        {
+            // ID should be there
+            assert!(false);
+        }
+    }
+}
+
+#[test]
+#[allow(clippy::assertions_on_constants)]
+fn test_server_group_add_and_remove_connections()
+{
+    let mut sg = ServerGroup::new(0);
+
+    for i in 0..5
+    {
+        sg.server_addrs.insert(i, "".into());
+    }
+
+    let mut cnt : usize = 0;
+    for i in 0..10
+    {
+        if let Some(id) = sg.find_min()
+        {
+            cnt += 1;
+
+            sg.add_connection(&id);
+        }
+
+    }
+
+    for i in 0..5
+    {
+        sg.remove_connection(&i)
+    }
+
+    assert!(cnt == 10);
+
+    for i in 0..5
+    {
+        if let Some(cnt) = sg.cxn_cntr.get(&i)
+        {
+            assert!(cnt == &1);
+        }
+        else
+        {
+            // ID should be there
+            assert!(false);
+        }
+    }
+}
+
+#[test]
+#[allow(clippy::assertions_on_constants)]
+fn test_server_group_find_min_and_healthy()
+{
+
+    let mut sg = ServerGroup::new(0);
+
+    for i in 0..5
+    {
+        sg.server_addrs.insert(i, "".into());
+
+        let mut health = ServerHealth::new();
+
+        health.state = UpstreamState::HEALTHY;
+
+        sg.server_health.insert(i, health);
+    }
+
+    let mut cnt : usize = 0;
+    for i in 0..10
+    {
+        if let Some(id) = sg.find_min_and_healthy()
+        {
+            cnt += 1;
+
+            sg.add_connection(&id);
+        }
+
+    }
+
+    assert!(cnt == 10);
+
+    for i in 0..5
+    {
+        if let Some(cnt) = sg.cxn_cntr.get(&i)
+        {
+            assert!(cnt == &2);
+        }
+        else
+        {
+            // ID should be there
+            assert!(false);
+        }
+    }
+}
+
+#[test]
+#[allow(clippy::assertions_on_constants)]
+fn test_server_group_find_min_and_some_unhealthy()
+{
+
+    let mut sg = ServerGroup::new(0);
+
+    for i in 0..5
+    {
+        sg.server_addrs.insert(i, "".into());
+
+        let mut health = ServerHealth::new();
+
+        health.state = UpstreamState::UNHEALTHY;
+
+        sg.server_health.insert(i, health);
+    }
+
+    let mut cnt : usize = 0;
+    for i in 0..10
+    {
+        if let Some(id) = sg.find_min_and_healthy()
+        {
+            cnt += 1;
+
+            sg.add_connection(&id);
+        }
+
+    }
+
+    assert!(cnt == 0);
+
+    for i in 0..5
+    {
+        if let Some(cnt) = sg.cxn_cntr.get(&i)
+        {
+            assert!(false);
+        }
+        else
+        {
+            // ID should be there
+            assert!(true);
+        }
+    }
+}
+
+#[test]
+#[allow(clippy::assertions_on_constants)]
+fn test_server_group_find_min_and_all_unhealthy()
+{
+
+    let mut sg = ServerGroup::new(0);
+
+    for i in 0..5
+    {
+        sg.server_addrs.insert(i, "".into());
+
+        let mut health = ServerHealth::new();
+
+        health.state = UpstreamState::HEALTHY;
+
+        sg.server_health.insert(i, health);
+    }
+    
+    for i in 5..10
+    {
+        sg.server_addrs.insert(i, "".into());
+
+        let mut health = ServerHealth::new();
+
+        health.state = UpstreamState::UNHEALTHY;
+
+        sg.server_health.insert(i, health);
+    }
+
+    let mut cnt : usize = 0;
+    for i in 0..10
+    {
+        if let Some(id) = sg.find_min_and_healthy()
+        {
+            cnt += 1;
+
+            sg.add_connection(&id);
+        }
+
+    }
+
+    assert!(cnt == 10);
+
+    for i in 0..5
+    {
+        if let Some(cnt) = sg.cxn_cntr.get(&i)
+        {
+            assert!(cnt == &2);
+        }
+        else
+        {
+            // ID should be there
+            assert!(false);
+        }
+    }
+    
+    for i in 5..10
+    {
+        if let Some(cnt) = sg.cxn_cntr.get(&i)
+        {
+            assert!(false);
+        }
+        else
+        {
+            assert!(true);
+        }
+    }
+}
+
+#[test]
+fn test_server_group_weights_and_capacity()
+{
+    let mut sg = ServerGroup::new(0);
+
+    sg.add_server_with_options(0, "".into(), ServerOptions { weight: 2, max_connections: None, ..ServerOptions::default() });
+    sg.add_server_with_options(1, "".into(), ServerOptions { weight: 1, max_connections: Some(2), ..ServerOptions::default() });
+
+    for i in 0..2
+    {
+        sg.server_health.get_mut(&i).unwrap().state = UpstreamState::HEALTHY;
+    }
+
+    let ctx = PickContext::default();
+
+    for i in 0..12
+    {
+        if let Some(id) = sg.select_server(&ctx)
+        {
+            sg.add_connection(&id);
+        }
+    }
+
+    // server 1 stops at its cap, the rest goes to server 0
+    assert!(sg.cxn_cntr.get(&0) == Some(&10));
+    assert!(sg.cxn_cntr.get(&1) == Some(&2));
+}
+
+#[test]
+fn test_server_group_slow_start()
+{
+    let mut sg = ServerGroup::new(0);
+
+    sg.set_slow_start(Duration::from_millis(200));
+
+    sg.add_server(0, "".into());
+    sg.add_server(1, "".into());
+
+    for i in 0..2
+    {
+        sg.server_health.get_mut(&i).unwrap().state = UpstreamState::HEALTHY;
+    }
+
+    // server 0 has been healthy for a while, server 1 just came back
+    sg.healthy_since.insert(0, Instant::now() - Duration::from_secs(60));
+
+    sg.cxn_cntr.insert(0, 6);
+    sg.cxn_cntr.insert(1, 5);
+
+    let ctx = PickContext::default();
+
+    assert!(sg.select_server(&ctx) == Some(0));
+
+    std::thread::sleep(Duration::from_millis(250));
+
+    assert!(sg.select_server(&ctx) == Some(1));
+}
+
+#[test]
+fn test_server_group_passive_health()
+{
+    let mut sg = ServerGroup::new(0);
+
+    sg.set_passive_health(PassiveHealthConfig { max_failures: 3, window: Duration::from_secs(10) });
+
+    sg.add_server(0, "".into());
+    sg.add_server(1, "".into());
+
+    for i in 0..2
+    {
+        sg.server_health.get_mut(&i).unwrap().state = UpstreamState::HEALTHY;
+    }
+
+    // a success in between means the failures are not consecutive
+    assert!(!sg.report_failure(&0));
+    assert!(!sg.report_failure(&0));
+    sg.report_success(&0, Duration::from_millis(1));
+    assert!(!sg.report_failure(&0));
+    assert!(!sg.report_failure(&0));
+
+    assert!(sg.server_health.get(&0).unwrap().is_healthy());
+
+    assert!(sg.report_failure(&0));
+
+    assert!(!sg.server_health.get(&0).unwrap().is_healthy());
+
+    let ctx = PickContext::default();
+
+    for i in 0..5
+    {
+        assert!(sg.select_server(&ctx) == Some(1));
+        sg.add_connection(&1);
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum UpstreamState
+{
+    // Not checked yet, not routable
+    UNKNOWN,
+    HEALTHY,
+    UNHEALTHY,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, PartialEq, Eq)]
+enum PingState
+{
+    IDLE,
+    CHECKING(Instant),
+}
+
+// Active health check settings of a server group.
+// A server needs rise passes in a row to become healthy and fall failures
+// in a row to become unhealthy. While down the check interval doubles
+// after every failure up to max_backoff.
+#[derive(Clone, Debug)]
+pub struct HealthCheckConfig
+{
+    pub kind        : ProbeKind,
+    pub port        : Option<u16>, // check a separate health port instead of the traffic port
+    pub interval    : Duration,
+    pub timeout     : Duration,
+    pub jitter      : Duration, // up to this much is added to every interval
+    pub rise        : u32,
+    pub fall        : u32,
+    pub max_backoff : Duration,
+    pub proxy_protocol : bool, // follows the group, see ServerGroup::set_proxy_protocol
+}
+
+impl Default for HealthCheckConfig
+{
+    fn default() -> Self
+    {
+        Self
+        {
+            kind        : ProbeKind::default(),
+            port        : None,
+            interval    : Duration::from_secs(30),
+            timeout     : Duration::from_secs(1),
+            jitter      : Duration::from_secs(0),
+            rise        : 1,
+            fall        : 1,
+            max_backoff : Duration::from_secs(300),
+            proxy_protocol : false,
+        }
+    }
+}
+
+pub struct HealthChecker
+{
+    server_id       : u32,
+    address         : String,
+    probe           : Option<Box<dyn Probe>>,
+    ping_state      : PingState,
+    upstream_state  : UpstreamState,
+    config          : HealthCheckConfig,
+    next_check      : Instant,
+    backoff         : Duration,
+    passes          : u32, // consecutive
+    failures        : u32, // consecutive
+    rng             : XorShiftRng,
+    last_rtt        : Option<Duration>, // of the last passed check, until taken
+}
+
+impl HealthChecker
+{
+    pub fn new(server_id: u32, address: String) ->  Self
+    {
+        Self::with_config(server_id, address, HealthCheckConfig::default())
+    }
+
+    pub fn with_config(server_id: u32, address: String, config: HealthCheckConfig) -> Self
+    {
+        let backoff = config.interval;
+
+        Self { server_id, address, probe : None, ping_state : PingState::IDLE, upstream_state : UpstreamState::UNKNOWN, config,
+               next_check: Instant::now(), backoff, passes: 0, failures: 0, rng: XorShiftRng::new(None), last_rtt: None }
+    }
+
+    fn set_config(&mut self, config: HealthCheckConfig)
+    {
+        self.backoff = config.interval;
+        self.config  = config;
+    }
+
+    fn get_check_address(&self) -> Result<std::net::SocketAddr, Box<dyn std::error::Error>>
+    {
+        let mut addr : std::net::SocketAddr = self.address.parse()?;
+
+        if let Some(port) = self.config.port
+        {
+            addr.set_port(port);
+        }
+
+        Ok(addr)
+    }
+
+    fn poll(&mut self) -> Result<(), Box<dyn std::error::Error>>
+    {
+        let now = Instant::now();
+
+        let mut next_state = self.ping_state.clone();
+
+        match self.ping_state
+        {
+            PingState::IDLE =>
+            {
+                if now >= self.next_check
+                {
+                    match self.get_check_address()
+                    {
+                        Ok(sock_addr) =>
+                        {
+                            self.probe = Some(self.config.kind.create(sock_addr, self.config.timeout, self.config.proxy_protocol));
+
+                            // Connect straight away
+                            next_state = self.poll_probe(now);
+                        },
+                        Err(e) =>
+                        {
+                            error!("{} health check address invalid: {e}", self.server_id);
+                            next_state = self.record_result(false);
+                        }
+                    }
+                }
+
+                // keep state as idle
+            },
+            PingState::CHECKING(started) =>
+            {
+                if started.elapsed() > self.config.timeout
+                {
+                    // Out of time
+                    error!("{} health check not completed within {:?}", self.server_id, self.config.timeout);
+                    next_state = self.record_result(false);
+                }
+                else
+                {
+                    next_state = self.poll_probe(started);
+                }
+            }
+        }
+
+        self.ping_state = next_state;
+
+        Ok(())
+    }
+
+    fn poll_probe(&mut self, started: Instant) -> PingState
+    {
+        let status = match &mut self.probe
+        {
+            Some(probe) => probe.poll(),
+            None => ProbeStatus::FAILED("no probe".into()),
+        };
+
+        match status
+        {
+            ProbeStatus::PENDING => PingState::CHECKING(started),
+            ProbeStatus::PASSED =>
+            {
+                self.last_rtt = self.probe.as_ref().and_then(|v| v.get_rtt());
+                self.record_result(true)
+            },
+            ProbeStatus::FAILED(e) =>
+            {
+                error!("{} health check failed: {e}", self.server_id);
+                self.record_result(false)
+            }
+        }
+    }
+
+    // Apply the rise / fall thresholds and schedule the next check
+    fn record_result(&mut self, passed: bool) -> PingState
+    {
+        self.probe = None;
+
+        let delay;
+
+        if passed
+        {
+            self.passes  += 1;
+            self.failures = 0;
+            self.backoff  = self.config.interval;
+
+            if self.upstream_state != UpstreamState::HEALTHY && self.passes >= self.config.rise
+            {
+                self.upstream_state = UpstreamState::HEALTHY;
+                info!("{} set to HEALTHY", self.server_id);
+            }
+
+            delay = self.config.interval;
+        }
+        else
+        {
+            self.failures += 1;
+            self.passes    = 0;
+
+            if self.upstream_state != UpstreamState::UNHEALTHY && self.failures >= self.config.fall
+            {
+                self.upstream_state = UpstreamState::UNHEALTHY;
+                error!("{} set to UNHEALTHY", self.server_id);
+            }
+
+            if self.upstream_state == UpstreamState::UNHEALTHY
+            {
+                // back off while the server is down
+                delay        = self.backoff;
+                self.backoff = (self.backoff * 2).min(self.config.max_backoff.max(self.config.interval));
+            }
+            else
+            {
+                delay = self.config.interval;
+            }
+        }
+
+        let jitter_ms = self.config.jitter.as_millis() as usize;
+        let jitter = if jitter_ms > 0 { Duration::from_millis(self.rng.next_below(jitter_ms + 1) as u64) } else { Duration::from_millis(0) };
+
+        self.next_check = Instant::now() + delay + jitter;
+
+        PingState::IDLE
+    }
+
+    // Mark the server unhealthy until the next active checks pass
+    fn eject(&mut self)
+    {
+        self.upstream_state = UpstreamState::UNHEALTHY;
+        self.passes = 0;
+    }
+
+    fn take_rtt(&mut self) -> Option<Duration>
+    {
+        self.last_rtt.take()
+    }
+
+    fn is_healthy(&self) -> bool
+    {
+        self.upstream_state == UpstreamState::HEALTHY
+    }
+
+    fn get_state(&self) -> UpstreamState
+    {
+        self.upstream_state
+    }
+}
+
+// Commands from the router to a health worker
+#[allow(clippy::upper_case_acronyms)]
+enum HealthCommand
+{
+    EJECT(u64), // the router's new epoch
+    CONFIG(HealthCheckConfig),
+}
+
+// Published by a health worker whenever its server changes state
+// or a check passes with a measured round trip. Events from before
+// the last eject carry an older epoch and are dropped by the router.
+struct HealthEvent
+{
+    server_id   : u32,
+    epoch       : u64,
+    state       : UpstreamState,
+    rtt         : Option<Duration>,
+}
+
+// Runs one server's HealthChecker on its own thread so a slow or blocking
+// probe never holds up the data path or the checks of other servers.
+// The thread stops once the router drops its end of the command channel.
+struct HealthWorker
+{
+    commands    : Sender<HealthCommand>,
+}
+
+impl HealthWorker
+{
+    fn spawn(checker: HealthChecker, epoch: u64, events: Sender<HealthEvent>) -> std::io::Result<Self>
+    {
+        let (commands, command_rx) = channel();
+
+        std::thread::Builder::new()
+            .name(format!("health-{}", checker.server_id))
+            .spawn(move || Self::run(checker, epoch, command_rx, events))?;
+
+        Ok(Self { commands })
+    }
+
+    fn run(mut checker: HealthChecker, mut epoch: u64, commands: Receiver<HealthCommand>, events: Sender<HealthEvent>)
+    {
+        loop
+        {
+            loop
+            {
+                match commands.try_recv()
+                {
+                    Ok(HealthCommand::EJECT(new_epoch)) =>
+                    {
+                        checker.eject();
+                        epoch = new_epoch;
+                    },
+                    Ok(HealthCommand::CONFIG(config)) => checker.set_config(config),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+
+            let before = checker.get_state();
+
+            if let Err(e) = checker.poll()
+            {
+                error!("{} health check error: {e}", checker.server_id);
+            }
+
+            let after = checker.get_state();
+            let rtt = checker.take_rtt();
+
+            if (after != before || rtt.is_some()) && events.send(HealthEvent { server_id: checker.server_id, epoch, state: after, rtt }).is_err()
+            {
+                return;
+            }
+
+            std::thread::sleep(HEALTH_POLL_INTERVAL);
+        }
+    }
+}
+
+// The router's view of a server's health, kept up to date from the
+// events of its worker. The worker is started on the group's first poll.
+struct ServerHealth
+{
+    state       : UpstreamState,
+    epoch       : u64, // bumped by every eject
+    worker      : Option<HealthWorker>,
+}
+
+impl ServerHealth
+{
+    fn new() -> Self
+    {
+        Self { state: UpstreamState::UNKNOWN, epoch: 0, worker: None }
+    }
+
+    fn send(&self, command: HealthCommand)
+    {
+        if let Some(worker) = &self.worker
+        {
+            let _ = worker.commands.send(command);
+        }
+    }
+
+    // Mark the server unhealthy until the next active checks pass
+    fn eject(&mut self)
+    {
+        self.state  = UpstreamState::UNHEALTHY;
+        self.epoch += 1;
+        self.send(HealthCommand::EJECT(self.epoch));
+    }
+
+    fn is_healthy(&self) -> bool
+    {
+        self.state == UpstreamState::HEALTHY
+    }
+
+    fn get_state(&self) -> UpstreamState
+    {
+        self.state
+    }
+}
+
+#[test]
+fn test_server_group_select_server_excluding()
+{
+    let mut sg = ServerGroup::new(0);
+
+    for i in 0..3
+    {
+        sg.add_server(i, "".into());
+        sg.server_health.get_mut(&i).unwrap().state = UpstreamState::HEALTHY;
+    }
+
+    let ctx = PickContext::default();
+
+    assert!(sg.select_server_excluding(&ctx, &[]) == Some(0));
+    assert!(sg.select_server_excluding(&ctx, &[0]) == Some(1));
+    assert!(sg.select_server_excluding(&ctx, &[0, 1]) == Some(2));
+    assert!(sg.select_server_excluding(&ctx, &[0, 1, 2]).is_none());
+}
+
+#[test]
+fn test_server_group_circuit_breaker()
+{
+    let mut sg = ServerGroup::new(0);
+
+    sg.add_server(0, "".into());
+    sg.add_server(1, "".into());
+
+    sg.set_circuit_breaker(BreakerConfig { failure_threshold: 2, latency_threshold: None, open_for: Duration::from_millis(50), half_open_probes: 1 });
+
+    for i in 0..2
+    {
+        sg.server_health.get_mut(&i).unwrap().state = UpstreamState::HEALTHY;
+    }
+
+    let ctx = PickContext::default();
+
+    sg.report_failure(&0);
+    assert!(sg.select_server(&ctx) == Some(0));
+    sg.report_failure(&0);
+
+    // Open, server 0 is skipped
+    assert!(sg.select_server(&ctx) == Some(1));
+
+    std::thread::sleep(Duration::from_millis(60));
+
+    // Half open, a single trial connection
+    assert!(sg.select_server(&ctx) == Some(0));
+    sg.add_connection(&1);
+    assert!(sg.select_server(&ctx) == Some(1));
+
+    sg.report_success(&0, Duration::from_millis(1));
+    assert!(sg.select_server(&ctx) == Some(0));
+}
+
+#[test]
+fn test_server_group_breaker_refusal_picks_again()
+{
+    let mut sg = ServerGroup::new(0);
+
+    sg.add_server(0, "".into());
+    sg.add_server(1, "".into());
+
+    // no trials at all, so the breaker refuses once it is due to half open
+    sg.set_circuit_breaker(BreakerConfig { failure_threshold: 1, latency_threshold: None, open_for: Duration::from_millis(10), half_open_probes: 0 });
+
+    for i in 0..2
+    {
+        sg.server_health.get_mut(&i).unwrap().state = UpstreamState::HEALTHY;
+    }
+
+    sg.report_failure(&0);
+    sg.add_connection(&1);
+
+    std::thread::sleep(Duration::from_millis(20));
+
+    // Least connected picks 0 first, its refusal moves on to 1
+    assert!(sg.select_server(&PickContext::default()) == Some(1));
+    assert!(sg.select_server_excluding(&PickContext::default(), &[ 1 ]).is_none());
+}
+
+#[test]
+fn test_server_group_backup_tier()
 {
     let mut sg = ServerGroup::new(0);
 
-    for i in 0..5
+    sg.set_backup_policy(BackupPolicy { activate_below: Threshold::COUNT(2), failback_after: Duration::from_millis(50) });
+
+    sg.add_server(0, "".into());
+    sg.add_server(1, "".into());
+    sg.add_server_with_options(2, "".into(), ServerOptions { tier: Tier::BACKUP, ..ServerOptions::default() });
+
+    for i in 0..3
     {
-        sg.server_addrs.insert(i, "".into());
+        sg.server_health.get_mut(&i).unwrap().state = UpstreamState::HEALTHY;
     }
 
-    let mut cnt : usize = 0;
-    for i in 0..10
-    {
-        if let Some(id) = sg.find_min()
-        {
-            cnt += 1;
+    sg.cxn_cntr.insert(0, 5);
+    sg.cxn_cntr.insert(1, 5);
 
-            sg.add_connection(&id);
-        }
+    let ctx = PickContext::default();
 
-    }
+    // The idle backup is left alone while both primaries are up
+    assert!(sg.select_server(&ctx) == Some(0));
 
-    for i in 0..5
-    {
-        sg.remove_connection(&i)
-    }
+    sg.server_health.get_mut(&1).unwrap().state = UpstreamState::UNHEALTHY;
+    assert!(sg.select_server(&ctx) == Some(2));
 
-    assert!(cnt == 10);
+    // Stays on the backups until the primary has been back for a while
+    sg.server_health.get_mut(&1).unwrap().state = UpstreamState::HEALTHY;
+    assert!(sg.select_server(&ctx) == Some(2));
 
-    for i in 0..5
-    {
-        if let Some(cnt) = sg.cxn_cntr.get(&i)
-        {
-            assert!(cnt == &1);
-        }
-        else
-        {
-            // ID should be there
-            assert!(false);
-        }
-    }
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(sg.select_server(&ctx) == Some(0));
 }
 
 #[test]
-fn test_server_group_find_min_and_healthy()
+fn test_server_group_failback_without_picks()
 {
-
     let mut sg = ServerGroup::new(0);
 
-    for i in 0..5
-    {
-        sg.server_addrs.insert(i, "".into());
-        sg.server_health.insert(i, HealthChecker::new(i, "".into()));
-    }
+    sg.set_backup_policy(BackupPolicy { activate_below: Threshold::COUNT(1), failback_after: Duration::from_millis(50) });
 
-    let mut cnt : usize = 0;
-    for i in 0..10
+    sg.add_server(0, "".into());
+    sg.add_server_with_options(1, "".into(), ServerOptions { tier: Tier::BACKUP, ..ServerOptions::default() });
+
+    // stand in workers, the states are set by hand
+    for i in 0..2
     {
-        if let Some(id) = sg.find_min_and_healthy()
-        {
-            cnt += 1;
+        sg.server_health.get_mut(&i).unwrap().worker = Some(HealthWorker { commands: channel().0 });
+    }
 
-            sg.add_connection(&id);
-        }
+    sg.poll().unwrap();
+    assert!(sg.backups_active);
 
-    }
+    sg.server_health.get_mut(&0).unwrap().state = UpstreamState::HEALTHY;
+    sg.poll().unwrap();
+    assert!(sg.backups_active);
 
-    assert!(cnt == 10);
+    std::thread::sleep(Duration::from_millis(60));
 
-    for i in 0..5
-    {
-        if let Some(cnt) = sg.cxn_cntr.get(&i)
-        {
-            assert!(cnt == &2);
-        }
-        else
-        {
-            // ID should be there
-            assert!(false);
-        }
-    }
+    // no server was selected, polling alone fails back
+    sg.poll().unwrap();
+    assert!(!sg.backups_active);
 }
 
 #[test]
-fn test_server_group_find_min_and_some_unhealthy()
+fn test_server_group_backup_threshold()
 {
+    assert!(Threshold::COUNT(1).is_below(0, 3));
+    assert!(!Threshold::COUNT(1).is_below(1, 3));
+    assert!(Threshold::PERCENT(50).is_below(1, 3));
+    assert!(!Threshold::PERCENT(50).is_below(2, 4));
+    assert!(Threshold::PERCENT(50).is_below(0, 0));
+}
 
+#[test]
+fn test_server_group_panic_mode()
+{
     let mut sg = ServerGroup::new(0);
 
-    for i in 0..5
+    for i in 0..4
     {
-        sg.server_addrs.insert(i, "".into());
+        sg.add_server(i, "".into());
+    }
 
-        let mut hc =  HealthChecker::new(i, "".into());
+    sg.server_health.get_mut(&3).unwrap().state = UpstreamState::HEALTHY;
 
-        hc.upstream_state = UpstreamState::UNHEALTHY;
+    let ctx = PickContext::default();
 
-        sg.server_health.insert(i, hc);
-    }
+    assert!(sg.select_server(&ctx) == Some(3));
 
-    let mut cnt : usize = 0;
-    for i in 0..10
-    {
-        if let Some(id) = sg.find_min_and_healthy()
-        {
-            cnt += 1;
+    // 1 of 4 healthy is below 50%, health is ignored
+    sg.set_no_healthy_policy(NoHealthyPolicy::PANIC(50));
+    assert!(sg.select_server(&ctx) == Some(0));
 
-            sg.add_connection(&id);
-        }
+    sg.server_health.get_mut(&2).unwrap().state = UpstreamState::HEALTHY;
+    assert!(sg.select_server(&ctx) == Some(2));
+}
+
+#[test]
+fn test_server_group_panic_mode_keeps_breakers_and_backups()
+{
+    let mut sg = ServerGroup::new(0);
 
+    for i in 0..4
+    {
+        sg.add_server(i, "".into());
     }
 
-    assert!(cnt == 0);
+    sg.add_server_with_options(4, "".into(), ServerOptions { tier: Tier::BACKUP, ..ServerOptions::default() });
 
-    for i in 0..5
+    sg.set_no_healthy_policy(NoHealthyPolicy::PANIC(50));
+    sg.set_circuit_breaker(BreakerConfig { failure_threshold: 1, latency_threshold: None, open_for: Duration::from_secs(60), half_open_probes: 1 });
+
+    // 1 of 5 healthy, a primary, so the backup stays idle
+    sg.server_health.get_mut(&3).unwrap().state = UpstreamState::HEALTHY;
+
+    sg.report_failure(&0);
+
+    for i in 1..4
     {
-        if let Some(cnt) = sg.cxn_cntr.get(&i)
-        {
-            assert!(false);
-        }
-        else
-        {
-            // ID should be there
-            assert!(true);
-        }
+        sg.add_connection(&i);
     }
+
+    // Least connected would be the open breaker or the backup
+    assert!(sg.select_server(&PickContext::default()) == Some(1));
 }
 
 #[test]
-fn test_server_group_find_min_and_all_unhealthy()
+fn test_server_group_reconcile()
 {
-
     let mut sg = ServerGroup::new(0);
 
-    for i in 0..5
+    let server = |addr: &str, weight: u32| DiscoveredServer { addr: addr.into(), opts: ServerOptions { weight, ..ServerOptions::default() } };
+
+    assert!(sg.reconcile(&[ server("10.0.0.1:2500", 1), server("10.0.0.2:2500", 1) ]) == (2, 0, 0));
+    assert!(sg.get_server_address(&1) == Some(&"10.0.0.2:2500".to_string()));
+
+    for i in 0..2
     {
-        sg.server_addrs.insert(i, "".into());
-        sg.server_health.insert(i, HealthChecker::new(i, "".into()));
+        sg.server_health.get_mut(&i).unwrap().state = UpstreamState::HEALTHY;
     }
-    
-    for i in 5..10
-    {
-        sg.server_addrs.insert(i, "".into());
 
-        let mut hc =  HealthChecker::new(i, "".into());
+    sg.add_connection(&0);
 
-        hc.upstream_state = UpstreamState::UNHEALTHY;
+    // 10.0.0.1 goes away, 10.0.0.2 gets heavier and 10.0.0.3 is new
+    assert!(sg.reconcile(&[ server("10.0.0.2:2500", 3), server("10.0.0.3:2500", 1) ]) == (1, 1, 1));
+    assert!(sg.server_opts.get(&1).unwrap().weight == 3);
+    assert!(sg.get_server_address(&2) == Some(&"10.0.0.3:2500".to_string()));
 
-        sg.server_health.insert(i, hc);
-    }
+    // Draining servers are not picked but stay until their connection is gone
+    assert!(sg.select_server_excluding(&PickContext::default(), &[1]).is_none());
 
-    let mut cnt : usize = 0;
-    for i in 0..10
-    {
-        if let Some(id) = sg.find_min_and_healthy()
-        {
-            cnt += 1;
+    sg.poll().unwrap();
+    assert!(sg.get_server_address(&0).is_some());
 
-            sg.add_connection(&id);
-        }
+    sg.remove_connection(&0);
+    sg.poll().unwrap();
+    assert!(sg.get_server_address(&0).is_none());
+}
 
-    }
+#[test]
+fn test_server_group_health_workers()
+{
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let dead = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
 
-    assert!(cnt == 10);
+    let mut sg = ServerGroup::new(0);
 
-    for i in 0..5
-    {
-        if let Some(cnt) = sg.cxn_cntr.get(&i)
-        {
-            assert!(cnt == &2);
-        }
-        else
-        {
-            // ID should be there
-            assert!(false);
-        }
-    }
-    
-    for i in 5..10
+    sg.set_health_check(HealthCheckConfig { kind: ProbeKind::TCP_CONNECT, ..HealthCheckConfig::default() });
+
+    sg.add_server(0, listener.local_addr().unwrap().to_string());
+    sg.add_server(1, dead.to_string());
+
+    // Nothing is checked until the group is polled
+    assert!(sg.server_health.get(&0).unwrap().get_state() == UpstreamState::UNKNOWN);
+
+    let start = Instant::now();
+
+    while start.elapsed() < Duration::from_secs(2)
     {
-        if let Some(cnt) = sg.cxn_cntr.get(&i)
-        {
-            assert!(false);
-        }
-        else
+        sg.poll().unwrap();
+
+        if sg.server_health.get(&0).unwrap().is_healthy() && sg.server_health.get(&1).unwrap().get_state() == UpstreamState::UNHEALTHY
         {
-            assert!(true);
+            break;
         }
+
+        std::thread::sleep(Duration::from_millis(10));
     }
-}
 
-#[derive(PartialEq, Eq)]
-enum UpstreamState
-{
-    HEALTHY,
-    UNHEALTHY,
-}
+    assert!(sg.server_health.get(&0).unwrap().is_healthy());
+    assert!(sg.server_health.get(&1).unwrap().get_state() == UpstreamState::UNHEALTHY);
 
-#[derive(Clone, PartialEq, Eq)]
-enum PingState
-{
-    IDLE(i64),
-    CONNECTED,
-    PING_SENT(i64),
-}
+    // The passed check carried its round trip, kept apart from the connect latency
+    assert!(sg.probe_rtt.get(&0).and_then(|v| v.get()).is_some());
+    assert!(!sg.probe_rtt.contains_key(&1));
+    assert!(sg.latency.is_empty());
 
-pub struct HealthChecker
-{
-    server_id       : u32,
-    address         : String,
-//    cxn_cnt         : usize,
-    up_stream       : Option<std::net::TcpStream>,
-    ping_state      : PingState,
-    upstream_state  : UpstreamState,
+    assert!(sg.select_server(&PickContext::default()) == Some(0));
 }
 
-impl HealthChecker
+#[test]
+fn test_server_health_rise_fall_and_backoff()
 {
-    pub fn new(server_id: u32, address: String) ->  Self
-    {
-        Self { server_id, address, up_stream : None, ping_state : PingState::IDLE(0), upstream_state : UpstreamState::HEALTHY }
-    }
-
-    fn poll(&mut self) -> Result<(), Box<dyn std::error::Error>>
-    {
-        let now = chrono::Utc::now().timestamp();
-
-        let mut next_state = self.ping_state.clone();
-
-        match self.ping_state
-        {
-            PingState::IDLE(idle_ts) =>
-            {
-                if now > idle_ts + 30
-                {
-                    // Connect
-                    match self.address.clone().parse()
-                    {
-                        Ok(sock_addr) =>
-                        {
-                            match TcpStream::connect_timeout(&sock_addr, Duration::from_millis(100))
-                            {
-                                Ok(stream) =>
-                                {
-                                    stream.set_read_timeout(Some(Duration::from_millis(1)))?;
-                                    stream.set_write_timeout(Some(Duration::from_millis(1)))?;
-                                    stream.set_nonblocking(true)?;
-                                    stream.set_nodelay(true)?;
-
-                                    self.up_stream = Some(stream);
-
-                                    next_state = PingState::CONNECTED;
-                                },
-                                Err(e) =>
-                                {
-                                    self.upstream_state = UpstreamState::UNHEALTHY;
-                                    next_state = PingState::IDLE(now);
-                                    error!("{} set to UNHEALTHY", self.server_id);
-                                    error!("{e}");
-                                }
-                            }
-                        },
-                        Err(e) =>
-                        {
-                            self.upstream_state = UpstreamState::UNHEALTHY;
-                            next_state = PingState::IDLE(now);
-                            error!("{} set to UNHEALTHY", self.server_id);
-                            error!("{e}");
-                        }
-                    }
-                }
+    let config = HealthCheckConfig { interval: Duration::from_secs(10), timeout: Duration::from_secs(1), jitter: Duration::from_secs(0),
+                                     rise: 2, fall: 3, max_backoff: Duration::from_secs(40), ..HealthCheckConfig::default() };
 
-                // keep state as idle
-            },
-            PingState::CONNECTED =>
-            {
-                if let Some(stream) = &mut self.up_stream
-                {
-                    // Send ping
-                    match stream.write_all(&"PING".as_bytes())
-                    {
-                        Ok(()) =>
-                        {
-                            next_state = PingState::PING_SENT(now);
-                            info!("{} sent ping", self.server_id);
-                        },
-                        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock=>
-                        {
-                            // wait for next poll
-                        },
-                        Err(e) =>
-                        {
-                            // Connection is in error, as such, it is 
-                            // UNHEALTHY -> set it as such
-                            self.upstream_state = UpstreamState::UNHEALTHY;
-                            self.up_stream = None;
-                            next_state = PingState::IDLE(now);
-                            
-                            error!("{} set to UNHEALTHY", self.server_id);
-                        }
-                    }
-                }
-            },
-            PingState::PING_SENT(timestamp) =>
-            {
-                if now > (timestamp + 1)
-                {
-                    // Out of time for pong
-                    // mark server as unhealthy
-                    self.upstream_state = UpstreamState::UNHEALTHY;
-                    self.up_stream = None;
-                    next_state = PingState::IDLE(now);
-                    error!("{} set to UNHEALTHY", self.server_id);
-                }
-                else
-                {
-                    if let Some(stream) = &mut self.up_stream
-                    {
-                        let mut buf : [u8; 16] = [0; 16];
-                        // poll and wait
+    let mut hc = HealthChecker::with_config(0, "".into(), config);
 
-                        match stream.read(&mut buf)
-                        {
-                            Ok(n) =>
-                            {
-                                if n == 4 && &buf[0..n] == "PONG".as_bytes()
-                                {
-                                    // if/when pong received check timestamp
-                                    if now > timestamp
-                                    {
-                                        // if pong not received within 1 second
-                                        // mark unhealthy
-                                        self.upstream_state = UpstreamState::UNHEALTHY;
-                                        error!("{} set to UNHEALTHY", self.server_id);
-                                    }
-                                    else
-                                    {
-                                        self.upstream_state = UpstreamState::HEALTHY;
-                                        info!("{} set to HEALTHY", self.server_id);
-                                    }
-                                }
-                                
-                                self.up_stream = None;
-                                next_state = PingState::IDLE(now);
-                            },
-                            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock=>
-                            {
-                                // wait for next poll
-                            },
-                            Err(e) =>
-                            {
-                                // Connection is in error, as such, it is 
-                                // UNHEALTHY -> set it as such
-                                self.upstream_state = UpstreamState::UNHEALTHY;
-                                self.up_stream = None;
-                                next_state = PingState::IDLE(now);
-                                
-                                error!("{} set to UNHEALTHY", self.server_id);
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    assert!(hc.get_state() == UpstreamState::UNKNOWN);
 
-        self.ping_state = next_state;
+    hc.record_result(true);
+    assert!(hc.get_state() == UpstreamState::UNKNOWN);
+    hc.record_result(true);
+    assert!(hc.get_state() == UpstreamState::HEALTHY);
 
-        Ok(())
-    }
+    hc.record_result(false);
+    hc.record_result(false);
+    assert!(hc.get_state() == UpstreamState::HEALTHY);
+    hc.record_result(false);
+    assert!(hc.get_state() == UpstreamState::UNHEALTHY);
 
-    fn is_healthy(&self) -> bool
+    // Backs off 10s -> 20s -> 40s -> 40s
+    let mut delays = vec![];
+    for i in 0..4
     {
-        return self.upstream_state == UpstreamState::HEALTHY
+        let before = Instant::now();
+        hc.record_result(false);
+        delays.push((hc.next_check - before).as_secs());
     }
+
+    assert!(delays == vec![20, 40, 40, 40]);
+
+    // A pass resets the back off
+    hc.record_result(true);
+    assert!(hc.get_state() == UpstreamState::UNHEALTHY);
+    hc.record_result(true);
+    assert!(hc.get_state() == UpstreamState::HEALTHY);
+    assert!(hc.backoff == Duration::from_secs(10));
 }
 
 //Server Group Tests
@@ -584,6 +1944,7 @@ fn test_server_health_not_listening()
 }
 
 #[test]
+#[allow(clippy::assertions_on_constants)]
 fn test_server_health_connect()
 {
     let addr: String = "127.0.0.1:25002".into();
@@ -617,10 +1978,11 @@ fn test_server_health_connect()
 
     hc.poll().unwrap();
 
-    assert!(hc.upstream_state == UpstreamState::HEALTHY);
+    assert!(hc.upstream_state == UpstreamState::UNKNOWN);
 }
 
 #[test]
+#[allow(clippy::assertions_on_constants)]
 fn test_server_health_reply_in_time()
 {
     let addr: String = "127.0.0.1:25003".into();
@@ -667,17 +2029,16 @@ fn test_server_health_reply_in_time()
 
     hc.poll().unwrap();
 
-    assert!(hc.upstream_state == UpstreamState::HEALTHY);
+    assert!(hc.upstream_state == UpstreamState::UNKNOWN);
 
-    let mut buf : [u8; 4] = [0; 4];
+    let mut buf : [u8; 16] = [0; 16];
     match stream.as_ref().unwrap().read(&mut buf)
     {
         Ok(n) =>
         {
-            if n == 4 && buf[0..n] == *"PING".as_bytes()
+            if buf[0..n] == *crate::probe::HEALTH_REQUEST
             {
-                // Send back "PONG"
-                stream.as_ref().unwrap().write_all("PONG".as_bytes()).unwrap();
+                stream.as_ref().unwrap().write_all(crate::probe::HEALTH_RESPONSE).unwrap();
             }
         },
         Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock =>
@@ -693,9 +2054,14 @@ fn test_server_health_reply_in_time()
     hc.poll().unwrap();
 
     assert!(hc.upstream_state == UpstreamState::HEALTHY);
+
+    // Millisecond round trip of the exchange, taken once
+    assert!(hc.take_rtt().map(|v| v < Duration::from_secs(1)).unwrap_or(false));
+    assert!(hc.take_rtt().is_none());
 }
 
 #[test]
+#[allow(clippy::assertions_on_constants)]
 fn test_server_health_reply_out_of_time()
 {
     let addr: String = "127.0.0.1:25004".into();
@@ -741,20 +2107,19 @@ fn test_server_health_reply_out_of_time()
 
     hc.poll().unwrap();
 
-    assert!(hc.upstream_state == UpstreamState::HEALTHY);
+    assert!(hc.upstream_state == UpstreamState::UNKNOWN);
 
     let period = std::time::Duration::from_millis(1100);
     std::thread::sleep(period);
 
-    let mut buf : [u8; 4] = [0; 4];
+    let mut buf : [u8; 16] = [0; 16];
     match stream.as_ref().unwrap().read(&mut buf)
     {
         Ok(n) =>
         {
-            if n == 4 && buf[0..n] == *"PING".as_bytes()
+            if buf[0..n] == *crate::probe::HEALTH_REQUEST
             {
-                // Send back "PONG"
-                stream.as_ref().unwrap().write_all("PONG".as_bytes()).unwrap();
+                stream.as_ref().unwrap().write_all(crate::probe::HEALTH_RESPONSE).unwrap();
             }
         },
         Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock =>
@@ -819,7 +2184,7 @@ fn test_server_health_reply_disconnect_from_upstream()
 
     hc.poll().unwrap();
 
-    assert!(hc.upstream_state == UpstreamState::HEALTHY);
+    assert!(hc.upstream_state == UpstreamState::UNKNOWN);
 
     stream = None;
 
@@ -828,3 +2193,80 @@ fn test_server_health_reply_disconnect_from_upstream()
     assert!(hc.upstream_state == UpstreamState::UNHEALTHY);
 }
 
+
+#[test]
+fn test_server_group_maintenance()
+{
+    let mut sg = ServerGroup::new(0);
+
+    sg.add_server(0, "".into());
+    sg.add_server(1, "".into());
+
+    for i in 0..2
+    {
+        sg.server_health.get_mut(&i).unwrap().state = UpstreamState::HEALTHY;
+    }
+
+    sg.cxn_cntr.insert(0, 0);
+    sg.cxn_cntr.insert(1, 3);
+
+    let ctx = PickContext::default();
+
+    assert!(sg.select_server(&ctx) == Some(0));
+
+    // A drained server gets nothing new but keeps its connections until the deadline
+    assert!(sg.drain_server(&0, Some(Duration::from_millis(50))));
+    assert!(!sg.drain_server(&7, None));
+    sg.cxn_cntr.insert(0, 2);
+
+    assert!(sg.select_server(&ctx) == Some(1));
+    assert!(sg.get_expired_drains().is_empty());
+
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(sg.get_expired_drains() == vec![0]);
+
+    // Draining again moves the deadline but not the start
+    let started = sg.get_server_drain(&0).unwrap().started;
+    sg.drain_server(&0, None);
+    assert!(sg.get_server_drain(&0).unwrap().started == started);
+    assert!(sg.get_expired_drains().is_empty());
+
+    assert!(sg.resume_server(&0));
+    assert!(!sg.resume_server(&0));
+    sg.cxn_cntr.insert(0, 0);
+    assert!(sg.select_server(&ctx) == Some(0));
+
+    // The whole group
+    sg.drain_group(Some(Duration::ZERO));
+    assert!(sg.select_server(&ctx).is_none());
+    assert!(sg.get_expired_drains() == vec![1]);
+
+    assert!(sg.resume_group());
+    assert!(sg.select_server(&ctx) == Some(0));
+    assert!(sg.status_lines().iter().all(|v| !v.contains("maintenance")));
+}
+
+#[test]
+fn test_server_group_eject_drops_stale_health_events()
+{
+    let mut sg = ServerGroup::new(0);
+
+    sg.add_server(0, "".into());
+    sg.server_health.get_mut(&0).unwrap().state = UpstreamState::HEALTHY;
+
+    // A passed check already in the channel when the server is ejected
+    let events = sg.health_events.0.clone();
+    events.send(HealthEvent { server_id: 0, epoch: 0, state: UpstreamState::HEALTHY, rtt: None }).unwrap();
+
+    sg.server_health.get_mut(&0).unwrap().eject();
+    events.send(HealthEvent { server_id: 0, epoch: 0, state: UpstreamState::HEALTHY, rtt: None }).unwrap();
+
+    sg.apply_health_events();
+    assert!(sg.server_health.get(&0).unwrap().get_state() == UpstreamState::UNHEALTHY);
+
+    // Only a check after the eject brings it back
+    events.send(HealthEvent { server_id: 0, epoch: 1, state: UpstreamState::HEALTHY, rtt: None }).unwrap();
+
+    sg.apply_health_events();
+    assert!(sg.server_health.get(&0).unwrap().is_healthy());
+}